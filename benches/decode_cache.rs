@@ -0,0 +1,26 @@
+// Plain timing loop, run with: cargo bench --no-default-features --features std
+use std::time::Instant;
+
+use chip8::machine::Machine;
+
+// LOAD r0, $00, ADD r0, $01, SKE r0, $FF, JUMP $202, JUMP $200
+const ROM: [u8; 10] = [0x60, 0x00, 0x70, 0x01, 0x30, 0xFF, 0x12, 0x02, 0x12, 0x00];
+const STEPS: usize = 5_000_000;
+
+fn run(cached: bool) -> f64 {
+    let mut m = Machine::new();
+    m.load_bytes(&ROM);
+    m.set_decode_cache(cached);
+    let start = Instant::now();
+    for _ in 0..STEPS {
+        m.step().unwrap();
+    }
+    start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    for &cached in &[false, true] {
+        let secs = run(cached);
+        println!("decode cache {:5}: {:.3}s, {:.1} ns/step", cached, secs, secs * 1e9 / STEPS as f64);
+    }
+}
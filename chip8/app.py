@@ -35,10 +35,10 @@ def crate_tone(frequency):
 
 def step(machine, screen):
     """Move forward a program execution."""
-    op = machine.step()
-    if op[1].startswith('DRAW') or op[1].startswith('CLS'):
+    outcome = machine.step()
+    if outcome['drew']:
         size = screen.get_size()
-        image = Image.frombuffer('L', (64, 32), machine.framebuffer)
+        image = Image.frombuffer('L', machine.resolution, machine.framebuffer)
         image = ImageOps.colorize(image, '#111', '#0a0')
         image = image.resize(size, resample=Image.BOX)
         frame = pygame.image.frombuffer(image.tobytes(), size, 'RGB')
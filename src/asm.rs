@@ -0,0 +1,162 @@
+use core::fmt;
+use core::fmt::Display;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::collections::BTreeMap;
+#[cfg(test)]
+use alloc::string::ToString;
+#[cfg(test)]
+use alloc::vec;
+
+use crate::isa::ISA;
+
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,    // 1-based line number
+    pub token: String,  // offending token
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid assembly '{}' at line {}", self.token, self.line)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AsmError {}
+
+// Number as $hex, 0xhex or decimal, at most max
+fn number(token: &str, max: usize) -> Option<usize> {
+    let value = if let Some(hex) = token.strip_prefix('$') {
+        usize::from_str_radix(hex, 16).ok()?
+    } else if let Some(hex) = token.strip_prefix("0x").or_else(|| token.strip_prefix("0X")) {
+        usize::from_str_radix(hex, 16).ok()?
+    } else {
+        token.parse().ok()?
+    };
+    if value <= max { Some(value) } else { None }
+}
+
+// Register as rX or vX, disassembly of KEYD/STOR/READ uses plain numbers
+fn register(token: &str) -> Option<usize> {
+    match token.chars().next()? {
+        'r' | 'R' | 'v' | 'V' => usize::from_str_radix(&token[1..], 16).ok().filter(|&x| x < 16),
+        _ => number(token, 0xf)
+    }
+}
+
+fn instruction(name: &str, ops: &[&str], labels: &BTreeMap<&str, usize>) -> Option<ISA> {
+    let addr = |i: usize| ops.get(i).and_then(|t| labels.get(t).copied().or_else(|| number(t, 0xfff)));
+    let reg = |i: usize| ops.get(i).and_then(|t| register(t));
+    let byte = |i: usize| ops.get(i).and_then(|t| number(t, 0xff)).map(|n| n as u8);
+    let nibble = |i: usize| ops.get(i).and_then(|t| number(t, 0xf));
+    let count = match name {
+        "CLS" | "RET" | "LORES" | "HIRES" | "SCR" | "SCL" | "EXIT" | "AUDIO" => 0,
+        "SYS" | "JP" | "CALL" | "LOADI" | "JUMPI" | "NOP" | "SKPR" | "SKUP" | "MOVED" | "KEYD"
+        | "LOADD" | "LOADS" | "ADDI" | "LDSPR" | "BCD" | "STOR" | "READ" | "PITCH" | "SCD" => 1,
+        "DRAW" => 3,
+        _ => 2
+    };
+    if ops.len() != count {
+        return None;
+    }
+    let op = match name {
+        "CLS" => ISA::CLS,
+        "RET" => ISA::RET,
+        "LORES" => ISA::LORES,
+        "HIRES" => ISA::HIRES,
+        "SCR" => ISA::SCR,
+        "SCL" => ISA::SCL,
+        "EXIT" => ISA::EXIT,
+        "SCD" => ISA::SCD(nibble(0)?),
+        "AUDIO" => ISA::AUDIO,
+        "SYS" => ISA::SYS(addr(0)?),
+        "JP" => ISA::JP(addr(0)?),
+        "CALL" => ISA::CALL(addr(0)?),
+        "LOADI" => ISA::LOADI(addr(0)?),
+        "JUMPI" => {
+            let a = addr(0)?;
+            ISA::JUMPI(a, a >> 8)
+        }
+        "SKE" => ISA::SKE(reg(0)?, byte(1)?),
+        "SKNE" => ISA::SKNE(reg(0)?, byte(1)?),
+        "LOAD" => ISA::LOAD(reg(0)?, byte(1)?),
+        "ADD" => ISA::ADD(reg(0)?, byte(1)?),
+        "RAND" => ISA::RAND(reg(0)?, byte(1)?),
+        "SKRE" => ISA::SKRE(reg(0)?, reg(1)?),
+        "MOVE" => ISA::MOVE(reg(0)?, reg(1)?),
+        "OR" => ISA::OR(reg(0)?, reg(1)?),
+        "AND" => ISA::AND(reg(0)?, reg(1)?),
+        "XOR" => ISA::XOR(reg(0)?, reg(1)?),
+        "ADDR" => ISA::ADDR(reg(0)?, reg(1)?),
+        "SUB" => ISA::SUB(reg(0)?, reg(1)?),
+        "SHR" => ISA::SHR(reg(0)?, reg(1)?),
+        "SUBN" => ISA::SUBN(reg(0)?, reg(1)?),
+        "SHL" => ISA::SHL(reg(0)?, reg(1)?),
+        "SKRNE" => ISA::SKRNE(reg(0)?, reg(1)?),
+        "DRAW" => ISA::DRAW(reg(0)?, reg(1)?, nibble(2)?),
+        "SKPR" => ISA::SKPR(reg(0)?),
+        "SKUP" => ISA::SKUP(reg(0)?),
+        "MOVED" => ISA::MOVED(reg(0)?),
+        "KEYD" => ISA::KEYD(reg(0)?),
+        "LOADD" => ISA::LOADD(reg(0)?),
+        "LOADS" => ISA::LOADS(reg(0)?),
+        "ADDI" => ISA::ADDI(reg(0)?),
+        "LDSPR" => ISA::LDSPR(reg(0)?),
+        "BCD" => ISA::BCD(reg(0)?),
+        "STOR" => ISA::STOR(reg(0)?),
+        "READ" => ISA::READ(reg(0)?),
+        "PITCH" => ISA::PITCH(reg(0)?),
+        "NOP" => ISA::NOP(ops.first().and_then(|t| number(t, 0xffff))? as u16),
+        _ => return None
+    };
+    Some(op)
+}
+
+// Assemble mnemonics in disassembly syntax, one instruction per line.
+// Lines may start with "label:", which can be used as an address
+// operand, and ';' or '#' start a comment.
+pub fn assemble(src: &str, base: usize) -> Result<Vec<u8>, AsmError> {
+    let mut labels = BTreeMap::new();
+    let mut lines = Vec::new();
+    for (index, line) in src.lines().enumerate() {
+        let mut code = line.split(&[';', '#'][..]).next().unwrap_or("").trim();
+        if let Some(colon) = code.find(':') {
+            let label = code[..colon].trim();
+            if label.is_empty() || labels.insert(label, base + 2 * lines.len()).is_some() {
+                return Err(AsmError { line: index + 1, token: String::from(label) });
+            }
+            code = code[colon + 1..].trim();
+        }
+        if !code.is_empty() {
+            lines.push((index + 1, code));
+        }
+    }
+    let mut bytes = Vec::new();
+    for (line, code) in lines {
+        let (name, rest) = code.split_at(code.find(char::is_whitespace).unwrap_or(code.len()));
+        let ops: Vec<&str> = rest.split(',').map(str::trim).filter(|t| !t.is_empty()).collect();
+        let op = instruction(&name.to_uppercase(), &ops, &labels)
+            .ok_or_else(|| AsmError { line, token: String::from(code) })?;
+        bytes.extend_from_slice(&op.encode().to_be_bytes());
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+#[test]
+fn test_assemble() {
+    let src = "start: LOAD r0, $0A  ; counter\n\nloop:\n  add r0, 255\n  SKE r0, $00\n  JP loop\n  DRAW r1, r2, $5\n  KEYD $003\n  JP start";
+    assert_eq!(assemble(src, 0x200), Ok(vec![
+        0x60, 0x0A, 0x70, 0xFF, 0x30, 0x00, 0x12, 0x02, 0xD1, 0x25, 0xF3, 0x0A, 0x12, 0x00,
+    ]));
+    assert_eq!(assemble("JP missing", 0x200), Err(AsmError { line: 1, token: "JP missing".to_string() }));
+    assert!(assemble("LOAD r0, $100", 0x200).is_err());
+    assert!(assemble("CLS r0", 0x200).is_err());
+    assert!(assemble("a:\na:", 0x200).is_err());
+    // disassembly of every instruction assembles back
+    for word in [0x00E0u16, 0x0123, 0x8126, 0x9AB0, 0xBFFF, 0xE19E, 0xF255, 0xF365, 0xFFFF].iter() {
+        let op = crate::isa::decode(&word.to_be_bytes()).unwrap();
+        assert_eq!(assemble(&op.to_string(), 0x200), Ok(word.to_be_bytes().to_vec()));
+    }
+}
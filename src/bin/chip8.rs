@@ -0,0 +1,117 @@
+// Terminal front-end, run with: cargo run --no-default-features --features cli -- ROM
+use std::collections::BTreeSet;
+use std::env;
+use std::io::{self, Read, Write};
+use std::process::{exit, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chip8::machine::Machine;
+
+const IPS: u32 = 700;
+const FRAME: Duration = Duration::from_micros(16_667);
+// terminals report no key releases, keep key down for a few frames
+const HOLD_FRAMES: u8 = 6;
+
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output().ok()?;
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Same layout as python front-end: hex digits and space for 5
+fn keypad(byte: u8) -> Option<usize> {
+    match byte {
+        b' ' => Some(0x5),
+        _ => (byte as char).to_digit(16).map(|key| key as usize),
+    }
+}
+
+fn spawn_input() -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0u8; 32];
+        while let Ok(count) = io::stdin().read(&mut buf) {
+            if count == 0 || buf[..count].iter().any(|&byte| sender.send(byte).is_err()) {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+fn run(m: &mut Machine, input: &Receiver<u8>) -> io::Result<()> {
+    let mut out = io::stdout();
+    let mut held = [0u8; 16];
+    let mut status = String::new();
+    loop {
+        let start = Instant::now();
+        while let Ok(byte) = input.try_recv() {
+            match byte {
+                b'q' | 0x03 => return Ok(()),
+                b'r' => {
+                    m.reset();
+                    m.goto_entry();
+                    status.clear();
+                }
+                _ => {
+                    if let Some(key) = keypad(byte.to_ascii_lowercase()) {
+                        held[key] = HOLD_FRAMES;
+                    }
+                }
+            }
+        }
+        for (key, frames) in held.iter_mut().enumerate() {
+            m.set_key(key, *frames > 0);
+            *frames = frames.saturating_sub(1);
+        }
+        if !m.is_halted() {
+            if let Err(err) = m.run_frame(IPS) {
+                status = err.to_string();
+            }
+        }
+        if m.take_resolution_changed().is_some() {
+            write!(out, "\x1b[2J")?;
+        }
+        // braille lines cover 4 pixel rows, redraw only the changed ones
+        let text = m.framebuffer_braille();
+        let lines: Vec<&str> = text.lines().collect();
+        let dirty: BTreeSet<usize> = m.dirty_rows().into_iter().map(|y| y / 4).collect();
+        for line in dirty {
+            write!(out, "\x1b[{};1H{}", line + 1, lines[line])?;
+        }
+        write!(out, "\x1b[{};1H\x1b[K{}  [q] quit  [r] reset", lines.len() + 1, status)?;
+        out.flush()?;
+        if let Some(rest) = FRAME.checked_sub(start.elapsed()) {
+            thread::sleep(rest);
+        }
+    }
+}
+
+fn main() {
+    let path = match env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: chip8 ROM");
+            exit(2);
+        }
+    };
+    let mut m = Machine::new();
+    if let Err(err) = m.load(&path) {
+        eprintln!("cannot load {}: {}", path, err);
+        exit(1);
+    }
+    m.auto_configure();
+    let saved = stty(&["-g"]);
+    stty(&["raw", "-echo"]);
+    print!("\x1b[2J\x1b[?25l");
+    let result = run(&mut m, &spawn_input());
+    print!("\x1b[?25h\r\n");
+    if let Some(saved) = saved {
+        stty(&[&saved]);
+    }
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        exit(1);
+    }
+}
@@ -0,0 +1,99 @@
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::collections::BTreeSet;
+
+use crate::isa::ISA;
+use crate::isa::decode;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Edge {
+    To(usize),  // constant target address
+    Unknown,    // computed target (JUMPI, RET) or wait (KEYD)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Block {
+    pub start: usize,          // address of first instruction
+    pub end: usize,            // address past last instruction
+    pub successors: Vec<Edge>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Cfg {
+    pub blocks: Vec<Block>,
+}
+
+// Successors of instruction at pc, None when it falls through
+fn successors(op: &ISA, pc: usize) -> Option<Vec<Edge>> {
+    let next = pc + op.size();
+    match *op {
+        ISA::JP(n) | ISA::SYS(n) => Some(vec![Edge::To(n)]),
+        ISA::CALL(n) => Some(vec![Edge::To(n), Edge::To(next)]),
+        ISA::RET | ISA::JUMPI(..) => Some(vec![Edge::Unknown]),
+        ISA::EXIT => Some(vec![]),
+        ISA::KEYD(_) => Some(vec![Edge::To(next), Edge::Unknown]),
+        _ if op.is_skip() => Some(vec![Edge::To(next), Edge::To(next + 2)]),
+        _ => None
+    }
+}
+
+// Split program loaded at base into basic blocks
+pub fn build_cfg(bytes: &[u8], base: usize) -> Cfg {
+    let end = base + bytes.len();
+    let code: Vec<(usize, ISA)> = bytes.chunks_exact(2)
+        .enumerate()
+        .filter_map(|(index, word)| decode(word).map(|op| (base + 2 * index, op)))
+        .collect();
+
+    let mut leaders = BTreeSet::new();
+    leaders.insert(base);
+    for (pc, op) in code.iter() {
+        if let Some(edges) = successors(op, *pc) {
+            leaders.insert(pc + op.size());
+            for edge in edges {
+                if let Edge::To(addr) = edge {
+                    leaders.insert(addr);
+                }
+            }
+        }
+    }
+
+    let mut blocks: Vec<Block> = Vec::new();
+    for (pc, op) in code.iter() {
+        if leaders.contains(pc) || blocks.is_empty() {
+            blocks.push(Block { start: *pc, end: *pc, successors: Vec::new() });
+        }
+        let block = blocks.last_mut().unwrap();
+        block.end = pc + op.size();
+        block.successors = match successors(op, *pc) {
+            Some(edges) => edges,
+            None if block.end < end => vec![Edge::To(block.end)],
+            None => Vec::new()
+        };
+    }
+    Cfg { blocks }
+}
+
+#[cfg(test)]
+#[test]
+fn test_build_cfg() {
+    let rom = [
+        0x60, 0x01,  // $200 LOAD r0, $01
+        0x30, 0x01,  // $202 SKE r0, $01
+        0x12, 0x00,  // $204 JP $200
+        0x22, 0x0A,  // $206 CALL $20A
+        0xB0, 0x00,  // $208 JUMPI $000
+        0x00, 0xEE,  // $20A RET
+    ];
+    let cfg = build_cfg(&rom, 0x200);
+    let edges: Vec<(usize, usize, Vec<Edge>)> = cfg.blocks.into_iter()
+        .map(|b| (b.start, b.end, b.successors))
+        .collect();
+    assert_eq!(edges, vec![
+        (0x200, 0x204, vec![Edge::To(0x204), Edge::To(0x206)]),
+        (0x204, 0x206, vec![Edge::To(0x200)]),
+        (0x206, 0x208, vec![Edge::To(0x20A), Edge::To(0x208)]),
+        (0x208, 0x20A, vec![Edge::Unknown]),
+        (0x20A, 0x20C, vec![Edge::Unknown]),
+    ]);
+}
@@ -0,0 +1,67 @@
+use rand::prelude::*;
+use rand::rngs::StdRng;
+
+use crate::machine::Machine;
+use crate::machine::StepError;
+use crate::machine::StopReason;
+
+// Programs that used to crash or stress edge cases
+pub const SEED_CORPUS: [&[u8]; 7] = [
+    &[0x22, 0x00],                          // CALL $200 until stack overflows
+    &[0x00, 0xEE],                          // RET past entry point until stack underflows
+    &[0x1F, 0xFF],                          // JP $FFF, last byte of RAM
+    &[0x12, 0x01],                          // JP $201, misaligned PC
+    &[0xAF, 0xFF, 0xF3, 0x33, 0xFF, 0x65],  // LOADI $FFF, BCD r3, READ rF
+    &[0x60, 0xFF, 0xE0, 0x9E, 0xE0, 0xA1],  // LOAD r0, $FF, SKPR r0, SKUP r0
+    &[0xAF, 0xFF, 0xDF, 0xFF, 0xBF, 0xFF],  // LOADI $FFF, DRAW rF, rF, $F, JUMPI $FFF
+];
+
+// Run program from RAM filled with pseudo-random bytes of seed
+pub fn fuzz_step(seed: u64, steps: usize) {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut data = [0u8; 4096 - 0x200];
+    rng.fill_bytes(&mut data);
+    fuzz_program(&data, seed, steps);
+}
+
+// Entry point for fuzzers, must never panic. Returns error that stopped
+// the program, if any.
+pub fn fuzz_program(data: &[u8], seed: u64, steps: usize) -> Option<StepError> {
+    let mut m = Machine::new();
+    m.set_seed(seed);
+    m.load_bytes(data);
+    for _ in 0..steps {
+        if let Err(err) = m.step() {
+            // every failure must be one of the known errors
+            match err {
+                StepError::InvalidOpcode { .. }
+                | StepError::StackOverflow { .. }
+                | StepError::StackUnderflow { .. }
+                | StepError::PcOutOfRange { .. }
+                | StepError::RamResized { .. }
+                | StepError::WriteToProtectedMemory { .. }
+                | StepError::Stopped(StopReason::RanIntoEmptyMemory)
+                | StepError::Stopped(StopReason::RanPastMemory)
+                | StepError::Stopped(StopReason::Halted)
+                | StepError::Stopped(StopReason::CycleLimitReached) => return Some(err),
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+#[test]
+fn test_seed_corpus() {
+    for program in SEED_CORPUS.iter() {
+        fuzz_program(program, 0, 1000);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_fuzz_step() {
+    for seed in 0..200 {
+        fuzz_step(seed, 1000);
+    }
+}
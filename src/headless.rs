@@ -0,0 +1,86 @@
+use alloc::vec::Vec;
+
+use crate::machine::Machine;
+use crate::machine::StepError;
+
+// Outcome of running a ROM offline
+#[derive(Debug, PartialEq)]
+pub struct RunResult {
+    pub framebuffer: Vec<u8>,      // 1 bit per pixel, rows top to bottom, MSB leftmost
+    pub width: usize,
+    pub height: usize,
+    pub checksum: u32,             // FNV-1a of registers, RAM and screen
+    pub frames: usize,             // frames run before stopping
+    pub stop: Option<StepError>,   // error that ended the run early
+}
+
+// Runs ROMs for a fixed number of frames without a display, e.g. for thumbnails
+pub struct HeadlessRunner {
+    machine: Machine,
+}
+
+impl Default for HeadlessRunner {
+    fn default() -> Self {
+        HeadlessRunner::new(Machine::new())
+    }
+}
+
+impl HeadlessRunner {
+    // Quirks and options of machine are kept for every ROM
+    pub fn new(machine: Machine) -> HeadlessRunner {
+        HeadlessRunner { machine }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    pub fn run_rom(&mut self, bytes: &[u8], frames: usize, ips: u32) -> RunResult {
+        let m = &mut self.machine;
+        m.load_bytes(bytes);
+        let mut stop = None;
+        let mut done = 0;
+        while done < frames {
+            if let Err(err) = m.run_frame(ips) {
+                stop = Some(err);
+                break;
+            }
+            done += 1;
+        }
+        let (width, height) = m.resolution();
+        RunResult { framebuffer: m.framebuffer_packed(), width, height, checksum: checksum(m), frames: done, stop }
+    }
+}
+
+fn checksum(m: &Machine) -> u32 {
+    let cpu = m.cpu();
+    let (width, height) = m.resolution();
+    let words = [cpu.i, cpu.pc, cpu.sp, cpu.dt as usize, cpu.st as usize];
+    cpu.r.iter()
+        .chain(words.iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<u8>>().iter())
+        .chain(m.mem().ram.iter())
+        .chain(m.mem().fb[..width * height].iter())
+        .fold(0x811c9dc5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x01000193))
+}
+
+impl Machine {
+    pub fn into_headless_runner(self) -> HeadlessRunner {
+        HeadlessRunner::new(self)
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_run_rom() {
+    // LOADI $000, DRAW r0, r1, $5, ADD r0, $08, JP $202
+    let rom = [0xA0, 0x00, 0xD0, 0x15, 0x70, 0x08, 0x12, 0x02];
+    let mut runner = Machine::new().into_headless_runner();
+    let result = runner.run_rom(&rom, 3, 120);
+    assert_eq!((result.width, result.height, result.frames, result.stop), (64, 32, 3, None));
+    assert_eq!(result.framebuffer.len(), 64 * 32 / 8);
+    // "0" glyph top row $F0 drawn at x 0 and 8 by first and third frame
+    assert_eq!(result.framebuffer[..3], [0xF0, 0xF0, 0x00]);
+    assert_eq!(result.checksum, 0x4797B5E2);
+    assert_eq!(result.checksum, runner.run_rom(&rom, 3, 120).checksum);
+    assert_ne!(result.checksum, runner.run_rom(&rom, 4, 120).checksum);
+}
@@ -1,13 +1,26 @@
-use std::fmt::Display;
-use std::fmt::Formatter;
-use std::fmt::Result;
-use std::convert::Into;
+use core::fmt::Display;
+use core::fmt::Formatter;
+use core::fmt::Result;
+use core::convert::Into;
+use core::ops::Range;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::string::ToString;
+use alloc::format;
+#[cfg(test)]
+use alloc::vec;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 #[derive(PartialEq)]
 pub enum ISA {
     CLS,                       // (00E0) Clear display
     RET,                       // (00EE) Return from subroutine
+    LORES,                     // (00FE) Switch to 64x32 low resolution (SCHIP)
+    HIRES,                     // (00FF) Switch to 128x64 high resolution (SCHIP)
+    SCD(usize),                // (00Cn) Scroll display down n lines (SCHIP)
+    SCR,                       // (00FB) Scroll display right 4 pixels (SCHIP)
+    SCL,                       // (00FC) Scroll display left 4 pixels (SCHIP)
+    EXIT,                      // (00FD) Exit interpreter (SCHIP)
     SYS(usize),                // (0nnn) Jump to machine code routine at nnn addr
     JP(usize),                 // (1nnn) Jump to nnn address
     CALL(usize),               // (2nnn) Call subroutine at nnn addr
@@ -27,7 +40,7 @@ pub enum ISA {
     SHL(usize, usize),         // (8xyE) Perform Vy = Vx << 1
     SKRNE(usize, usize),       // (9xy0) Skip next instruction if Vx != Vy
     LOADI(usize),              // (Annn) Load index with value nnn
-    JUMPI(usize),              // (Bnnn) Jump to nnn + index
+    JUMPI(usize, usize),       // (Bnnn) Jump to nnn + V0, or Bxnn to xnn + Vx, x kept as second field
     RAND(usize, u8),           // (Cxnn) Generate random number and store it in Vx
     DRAW(usize, usize, usize), // (Dxyn) Draw n sprint at (Vx, Vy) location
     SKPR(usize),               // (Ex9E) Skip next instruction if Vx key is pressed
@@ -41,14 +54,220 @@ pub enum ISA {
     BCD(usize),                // (Fx33) Store BCD representation of Vx value at mem[index..index+2]
     STOR(usize),               // (Fx55) Store V0 to Vx register values at mem[index:index+x]
     READ(usize),               // (Fx65) Load V0 to Vx register values with mem[index:index+x]
+    PITCH(usize),              // (Fx3A) Set audio pitch from Vx value (XO-CHIP)
+    AUDIO,                     // (F002) Load audio pattern from mem[index..index+16] (XO-CHIP)
     NOP(u16),                  // (????) Invalid operation
 }
 
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Operands {
+    None,                               // no operands
+    Addr(usize),                        // nnn
+    RegByte(usize, u8),                 // x, nn
+    RegReg(usize, usize),               // x, y
+    Reg(usize),                         // x
+    Nibble(usize),                      // n
+    RegRegNibble(usize, usize, usize),  // x, y, n
+    Word(u16),                          // raw opcode
+}
+
+// High nibble routing of opcodes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpGroup {
+    Zero,            // 0nnn
+    Jump,            // 1nnn
+    Call,            // 2nnn
+    SkipEqual,       // 3xnn
+    SkipNotEqual,    // 4xnn
+    SkipRegEqual,    // 5xy0
+    Load,            // 6xnn
+    Add,             // 7xnn
+    Arithmetic,      // 8xyn
+    SkipRegNotEqual, // 9xy0
+    LoadIndex,       // Annn
+    JumpIndex,       // Bnnn
+    Random,          // Cxnn
+    Draw,            // Dxyn
+    EGroup,          // Exnn
+    FGroup,          // Fxnn
+}
+
+pub fn opcode_group(opcode: u16) -> OpGroup {
+    match opcode >> 12 {
+        0x0 => OpGroup::Zero,
+        0x1 => OpGroup::Jump,
+        0x2 => OpGroup::Call,
+        0x3 => OpGroup::SkipEqual,
+        0x4 => OpGroup::SkipNotEqual,
+        0x5 => OpGroup::SkipRegEqual,
+        0x6 => OpGroup::Load,
+        0x7 => OpGroup::Add,
+        0x8 => OpGroup::Arithmetic,
+        0x9 => OpGroup::SkipRegNotEqual,
+        0xA => OpGroup::LoadIndex,
+        0xB => OpGroup::JumpIndex,
+        0xC => OpGroup::Random,
+        0xD => OpGroup::Draw,
+        0xE => OpGroup::EGroup,
+        _ => OpGroup::FGroup,
+    }
+}
+
+impl ISA {
+    // Opcode word for instruction, inverse of decode
+    pub fn encode(&self) -> u16 {
+        let xy = |x: usize, y: usize| ((x as u16 & 0xf) << 8) | ((y as u16 & 0xf) << 4);
+        let xn = |x: usize, n: u8| ((x as u16 & 0xf) << 8) | n as u16;
+        let nnn = |a: usize| a as u16 & 0xfff;
+        match *self {
+            ISA::CLS => 0x00E0,
+            ISA::RET => 0x00EE,
+            ISA::LORES => 0x00FE,
+            ISA::HIRES => 0x00FF,
+            ISA::SCD(n) => 0x00C0 | (n as u16 & 0xf),
+            ISA::SCR => 0x00FB,
+            ISA::SCL => 0x00FC,
+            ISA::EXIT => 0x00FD,
+            ISA::SYS(a) => nnn(a),
+            ISA::JP(a) => 0x1000 | nnn(a),
+            ISA::CALL(a) => 0x2000 | nnn(a),
+            ISA::SKE(x, n) => 0x3000 | xn(x, n),
+            ISA::SKNE(x, n) => 0x4000 | xn(x, n),
+            ISA::SKRE(x, y) => 0x5000 | xy(x, y),
+            ISA::LOAD(x, n) => 0x6000 | xn(x, n),
+            ISA::ADD(x, n) => 0x7000 | xn(x, n),
+            ISA::MOVE(x, y) => 0x8000 | xy(x, y),
+            ISA::OR(x, y) => 0x8001 | xy(x, y),
+            ISA::AND(x, y) => 0x8002 | xy(x, y),
+            ISA::XOR(x, y) => 0x8003 | xy(x, y),
+            ISA::ADDR(x, y) => 0x8004 | xy(x, y),
+            ISA::SUB(x, y) => 0x8005 | xy(x, y),
+            ISA::SHR(x, y) => 0x8006 | xy(x, y),
+            ISA::SUBN(x, y) => 0x8007 | xy(x, y),
+            ISA::SHL(x, y) => 0x800E | xy(x, y),
+            ISA::SKRNE(x, y) => 0x9000 | xy(x, y),
+            ISA::LOADI(a) => 0xA000 | nnn(a),
+            ISA::JUMPI(a, _) => 0xB000 | nnn(a),
+            ISA::RAND(x, n) => 0xC000 | xn(x, n),
+            ISA::DRAW(x, y, n) => 0xD000 | xy(x, y) | (n as u16 & 0xf),
+            ISA::SKPR(x) => 0xE09E | xy(x, 0),
+            ISA::SKUP(x) => 0xE0A1 | xy(x, 0),
+            ISA::MOVED(x) => 0xF007 | xy(x, 0),
+            ISA::KEYD(x) => 0xF00A | xy(x, 0),
+            ISA::LOADD(x) => 0xF015 | xy(x, 0),
+            ISA::LOADS(x) => 0xF018 | xy(x, 0),
+            ISA::ADDI(x) => 0xF01E | xy(x, 0),
+            ISA::LDSPR(x) => 0xF029 | xy(x, 0),
+            ISA::BCD(x) => 0xF033 | xy(x, 0),
+            ISA::STOR(x) => 0xF055 | xy(x, 0),
+            ISA::READ(x) => 0xF065 | xy(x, 0),
+            ISA::PITCH(x) => 0xF03A | xy(x, 0),
+            ISA::AUDIO => 0xF002,
+            ISA::NOP(c) => c,
+        }
+    }
+
+    // instruction length in bytes
+    pub fn size(&self) -> usize {
+        2
+    }
+
+    // control flow changes to constant or computed target
+    pub fn is_branch(&self) -> bool {
+        matches!(self, ISA::JP(_) | ISA::CALL(_) | ISA::RET | ISA::JUMPI(..) | ISA::SYS(_))
+    }
+
+    // conditionally skips next instruction
+    pub fn is_skip(&self) -> bool {
+        matches!(self, ISA::SKE(..) | ISA::SKNE(..) | ISA::SKRE(..) | ISA::SKRNE(..) | ISA::SKPR(_) | ISA::SKUP(_))
+    }
+
+    // touches framebuffer
+    pub fn is_draw(&self) -> bool {
+        matches!(self, ISA::DRAW(..) | ISA::CLS | ISA::LORES | ISA::HIRES | ISA::SCD(_) | ISA::SCR | ISA::SCL)
+    }
+
+    pub fn operands(&self) -> Operands {
+        match *self {
+            ISA::CLS | ISA::RET | ISA::LORES | ISA::HIRES | ISA::SCR | ISA::SCL | ISA::EXIT | ISA::AUDIO => Operands::None,
+            ISA::SCD(n) => Operands::Nibble(n),
+            ISA::SYS(a) | ISA::JP(a) | ISA::CALL(a) | ISA::LOADI(a) | ISA::JUMPI(a, _) => Operands::Addr(a),
+            ISA::SKE(x, n) | ISA::SKNE(x, n) | ISA::LOAD(x, n) | ISA::ADD(x, n) | ISA::RAND(x, n) => {
+                Operands::RegByte(x, n)
+            }
+            ISA::SKRE(x, y) | ISA::MOVE(x, y) | ISA::OR(x, y) | ISA::AND(x, y) | ISA::XOR(x, y)
+            | ISA::ADDR(x, y) | ISA::SUB(x, y) | ISA::SHR(x, y) | ISA::SUBN(x, y) | ISA::SHL(x, y)
+            | ISA::SKRNE(x, y) => Operands::RegReg(x, y),
+            ISA::DRAW(x, y, n) => Operands::RegRegNibble(x, y, n),
+            ISA::SKPR(x) | ISA::SKUP(x) | ISA::MOVED(x) | ISA::KEYD(x) | ISA::LOADD(x) | ISA::LOADS(x)
+            | ISA::ADDI(x) | ISA::LDSPR(x) | ISA::BCD(x) | ISA::STOR(x) | ISA::READ(x) | ISA::PITCH(x) => Operands::Reg(x),
+            ISA::NOP(c) => Operands::Word(c),
+        }
+    }
+
+    // Plain English explanation, e.g. "Set register VA to 0x0F"
+    pub fn describe(&self) -> String {
+        match *self {
+            ISA::CLS => "Clear the screen".to_string(),
+            ISA::RET => "Return from subroutine".to_string(),
+            ISA::LORES => "Switch to 64x32 low resolution".to_string(),
+            ISA::HIRES => "Switch to 128x64 high resolution".to_string(),
+            ISA::SCD(n) => format!("Scroll the screen down {} lines", n),
+            ISA::SCR => "Scroll the screen right 4 pixels".to_string(),
+            ISA::SCL => "Scroll the screen left 4 pixels".to_string(),
+            ISA::EXIT => "Exit the interpreter".to_string(),
+            ISA::SYS(a) => format!("Call machine code routine at 0x{:03X}", a),
+            ISA::JP(a) => format!("Jump to 0x{:03X}", a),
+            ISA::CALL(a) => format!("Call subroutine at 0x{:03X}", a),
+            ISA::SKE(x, n) => format!("Skip next instruction if V{:X} equals 0x{:02X}", x, n),
+            ISA::SKNE(x, n) => format!("Skip next instruction if V{:X} does not equal 0x{:02X}", x, n),
+            ISA::SKRE(x, y) => format!("Skip next instruction if V{:X} equals V{:X}", x, y),
+            ISA::LOAD(x, n) => format!("Set register V{:X} to 0x{:02X}", x, n),
+            ISA::ADD(x, n) => format!("Add 0x{:02X} to V{:X} without carry", n, x),
+            ISA::MOVE(x, y) => format!("Copy V{:X} into V{:X}", y, x),
+            ISA::OR(x, y) => format!("Set V{:X} to V{:X} OR V{:X}", x, x, y),
+            ISA::AND(x, y) => format!("Set V{:X} to V{:X} AND V{:X}", x, x, y),
+            ISA::XOR(x, y) => format!("Set V{:X} to V{:X} XOR V{:X}", x, x, y),
+            ISA::ADDR(x, y) => format!("Add V{:X} to V{:X}, VF set on carry", y, x),
+            ISA::SUB(x, y) => format!("Subtract V{:X} from V{:X}, VF cleared on borrow", y, x),
+            ISA::SHR(x, y) => format!("Shift V{:X} (or V{:X}) right into V{:X}, VF gets shifted out bit", x, y, x),
+            ISA::SUBN(x, y) => format!("Set V{:X} to V{:X} minus V{:X}, VF cleared on borrow", x, y, x),
+            ISA::SHL(x, y) => format!("Shift V{:X} (or V{:X}) left into V{:X}, VF gets shifted out bit", x, y, x),
+            ISA::SKRNE(x, y) => format!("Skip next instruction if V{:X} does not equal V{:X}", x, y),
+            ISA::LOADI(a) => format!("Set I to 0x{:03X}", a),
+            ISA::JUMPI(a, x) => format!("Jump to 0x{:03X} plus V0, or V{:X} with jump_uses_vx", a, x),
+            ISA::RAND(x, n) => format!("Set V{:X} to random byte AND 0x{:02X}", x, n),
+            ISA::DRAW(x, y, n) => format!("Draw {} byte sprite from I at (V{:X}, V{:X}), VF set on collision", n, x, y),
+            ISA::SKPR(x) => format!("Skip next instruction if key in V{:X} is pressed", x),
+            ISA::SKUP(x) => format!("Skip next instruction if key in V{:X} is not pressed", x),
+            ISA::MOVED(x) => format!("Set V{:X} to delay timer", x),
+            ISA::KEYD(x) => format!("Wait for key press and store it in V{:X}", x),
+            ISA::LOADD(x) => format!("Set delay timer to V{:X}", x),
+            ISA::LOADS(x) => format!("Set sound timer to V{:X}", x),
+            ISA::ADDI(x) => format!("Add V{:X} to I", x),
+            ISA::LDSPR(x) => format!("Point I at font glyph for digit in V{:X}", x),
+            ISA::BCD(x) => format!("Store decimal digits of V{:X} at I, I+1 and I+2", x),
+            ISA::STOR(x) => format!("Store V0 through V{:X} in memory starting at I", x),
+            ISA::READ(x) => format!("Load V0 through V{:X} from memory starting at I", x),
+            ISA::PITCH(x) => format!("Set audio pitch to V{:X}", x),
+            ISA::AUDIO => "Load 16 byte audio pattern from I".to_string(),
+            ISA::NOP(c) => format!("Unknown or invalid opcode 0x{:04X}", c),
+        }
+    }
+}
+
 impl Display for ISA {
     fn fmt(&self, f: &mut Formatter) -> Result {
         match self {
             ISA::CLS => write!(f, "CLS"),
             ISA::RET => write!(f, "RET"),
+            ISA::LORES => write!(f, "LORES"),
+            ISA::HIRES => write!(f, "HIRES"),
+            ISA::SCD(n) => write!(f, "SCD ${:X}", n),
+            ISA::SCR => write!(f, "SCR"),
+            ISA::SCL => write!(f, "SCL"),
+            ISA::EXIT => write!(f, "EXIT"),
             ISA::SYS(a) => write!(f, "SYS ${:03X}", a),
             ISA::JP(a) => write!(f, "JP ${:03X}", a),
             ISA::CALL(a) => write!(f, "CALL ${:03X}", a),
@@ -59,7 +278,7 @@ impl Display for ISA {
             ISA::ADD(x, n) => write!(f, "ADD r{:X}, ${:03X}", x, n),
             ISA::MOVE(x, y) => write!(f, "MOVE r{:X}, r{:X}", x, y),
             ISA::OR(x, y) => write!(f, "OR r{:X}, r{:X}", x, y),
-            ISA::AND(x, y) => write!(f, "ADD r{:X}, r{:X}", x, y),
+            ISA::AND(x, y) => write!(f, "AND r{:X}, r{:X}", x, y),
             ISA::XOR(x, y) => write!(f, "XOR r{:X}, r{:X}", x, y),
             ISA::ADDR(x, y) => write!(f, "ADDR r{:X}, r{:X}", x, y),
             ISA::SUB(x, y) => write!(f, "SUB r{:X}, r{:X}", x, y),
@@ -68,7 +287,7 @@ impl Display for ISA {
             ISA::SHL(x, y) => write!(f, "SHL r{:X}, r{:X}", x, y),
             ISA::SKRNE(x, y) => write!(f, "SKRNE r{:X}, r{:X}", x, y),
             ISA::LOADI(n) => write!(f, "LOADI ${:03X}", n),
-            ISA::JUMPI(n) => write!(f, "JUMPI ${:03X}", n),
+            ISA::JUMPI(n, _) => write!(f, "JUMPI ${:03X}", n),
             ISA::RAND(x, n) => write!(f, "RAND r{:X}, ${:03X}", x, n),
             ISA::DRAW(x, y, n) => write!(f, "DRAW r{:X}, r{:X}, ${:X}", x, y, n),
             ISA::SKPR(x) => write!(f, "SKPR r{:X}", x),
@@ -82,16 +301,101 @@ impl Display for ISA {
             ISA::BCD(x) => write!(f, "BCD r{:X}", x),
             ISA::STOR(x) => write!(f, "STOR ${:X}", x),
             ISA::READ(x) => write!(f, "READ ${:X}", x),
+            ISA::PITCH(x) => write!(f, "PITCH r{:X}", x),
+            ISA::AUDIO => write!(f, "AUDIO"),
             ISA::NOP(c) => write!(f, "NOP ${:04X}", c),
         }
     }
 }
 
+// Opcode (pattern, mask, mnemonic) as matched by decode, first match wins
+const MNEMONICS: [(u16, u16, &str); 43] = [
+    (0x00E0, 0xFFFF, "CLS"),
+    (0x00EE, 0xFFFF, "RET"),
+    (0x00FE, 0xFFFF, "LORES"),
+    (0x00FF, 0xFFFF, "HIRES"),
+    (0x00C0, 0xFFF0, "SCD"),
+    (0x00FB, 0xFFFF, "SCR"),
+    (0x00FC, 0xFFFF, "SCL"),
+    (0x00FD, 0xFFFF, "EXIT"),
+    (0x0000, 0xF000, "SYS"),
+    (0x1000, 0xF000, "JP"),
+    (0x2000, 0xF000, "CALL"),
+    (0x3000, 0xF000, "SKE"),
+    (0x4000, 0xF000, "SKNE"),
+    (0x5000, 0xF000, "SKRE"),
+    (0x6000, 0xF000, "LOAD"),
+    (0x7000, 0xF000, "ADD"),
+    (0x8000, 0xF00F, "MOVE"),
+    (0x8001, 0xF00F, "OR"),
+    (0x8002, 0xF00F, "AND"),
+    (0x8003, 0xF00F, "XOR"),
+    (0x8004, 0xF00F, "ADDR"),
+    (0x8005, 0xF00F, "SUB"),
+    (0x8006, 0xF00F, "SHR"),
+    (0x8007, 0xF00F, "SUBN"),
+    (0x800E, 0xF00F, "SHL"),
+    (0x9000, 0xF000, "SKRNE"),
+    (0xA000, 0xF000, "LOADI"),
+    (0xB000, 0xF000, "JUMPI"),
+    (0xC000, 0xF000, "RAND"),
+    (0xD000, 0xF000, "DRAW"),
+    (0xE09E, 0xF0FF, "SKPR"),
+    (0xE0A1, 0xF0FF, "SKUP"),
+    (0xF002, 0xFFFF, "AUDIO"),
+    (0xF007, 0xF0FF, "MOVED"),
+    (0xF00A, 0xF0FF, "KEYD"),
+    (0xF015, 0xF0FF, "LOADD"),
+    (0xF018, 0xF0FF, "LOADS"),
+    (0xF01E, 0xF0FF, "ADDI"),
+    (0xF029, 0xF0FF, "LDSPR"),
+    (0xF033, 0xF0FF, "BCD"),
+    (0xF03A, 0xF0FF, "PITCH"),
+    (0xF055, 0xF0FF, "STOR"),
+    (0xF065, 0xF0FF, "READ"),
+];
+
+pub fn mnemonic_table() -> Vec<(u16, u16, &'static str)> {
+    MNEMONICS.to_vec()
+}
+
 pub fn decode<'a>(bytes: &[u8]) -> Option<ISA> {
     let high = bytes.get(0)?;
     let low = bytes.get(1)?;
-    let opcode = ((*high as u16) << 8) | (*low as u16);
-    let op = match opcode & 0xf000 {
+    Some(decode_pair((*high, *low)))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecodeError {
+    Truncated,         // less than two bytes
+    InvalidOpcode(u16),
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            DecodeError::Truncated => write!(f, "truncated instruction"),
+            DecodeError::InvalidOpcode(opcode) => write!(f, "invalid opcode ${:04X}", opcode),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for DecodeError {}
+
+// Like decode but invalid words are errors instead of NOP, tells data from code
+pub fn decode_strict(bytes: &[u8]) -> core::result::Result<ISA, DecodeError> {
+    match decode(bytes) {
+        Some(ISA::NOP(opcode)) => Err(DecodeError::InvalidOpcode(opcode)),
+        Some(op) => Ok(op),
+        None => Err(DecodeError::Truncated),
+    }
+}
+
+// Decode instruction from its big endian byte pair
+pub fn decode_pair((high, low): (u8, u8)) -> ISA {
+    let opcode = ((high as u16) << 8) | (low as u16);
+    match opcode & 0xf000 {
         0x0000 => {
             if opcode == 0x00e0 {
                 ISA::CLS
@@ -99,6 +403,25 @@ pub fn decode<'a>(bytes: &[u8]) -> Option<ISA> {
             else if opcode == 0x00ee {
                 ISA::RET
             }
+            else if opcode == 0x00fe {
+                ISA::LORES
+            }
+            else if opcode == 0x00ff {
+                ISA::HIRES
+            }
+            // SCHIP scroll and exit, SYS to $0Cn/$0FB..$0FD is not a useful target
+            else if opcode & 0xfff0 == 0x00c0 {
+                ISA::SCD((opcode & 0x000f).into())
+            }
+            else if opcode == 0x00fb {
+                ISA::SCR
+            }
+            else if opcode == 0x00fc {
+                ISA::SCL
+            }
+            else if opcode == 0x00fd {
+                ISA::EXIT
+            }
             else {
                 ISA::SYS((opcode & 0x0fff).into())
             }
@@ -128,7 +451,7 @@ pub fn decode<'a>(bytes: &[u8]) -> Option<ISA> {
         }
         0x9000 => ISA::SKRNE(((opcode & 0x0f00) >> 8).into(), ((opcode & 0x00f0) >> 4).into()),
         0xA000 => ISA::LOADI((opcode & 0x0fff).into()),
-        0xB000 => ISA::JUMPI((opcode & 0x0fff).into()),
+        0xB000 => ISA::JUMPI((opcode & 0x0fff).into(), ((opcode & 0x0f00) >> 8).into()),
         0xC000 => ISA::RAND(((opcode & 0x0f00) >> 8).into(), (opcode & 0x00ff) as u8),
         0xD000 => ISA::DRAW(
             ((opcode & 0x0f00) >> 8).into(),
@@ -155,20 +478,98 @@ pub fn decode<'a>(bytes: &[u8]) -> Option<ISA> {
                 0x33 => ISA::BCD(x),
                 0x55 => ISA::STOR(x),
                 0x65 => ISA::READ(x),
+                0x3A => ISA::PITCH(x),
+                0x02 if x == 0 => ISA::AUDIO,
                 _ => ISA::NOP(opcode)
             }
         }
         _ => ISA::NOP(opcode)
-    };
-    Some(op)
+    }
+}
+
+// Instruction that differs between two ROMs, None where a ROM has no
+// complete instruction at addr
+#[derive(Debug, Clone, PartialEq)]
+pub struct DisasmDiff {
+    pub addr: usize,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+// Linear sweep of both ROMs loaded at base, reporting changed instructions
+pub fn diff_roms(a: &[u8], b: &[u8], base: usize) -> Vec<DisasmDiff> {
+    let chunk = |rom: &[u8], offset: usize| rom.get(offset..rom.len().min(offset + 2)).unwrap_or(&[]).to_vec();
+    (0..a.len().max(b.len())).step_by(2)
+        .filter(|&offset| chunk(a, offset) != chunk(b, offset))
+        .map(|offset| DisasmDiff {
+            addr: base + offset,
+            old: decode(&chunk(a, offset)).map(|op| op.to_string()),
+            new: decode(&chunk(b, offset)).map(|op| op.to_string()),
+        })
+        .collect()
+}
+
+// Line of annotated listing, bytes inside data ranges are never decoded
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisasmLine {
+    Code { addr: usize, opcode: u16, op: ISA },
+    Data { addr: usize, byte: u8 },
+}
+
+impl DisasmLine {
+    pub fn addr(&self) -> usize {
+        match *self {
+            DisasmLine::Code { addr, .. } | DisasmLine::Data { addr, .. } => addr,
+        }
+    }
+}
+
+impl Display for DisasmLine {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            DisasmLine::Code { op, .. } => write!(f, "{}", op),
+            DisasmLine::Data { byte, .. } => write!(f, "DB 0x{:02X}", byte),
+        }
+    }
+}
+
+// Linear sweep of ROM loaded at base, addresses in data ranges become single
+// data bytes, as do instructions cut short by a data range or ROM end
+pub fn disassemble(bytes: &[u8], base: usize, data: &[Range<usize>]) -> Vec<DisasmLine> {
+    let is_data = |addr: usize| data.iter().any(|range| range.contains(&addr));
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let addr = base + offset;
+        match bytes.get(offset..offset + 2) {
+            Some(pair) if !is_data(addr) && !is_data(addr + 1) => {
+                let (high, low) = (pair[0], pair[1]);
+                lines.push(DisasmLine::Code { addr, opcode: u16::from_be_bytes([high, low]), op: decode_pair((high, low)) });
+                offset += 2;
+            }
+            _ => {
+                lines.push(DisasmLine::Data { addr, byte: bytes[offset] });
+                offset += 1;
+            }
+        }
+    }
+    lines
 }
 
 #[cfg(test)]
 #[test]
 fn test_decode() {
-    let tests: [([u8; 2], ISA); 35] = [
+    let tests: [([u8; 2], ISA); 46] = [
         ([0x00, 0xE0], ISA::CLS),
         ([0x00, 0xEE], ISA::RET),
+        ([0x00, 0xFE], ISA::LORES),
+        ([0x00, 0xFF], ISA::HIRES),
+        ([0x00, 0xC0], ISA::SCD(0)),
+        ([0x00, 0xCF], ISA::SCD(15)),
+        ([0x00, 0xFB], ISA::SCR),
+        ([0x00, 0xFC], ISA::SCL),
+        ([0x00, 0xFD], ISA::EXIT),
+        ([0x00, 0xBF], ISA::SYS(0x0BF)),
         ([0x0F, 0xFF], ISA::SYS(4095)),
         ([0x1F, 0xFF], ISA::JP(4095)),
         ([0x2F, 0xFF], ISA::CALL(4095)),
@@ -188,7 +589,8 @@ fn test_decode() {
         ([0x8F, 0xFE], ISA::SHL(15, 15)),
         ([0x9F, 0xFF], ISA::SKRNE(15, 15)),
         ([0xAF, 0xFF], ISA::LOADI(4095)),
-        ([0xBF, 0xFF], ISA::JUMPI(4095)),
+        ([0xBF, 0xFF], ISA::JUMPI(4095, 15)),
+        ([0xB1, 0x23], ISA::JUMPI(0x123, 1)),
         ([0xCF, 0xFF], ISA::RAND(15, 255)),
         ([0xDF, 0xFF], ISA::DRAW(15, 15, 15)),
         ([0xEF, 0x9E], ISA::SKPR(15)),
@@ -202,10 +604,122 @@ fn test_decode() {
         ([0xFF, 0x33], ISA::BCD(15)),
         ([0xFF, 0x55], ISA::STOR(15)),
         ([0xFF, 0x65], ISA::READ(15)),
+        ([0xFF, 0x3A], ISA::PITCH(15)),
+        ([0xF0, 0x02], ISA::AUDIO),
     ];
     for (data, expected) in tests.iter() {
         let op = decode(&data[..]);
         assert!(op.is_some());
         assert_eq!(op.unwrap(), *expected);
+        assert_eq!(decode(&expected.encode().to_be_bytes()).as_ref(), Some(expected));
     }
 }
+
+#[cfg(test)]
+#[test]
+fn test_operands() {
+    assert_eq!(ISA::CLS.operands(), Operands::None);
+    assert_eq!(ISA::JP(0x200).operands(), Operands::Addr(0x200));
+    assert_eq!(ISA::LOAD(3, 0x0A).operands(), Operands::RegByte(3, 0x0A));
+    assert_eq!(ISA::SUB(1, 2).operands(), Operands::RegReg(1, 2));
+    assert_eq!(ISA::BCD(5).operands(), Operands::Reg(5));
+    assert_eq!(ISA::SCD(3).operands(), Operands::Nibble(3));
+    assert_eq!(ISA::DRAW(1, 2, 5).operands(), Operands::RegRegNibble(1, 2, 5));
+    assert_eq!(ISA::NOP(0xFFFF).operands(), Operands::Word(0xFFFF));
+}
+
+#[cfg(test)]
+#[test]
+fn test_classification() {
+    assert!(ISA::CALL(0x300).is_branch());
+    assert!(ISA::RET.is_branch());
+    assert!(!ISA::SKE(0, 1).is_branch());
+    assert!(ISA::SKUP(1).is_skip());
+    assert!(!ISA::ADD(0, 1).is_skip());
+    assert!(ISA::DRAW(0, 1, 5).is_draw());
+    assert!(ISA::CLS.is_draw());
+    assert!(!ISA::LDSPR(0).is_draw());
+}
+
+#[cfg(test)]
+#[test]
+fn test_opcode_group() {
+    assert_eq!(opcode_group(0x00E0), OpGroup::Zero);
+    assert_eq!(opcode_group(0x1200), OpGroup::Jump);
+    assert_eq!(opcode_group(0x8124), OpGroup::Arithmetic);
+    assert_eq!(opcode_group(0xD015), OpGroup::Draw);
+    assert_eq!(opcode_group(0xE19E), OpGroup::EGroup);
+    assert_eq!(opcode_group(0xFFFF), OpGroup::FGroup);
+}
+
+#[cfg(test)]
+#[test]
+fn test_mnemonic_table() {
+    let table = mnemonic_table();
+    for &(pattern, mask, name) in table.iter() {
+        for &operands in [0x0000, 0x0FFF & !mask, 0x0ABC & !mask].iter() {
+            let opcode = pattern | operands;
+            let first = table.iter().find(|&&(p, m, _)| opcode & m == p).unwrap();
+            if first.2 != name {
+                // shadowed by more specific entry, e.g. SYS $0E0 is CLS
+                continue;
+            }
+            let text = decode(&opcode.to_be_bytes()).unwrap().to_string();
+            assert_eq!(text.split(' ').next(), Some(name));
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_display_shift_keeps_vy() {
+    // VIP shifts read Vy so disassembly must not drop it
+    assert_eq!(format!("{}", ISA::SHR(1, 2)), "SHR r1, r2");
+    assert_eq!(format!("{}", ISA::SHL(0xA, 0xB)), "SHL rA, rB");
+    assert_eq!(decode(&[0x81, 0x26]), Some(ISA::SHR(1, 2)));
+}
+
+#[cfg(test)]
+#[test]
+fn test_diff_roms() {
+    let a = [0x60, 0x05, 0x70, 0x01, 0x12, 0x02];
+    let mut b = a;
+    b[3] = 0x02;
+    assert_eq!(diff_roms(&a, &b, 0x200), vec![DisasmDiff {
+        addr: 0x202,
+        old: Some(ISA::ADD(0, 1).to_string()),
+        new: Some(ISA::ADD(0, 2).to_string()),
+    }]);
+    assert!(diff_roms(&a, &a, 0x200).is_empty());
+    // extra instruction and trailing odd byte
+    let diff = diff_roms(&a[..4], &[0x60, 0x05, 0x70, 0x01, 0x00, 0xE0, 0xFF], 0x200);
+    assert_eq!(diff.len(), 2);
+    assert_eq!((diff[0].addr, diff[0].old.as_deref(), diff[0].new.as_deref()), (0x204, None, Some("CLS")));
+    assert_eq!((diff[1].addr, diff[1].old.as_deref(), diff[1].new.as_deref()), (0x206, None, None));
+}
+
+#[cfg(test)]
+#[test]
+fn test_disassemble_data() {
+    // LOADI $206, DRAW r0, r0, $3, JP $204, sprite $F0 $90 $F0
+    let rom = [0xA2, 0x06, 0xD0, 0x03, 0x12, 0x04, 0xF0, 0x90, 0xF0];
+    let lines = disassemble(&rom, 0x200, &[0x206..0x209]);
+    let text: Vec<String> = lines.iter().map(|line| line.to_string()).collect();
+    assert_eq!(text, ["LOADI $206", "DRAW r0, r0, $3", "JP $204", "DB 0xF0", "DB 0x90", "DB 0xF0"]);
+    assert_eq!(lines[3], DisasmLine::Data { addr: 0x206, byte: 0xF0 });
+    assert_eq!(lines[2], DisasmLine::Code { addr: 0x204, opcode: 0x1204, op: ISA::JP(0x204) });
+    // data starting mid-instruction splits it into bytes
+    let lines = disassemble(&rom[..4], 0x200, &[0x203..0x204]);
+    assert_eq!(lines.iter().map(DisasmLine::addr).collect::<Vec<usize>>(), [0x200, 0x202, 0x203]);
+    assert_eq!(disassemble(&rom, 0x200, &[]).len(), 5);
+}
+
+#[cfg(test)]
+#[test]
+fn test_decode_strict() {
+    assert_eq!(decode_strict(&[0x81, 0x2F]), Err(DecodeError::InvalidOpcode(0x812F)));
+    assert_eq!(decode(&[0x81, 0x2F]), Some(ISA::NOP(0x812F)));
+    assert_eq!(decode_strict(&[0x81, 0x24]), Ok(ISA::ADDR(1, 2)));
+    assert_eq!(decode_strict(&[0x81]), Err(DecodeError::Truncated));
+    assert_eq!(DecodeError::InvalidOpcode(0x812F).to_string(), "invalid opcode $812F");
+}
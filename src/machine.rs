@@ -1,16 +1,42 @@
+#[cfg(feature = "std")]
 use std::io::prelude::*;
+#[cfg(feature = "std")]
 use std::io::Result;
+#[cfg(feature = "std")]
 use std::io::BufReader;
+#[cfg(feature = "std")]
 use std::fs::File;
-use std::fmt;
-use std::fmt::Display;
+#[cfg(feature = "std")]
+use std::time::{Duration, Instant};
+#[cfg(feature = "std")]
+use std::thread;
+use core::fmt;
+use core::fmt::Display;
+use core::mem::Discriminant;
+use core::ops::Range;
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+#[cfg(test)]
+use alloc::string::ToString;
+use alloc::boxed::Box;
+use alloc::format;
 use itertools::Itertools;
 use rand::prelude::*;
+use rand::rngs::StdRng;
 
 use crate::isa::ISA;
 use crate::isa::decode;
+use crate::isa::decode_pair;
+use crate::asm::assemble;
+use crate::asm::AsmError;
+use crate::quirks::Quirks;
+use crate::quirks::Target;
+use crate::render::Renderer;
+use crate::render::NullRenderer;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CPU {
     pub r: [u8; 16], // general purpose registers
     pub i: usize,    // adressing register (16 bits)
@@ -44,11 +70,27 @@ impl Display for CPU {
     }
 }
 
+pub const DEFAULT_STACK_DEPTH: usize = 16;
+pub const DEFAULT_RAM_SIZE: usize = 4096;
+pub const XOCHIP_RAM_SIZE: usize = 65536;
+
+// Save state layout: magic, version, hires flag, RAM size u32, stack slots
+// u16, sp u16, i u32, pc u32, dt, st, then registers, keys, RAM, stack u16s
+// and both framebuffer planes, all little endian
+const STATE_MAGIC: &[u8; 4] = b"C8ST";
+const STATE_VERSION: u8 = 1;
+const STATE_HEADER_LEN: usize = 24;
+
+#[derive(Clone)]
 pub struct Memory {
     pub rom: [u8; 80],      // up to 512 bytes
-    pub ram: [u8; 4096],    // 4k RAM
-    pub stack: [u16; 24],   // 24 call depth
-    pub fb: [u8; 64*32],  // 64x32 pixels framebuffer
+    pub ram: Vec<u8>,       // 4k RAM, 64k for XO-CHIP
+    pub stack: Vec<u16>,    // call depth slots plus entry point
+    pub fb: [u8; 128*64], // up to 128x64 pixels framebuffer
+    pub fb2: [u8; 128*64], // XO-CHIP second plane, blank until plane select
+    pub width: usize,     // active framebuffer width
+    pub height: usize,    // active framebuffer height
+    pub audio: [u8; 16],  // XO-CHIP 1-bit audio pattern
 }
 
 impl Default for Memory {
@@ -72,21 +114,25 @@ impl Default for Memory {
                 0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
                 0xF0, 0x80, 0xF0, 0x80, 0x80, // F
             ],
-            ram: [0; 4096],
-            stack: [0; 24],
-            fb: [0; 64*32]
+            ram: vec![0; DEFAULT_RAM_SIZE],
+            stack: vec![0; DEFAULT_STACK_DEPTH + 1],
+            fb: [0; 128*64],
+            fb2: [0; 128*64],
+            width: 64,
+            height: 32,
+            audio: [0; 16]
         }
     }
 }
 
 impl Display for Memory {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for line in self.fb.chunks(64) {
+        for line in self.fb[..self.width * self.height].chunks(self.width) {
             for pixel in line.iter() {
                 if *pixel != 0 {
-                    write!(f, " ")?;
-                } else {
                     write!(f, "0")?;
+                } else {
+                    write!(f, " ")?;
                 }
             }
             write!(f, "\n")?;
@@ -96,6 +142,26 @@ impl Display for Memory {
 }
 
 impl Memory {
+    // Zero RAM, stack, both framebuffer planes and audio pattern, font is reinstalled
+    pub fn clear(&mut self) {
+        self.ram.iter_mut().for_each(|byte| *byte = 0);
+        self.ram[0..self.rom.len()].copy_from_slice(&self.rom[..]);
+        self.stack.iter_mut().for_each(|slot| *slot = 0);
+        self.fb.iter_mut().for_each(|pixel| *pixel = 0);
+        self.fb2.iter_mut().for_each(|pixel| *pixel = 0);
+        self.audio = [0; 16];
+    }
+
+    fn load_bytes(&mut self, data: &[u8], addr: usize) -> usize {
+        // copy ROM into RAM
+        self.ram[0..self.rom.len()].copy_from_slice(&self.rom[..]);
+        // copy PROG into RAM
+        let size = data.len().min(self.ram.len() - addr);
+        self.ram[addr..(addr + size)].copy_from_slice(&data[..size]);
+        size
+    }
+
+    #[cfg(feature = "std")]
     fn load(&mut self, filename: &str, addr: usize) -> Result<usize> {
         let file = File::open(filename)?;
         let mut reader = BufReader::new(file);
@@ -114,254 +180,4667 @@ impl Memory {
         Ok(pos - addr)
     }
 
-    fn opcode(&mut self, addr: usize) -> &[u8] {
-        &self.ram[addr .. (addr + 2)]
+    // Framebuffer index of pixel i in sprite row j holding left aligned row
+    // bits, drawn at (px, py), None when the pixel is unset or clipped
+    fn sprite_pixel(&self, row: u16, (px, py): (usize, usize), (i, j): (usize, usize), clip: bool) -> Option<usize> {
+        if clip && (px + i >= self.width || py + j >= self.height) {
+            return None;
+        }
+        if row & (0x8000 >> i) == 0 {
+            return None;
+        }
+        Some(self.width * ((py + j) % self.height) + (px + i) % self.width)
+    }
+
+    // wrap address into RAM, 12 bits for CHIP-8 and 16 bits for XO-CHIP
+    fn addr(&self, x: usize) -> usize {
+        x & (self.ram.len() - 1)
+    }
+
+    // Instruction bytes at addr, None when they run past end of RAM
+    fn opcode(&self, addr: usize) -> Option<(u8, u8)> {
+        self.ram.get(addr).copied().zip(self.ram.get(addr.wrapping_add(1)).copied())
+    }
+}
+
+// XO-CHIP audio playback rate: 4000 * 2 ^ ((pitch - 64) / 48) Hz
+fn pitch_to_hz(pitch: u8) -> f32 {
+    let exponent = pitch as i32 - 64;
+    let octaves = exponent.div_euclid(48);
+    // 2 ^ frac = e ^ (frac * ln 2) from Taylor series, core has no powf
+    let y = exponent.rem_euclid(48) as f32 / 48.0 * core::f32::consts::LN_2;
+    let (mut term, mut fraction) = (1.0f32, 1.0f32);
+    for k in 1..8 {
+        term *= y / k as f32;
+        fraction += term;
+    }
+    let scale = if octaves >= 0 { (1u32 << octaves) as f32 } else { 1.0 / (1u32 << -octaves) as f32 };
+    4000.0 * scale * fraction
+}
+
+// ALU ops leaving a 0/1 flag in VF under given quirks
+fn writes_flag(op: &ISA, quirks: &Quirks) -> bool {
+    match op {
+        ISA::ADDR(..) | ISA::SUB(..) | ISA::SUBN(..) | ISA::SHR(..) | ISA::SHL(..) => true,
+        ISA::OR(..) | ISA::AND(..) | ISA::XOR(..) => quirks.logic_resets_vf,
+        _ => false,
+    }
+}
+
+// Debug builds check VF after ALU ops only ever holds a 0/1 flag
+#[cfg(debug_assertions)]
+fn check_vf(op: &ISA, vf: u8, quirks: &Quirks) {
+    debug_assert!(!writes_flag(op, quirks) || vf <= 1, "{} left VF = ${:02X}", op, vf);
+}
+
+#[derive(Debug, PartialEq)]
+pub struct HexError {
+    pub line: usize,    // 1-based line number
+    pub token: String,  // offending token
+}
+
+impl Display for HexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid hex byte '{}' at line {}", self.token, self.line)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for HexError {}
+
+#[derive(Debug, PartialEq)]
+pub struct OverlayError {
+    pub addr: usize,  // requested load address
+    pub len: usize,   // overlay size in bytes
+}
+
+impl Display for OverlayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "overlay of {} bytes at ${:03X} does not fit in RAM", self.len, self.addr)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for OverlayError {}
+
+#[derive(Debug, PartialEq)]
+pub struct EntryError {
+    pub addr: usize,  // rejected entry point
+}
+
+impl Display for EntryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "entry point ${:03X} is odd or past end of RAM", self.addr)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for EntryError {}
+
+// Save state rejected by restore
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    WrongLength { expected: usize, actual: usize },
+    BadRamSize(usize),
+    BadStackPointer(usize),
+    EmptyStack,
+}
+
+impl Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(f, "unsupported save state version {}", version),
+            StateError::WrongLength { expected, actual } => {
+                write!(f, "save state is {} bytes, expected {}", actual, expected)
+            }
+            StateError::BadRamSize(size) => write!(f, "save state RAM size {} is not supported", size),
+            StateError::BadStackPointer(sp) => write!(f, "save state stack pointer {} is past stack", sp),
+            StateError::EmptyStack => write!(f, "save state has no stack slots"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for StateError {}
+
+// Parse Octo-style hex dump, e.g. "0x12 0x00" or "1200, 00E0 # comment"
+fn parse_hex(text: &str) -> core::result::Result<Vec<u8>, HexError> {
+    let mut bytes = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let code = line.split('#').next().unwrap_or("");
+        for token in code.split(|c: char| c.is_whitespace() || c == ',').filter(|t| !t.is_empty()) {
+            let error = || HexError { line: index + 1, token: String::from(token) };
+            let digits = token.trim_start_matches("0x").trim_start_matches("0X");
+            if digits.is_empty() || (digits.len() > 2 && digits.len() % 2 != 0) {
+                return Err(error());
+            }
+            for chunk in digits.as_bytes().chunks(2) {
+                let chunk = core::str::from_utf8(chunk).map_err(|_| error())?;
+                bytes.push(u8::from_str_radix(chunk, 16).map_err(|_| error())?);
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Up = 0,
+    Down = 1,
+    Left = 2,
+    Right = 3,
+}
+
+// Key picked by KEYD when several keys are held
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyPolicy {
+    Lowest,      // lowest numbered key, default
+    MostRecent,  // key pressed last
+}
+
+// What step does when PC points past end of RAM, e.g. after bad JP or CALL.
+// Dry run never wraps or halts, Wrap fails with PcOutOfRange there.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PcPolicy {
+    Wrap,   // mask PC to RAM size and carry on
+    Halt,   // stop with RanPastMemory
+    Error,  // fail with PcOutOfRange, default
+}
+
+// How run_until_draw ended
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunStatus {
+    Drew(usize),  // instructions executed, last one changed the screen
+    CycleLimit,   // max_cycles ran without a screen change
+}
+
+#[derive(Debug, PartialEq)]
+pub struct StepOutcome {
+    pub pc: usize,      // address of executed instruction
+    pub op: ISA,        // decoded instruction
+    pub opcode: u16,    // raw instruction word
+    pub drew: bool,     // framebuffer was touched
+    pub branched: bool, // execution did not fall through
+    pub new_pc: usize,  // address of next instruction
+    pub key_waiting: bool, // KEYD is blocked waiting for key
+    pub written_addresses: Vec<usize>,  // RAM written by STOR/BCD, screen changes are in framebuffer_delta
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RomInfo {
+    pub bytes: usize,           // program size
+    pub base: usize,            // load address
+    pub first_op: Option<ISA>,  // instruction at entry point
+    pub uses_schip: bool,       // contains SCHIP only instructions
+    pub uses_xochip: bool,      // contains XO-CHIP only instructions
+    pub warnings: Vec<RomWarning>,
+}
+
+// Final state of load_and_run
+#[derive(Debug, PartialEq)]
+pub struct RunSummary {
+    pub cycles_run: usize,     // instructions executed
+    pub registers: [u8; 16],
+    pub i: usize,
+    pub pc: usize,
+    pub stop: StepError,       // Stopped(Halted) for a clean halt, CycleLimitReached at cap
+}
+
+// Suspicious but loadable ROM file, reported by load_validated
+#[derive(Debug, Clone, PartialEq)]
+pub enum RomWarning {
+    UnknownExtension,                // not .ch8 or .c8
+    Truncated { dropped: usize },    // bytes past end of RAM left out
+}
+
+impl Display for RomWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomWarning::UnknownExtension => write!(f, "file extension is not .ch8 or .c8"),
+            RomWarning::Truncated { dropped } => write!(f, "{} bytes past end of RAM were not loaded", dropped),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    Font,      // built-in hex digit glyphs
+    Reserved,  // original interpreter area
+    Program,   // loaded ROM
+    Free,      // unused RAM
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub end: usize,  // exclusive
+    pub kind: RegionKind,
+    pub label: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Stats {
+    pub cycles: usize,       // executed instructions
+    pub draws: usize,        // DRAW instructions plotted
+    pub skips_taken: usize,  // conditional skips that skipped
+}
+
+// Plain copy of CPU, memory and keys for setting up exact scenarios
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineSnapshot {
+    pub registers: [u8; 16],
+    pub i: usize,
+    pub pc: usize,
+    pub sp: usize,
+    pub dt: u8,
+    pub st: u8,
+    pub ram: Vec<u8>,
+    pub stack: Vec<u16>,
+    pub keys: [bool; 16],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    RanIntoEmptyMemory,  // fetched $0000, usually past end of program
+    RanPastMemory,       // PC has no full instruction left in RAM
+    Halted,              // earlier stop or jump to itself, see resume
+    CycleLimitReached,   // run used up its cycle budget without halting
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StepError {
+    InvalidOpcode { opcode: u16, addr: usize },
+    StackOverflow { addr: usize },
+    StackUnderflow { addr: usize },
+    WriteToProtectedMemory { addr: usize },
+    PcOutOfRange { pc: usize },
+    RamResized { addr: usize },
+    Stopped(StopReason),
+}
+
+impl Display for StepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StepError::InvalidOpcode { opcode, addr } => {
+                write!(f, "invalid opcode ${:04X} at ${:03X}", opcode, addr)
+            }
+            StepError::StackOverflow { addr } => {
+                write!(f, "stack overflow at ${:03X}", addr)
+            }
+            StepError::StackUnderflow { addr } => {
+                write!(f, "stack underflow at ${:03X}", addr)
+            }
+            StepError::WriteToProtectedMemory { addr } => {
+                write!(f, "write to protected memory at ${:03X}", addr)
+            }
+            StepError::PcOutOfRange { pc } => {
+                write!(f, "PC out of range at ${:03X}", pc)
+            }
+            StepError::RamResized { addr } => {
+                write!(f, "op handler resized RAM at ${:03X}", addr)
+            }
+            StepError::Stopped(reason) => write!(f, "stopped: {}", reason),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for StepError {}
+
+impl Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::RanIntoEmptyMemory => write!(f, "ran into empty memory"),
+            StopReason::RanPastMemory => write!(f, "ran past end of RAM"),
+            StopReason::Halted => write!(f, "machine is halted"),
+            StopReason::CycleLimitReached => write!(f, "cycle limit reached"),
+        }
     }
 }
 
+// State overwritten by single instruction
+#[derive(Clone)]
+struct UndoEntry {
+    cpu: CPU,
+    pending_key: Option<usize>,
+    resolution: (usize, usize),
+    audio: [u8; 16],
+    tone_hz: f32,
+    ram: Vec<(usize, u8)>,
+    stack: Vec<(usize, u16)>,
+    fb: Vec<(usize, u8)>,
+}
+
 pub struct Machine {
     pub keys: [bool; 16],
     cpu: CPU,
     memory: Memory,
+    rng: Box<dyn Rng8>,
+    seed_rng: Box<dyn Rng8>,
+    directions: [usize; 4],
+    quirks: Quirks,
+    i_mask: usize,
+    rom_len: usize,
+    entry_pc: usize,
+    randomize_ram: bool,
+    track_self_modify: bool,
+    self_modified: Vec<usize>,
+    decode_cache: Option<Vec<Option<(u16, ISA)>>>,
+    vf_events: Option<Vec<(usize, ISA, u8)>>,
+    trace: Option<Vec<(usize, usize, u16, ISA)>>,
+    uninit_reads: Option<Vec<(usize, ISA, usize)>>,
+    written_regs: u16,
+    misaligned: bool,
+    tone_hz: f32,
+    stop_on_empty: bool,
+    last_changed_reg: Option<usize>,
+    protect_font: bool,
+    protected: Option<Range<usize>>,
+    pending_key: Option<usize>,
+    key_waiting: bool,
+    halted: bool,
+    timers_frozen: bool,
+    key_hold: [u32; 16],
+    key_policy: KeyPolicy,
+    pc_policy: PcPolicy,
+    press_order: [u64; 16],
+    press_count: u64,
+    key_queue: VecDeque<(u64, usize, bool)>,
+    vblank: bool,
+    resolution_changed: bool,
+    stats: Stats,
+    spin_window: usize,
+    recent_pcs: VecDeque<usize>,
+    pc_history: Vec<usize>,
+    pc_history_len: usize,
+    collision_history: Vec<u32>,
+    collision_history_len: usize,
+    frame_collisions: u32,
+    fb_shadow: [u8; 128*64],
+    row_shadow: [u8; 128*64],
+    row_shadow_resolution: (usize, usize),
+    renderer: Box<dyn Renderer + Send>,
+    pre_hook: Option<PreHook>,
+    post_hook: Option<PostHook>,
+    op_handlers: Vec<(Discriminant<ISA>, OpHandler)>,
+    trace_callback: Option<PostHook>,
+    sound_callback: Option<SoundCallback>,
+    dt_zero_callback: Option<DtCallback>,
+    sound_on: bool,
+    undo_depth: usize,
+    undo_log: VecDeque<UndoEntry>,
+    recording: bool,
+    step_writes: Vec<usize>,
+}
+
+// Source of RAND bytes, seeded StdRng by default. Implement on a Clone type,
+// fork and reset_preserving_seed copy it through clone_box.
+pub trait Rng8: RngClone + Send {
+    fn next_byte(&mut self) -> u8;
+
+    // Used by randomize_ram, so loading a program consumes bytes of the
+    // source before the first RAND
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        dest.iter_mut().for_each(|byte| *byte = self.next_byte());
+    }
+}
+
+pub trait RngClone {
+    fn clone_box(&self) -> Box<dyn Rng8>;
+}
+
+impl<T: Rng8 + Clone + 'static> RngClone for T {
+    fn clone_box(&self) -> Box<dyn Rng8> {
+        Box::new(self.clone())
+    }
+}
+
+impl Rng8 for StdRng {
+    fn next_byte(&mut self) -> u8 {
+        self.gen()
+    }
+
+    // Same RAM contents per seed as before Rng8 existed
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        RngCore::fill_bytes(self, dest);
+    }
+}
+
+// Repeats given bytes forever, for test vectors recorded elsewhere
+#[derive(Clone)]
+pub struct CyclicRng {
+    bytes: Vec<u8>,
+    pos: usize,
+}
+
+impl CyclicRng {
+    pub fn new(bytes: Vec<u8>) -> CyclicRng {
+        assert!(!bytes.is_empty(), "empty byte sequence");
+        CyclicRng { bytes, pos: 0 }
+    }
+}
+
+impl Rng8 for CyclicRng {
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.bytes[self.pos];
+        self.pos = (self.pos + 1) % self.bytes.len();
+        byte
+    }
+}
+
+pub type PreHook = Box<dyn FnMut(usize, &ISA) + Send>;
+pub type PostHook = Box<dyn FnMut(&StepOutcome) + Send>;
+pub type OpHandler = Box<dyn FnMut(&mut CPU, &mut Memory, &ISA) + Send>;
+pub type SoundCallback = Box<dyn FnMut(bool) + Send>;
+pub type DtCallback = Box<dyn FnMut() + Send>;
+
+pub struct MachineBuilder {
+    stack_depth: usize,
+    randomize_ram: bool,
+}
+
+impl Default for MachineBuilder {
+    fn default() -> Self {
+        MachineBuilder::new()
+    }
+}
+
+impl MachineBuilder {
+    pub fn new() -> MachineBuilder {
+        MachineBuilder {
+            stack_depth: DEFAULT_STACK_DEPTH,
+            randomize_ram: false,
+        }
+    }
+
+    // Maximum number of nested CALLs
+    pub fn stack_depth(mut self, depth: usize) -> Self {
+        self.stack_depth = depth;
+        self
+    }
+
+    // Fill RAM above loaded program with random bytes instead of zeros,
+    // shakes out ROMs reading uninitialized memory
+    pub fn randomize_ram(mut self, enabled: bool) -> Self {
+        self.randomize_ram = enabled;
+        self
+    }
+
+    pub fn build(self) -> Machine {
+        let mut m = Machine::new();
+        m.memory.stack = vec![0; self.stack_depth + 1];
+        m.randomize_ram = self.randomize_ram;
+        m
+    }
+}
+
+impl Display for Machine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.cpu)?;
+        writeln!(f)?;
+        write!(f, "{}", self.memory)
+    }
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Machine::new()
+    }
 }
 
 impl Machine {
 
     pub fn new() -> Machine {
+        let rng = Machine::default_rng();
         Machine {
             cpu: CPU::default(),
             memory: Memory::default(),
             keys: [false; 16],
+            rng: rng.clone_box(),
+            seed_rng: rng,
+            directions: [0x2, 0x8, 0x4, 0x6],  // up, down, left, right
+            quirks: Quirks::for_target(Target::Chip8),
+            i_mask: 0xfff,
+            rom_len: 0,
+            entry_pc: 0x200,
+            randomize_ram: false,
+            track_self_modify: false,
+            self_modified: Vec::new(),
+            decode_cache: None,
+            vf_events: None,
+            trace: None,
+            uninit_reads: None,
+            written_regs: 0,
+            misaligned: false,
+            tone_hz: 440.0,
+            stop_on_empty: true,
+            last_changed_reg: None,
+            protect_font: false,
+            protected: None,
+            pending_key: None,
+            key_waiting: false,
+            halted: false,
+            timers_frozen: false,
+            key_hold: [0; 16],
+            key_policy: KeyPolicy::Lowest,
+            pc_policy: PcPolicy::Error,
+            press_order: [0; 16],
+            press_count: 0,
+            key_queue: VecDeque::new(),
+            vblank: true,
+            resolution_changed: false,
+            stats: Stats::default(),
+            spin_window: 16,
+            recent_pcs: VecDeque::new(),
+            pc_history: Vec::new(),
+            pc_history_len: 0,
+            collision_history: Vec::new(),
+            collision_history_len: 0,
+            frame_collisions: 0,
+            fb_shadow: [0; 128*64],
+            row_shadow: [0; 128*64],
+            row_shadow_resolution: (64, 32),
+            renderer: Box::new(NullRenderer),
+            pre_hook: None,
+            post_hook: None,
+            op_handlers: Vec::new(),
+            trace_callback: None,
+            sound_callback: None,
+            dt_zero_callback: None,
+            sound_on: false,
+            undo_depth: 0,
+            undo_log: VecDeque::new(),
+            recording: false,
+            step_writes: Vec::new(),
         }
     }
 
+    #[cfg(feature = "std")]
+    fn default_rng() -> Box<dyn Rng8> {
+        Box::new(StdRng::from_entropy())
+    }
+
+    #[cfg(not(feature = "std"))]
+    fn default_rng() -> Box<dyn Rng8> {
+        // no entropy source without std, callers should reseed
+        Box::new(StdRng::seed_from_u64(0))
+    }
+
+    // Reseed RAND for reproducible runs
+    pub fn set_seed(&mut self, seed: u64) {
+        self.set_rng(Box::new(StdRng::seed_from_u64(seed)));
+    }
+
+    // Replace RAND source, e.g. with a recorded byte sequence, the state at
+    // this point is what reset_preserving_seed rewinds to
+    pub fn set_rng(&mut self, rng: Box<dyn Rng8>) {
+        self.seed_rng = rng.clone_box();
+        self.rng = rng;
+    }
+
     pub fn cpu(&self) -> &CPU {
         &self.cpu
     }
 
+    /// Mutable CPU for setting up tool and test scenarios, changes bypass hooks and undo
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    // Set V0..VF and I in one go for scenario setup, I wraps to i_mask
+    pub fn set_register_bank(&mut self, regs: [u8; 16], i: usize) {
+        self.cpu.r = regs;
+        self.cpu.i = i & self.i_mask;
+    }
+
     pub fn mem(&self) -> &Memory {
         &self.memory
     }
 
-    pub fn reset(&mut self) {
-        self.cpu.pc = 0x200;  // common entry point
-        self.cpu.sp = self.memory.stack.len() -1;  // end of stack
-        self.memory.stack[self.cpu.sp] = self.cpu.pc as u16;  // entrypoint
+    /// Mutable RAM for patching tools, writes bypass any checks done by `step`
+    pub fn ram_mut(&mut self) -> &mut [u8] {
+        self.flush_decode_cache();
+        &mut self.memory.ram[..]
     }
 
-    pub fn load(&mut self, filename: &str) -> Result<usize> {
-        self.reset();
-        self.memory.load(filename, self.cpu.pc)
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
     }
 
-    pub fn step(&mut self) -> Option<(usize, ISA)> {
-        let pc = self.cpu.pc.clone();
-        let op = decode(self.memory.opcode(self.cpu.pc))?;
-        match op {
-            ISA::CLS => {
-                self.memory.fb.clone_from_slice(&[0; 64*32]);
-                self.cpu.pc += 2;
-            },
-            ISA::RET => {
-                self.cpu.pc = self.memory.stack[self.cpu.sp] as usize;
-                self.cpu.sp += 1;
-            },
-            ISA::SYS(n) => {
-                self.cpu.pc = n;
-            },
-            ISA::JP(n) => {
-                self.cpu.pc = n;
-            },
-            ISA::CALL(n) => {
-                self.cpu.sp -= 1;
-                self.cpu.pc += 2;
-                self.memory.stack[self.cpu.sp] = self.cpu.pc as u16;
-                self.cpu.pc = n;
-            },
-            ISA::SKE(x, n) => {
-                if self.cpu.r[x] == n {
-                    self.cpu.pc += 4;
-                } else {
-                    self.cpu.pc += 2;
+    pub fn quirks_mut(&mut self) -> &mut Quirks {
+        &mut self.quirks
+    }
+
+    pub fn i_mask(&self) -> usize {
+        self.i_mask
+    }
+
+    // Range I wraps to on ADDI and STOR/READ increments, follows RAM size
+    // unless narrowed here; RAM accesses still wrap to RAM size
+    pub fn set_i_mask(&mut self, mask: usize) {
+        self.i_mask = mask;
+        self.cpu.i &= mask;
+    }
+
+    // Also sizes RAM, XO-CHIP addresses 64k, and switches to lores every
+    // target starts in
+    pub fn set_target(&mut self, target: Target) {
+        self.quirks = Quirks::for_target(target);
+        self.set_ram_size(match target {
+            Target::XoChip => XOCHIP_RAM_SIZE,
+            Target::Chip8 | Target::SuperChip => DEFAULT_RAM_SIZE,
+        });
+        self.set_resolution(false);
+    }
+
+    // Resize RAM keeping contents below new size, addresses and I wrap to it.
+    // Size must be a power of two. A mask from set_i_mask is kept, clamped to
+    // new size.
+    pub fn set_ram_size(&mut self, size: usize) {
+        assert!(size.is_power_of_two() && size > self.memory.rom.len(), "invalid RAM size");
+        let custom_mask = self.i_mask != self.memory.ram.len() - 1;
+        self.memory.ram.resize(size, 0);
+        self.i_mask = if custom_mask { self.i_mask & (size - 1) } else { size - 1 };
+        self.cpu.i &= self.i_mask;
+        self.cpu.pc = self.memory.addr(self.cpu.pc);
+        self.entry_pc = self.memory.addr(self.entry_pc);
+        self.rom_len = self.rom_len.min(size - self.entry_pc);
+        if self.decode_cache.is_some() {
+            self.set_decode_cache(true);
+        }
+    }
+
+    pub fn resolution(&self) -> (usize, usize) {
+        (self.memory.width, self.memory.height)
+    }
+
+    pub fn set_resolution(&mut self, hires: bool) {
+        let (width, height) = if hires { (128, 64) } else { (64, 32) };
+        if (width, height) != self.resolution() {
+            self.resolution_changed = true;
+        }
+        self.memory.width = width;
+        self.memory.height = height;
+        self.clear_fb();
+    }
+
+    // New dimensions once after a mode switch, so front-ends know to resize
+    pub fn take_resolution_changed(&mut self) -> Option<(usize, usize)> {
+        if self.resolution_changed {
+            self.resolution_changed = false;
+            Some(self.resolution())
+        } else {
+            None
+        }
+    }
+
+    fn clear_fb(&mut self) {
+        for pixel in 0..self.memory.fb.len() {
+            self.write_fb(pixel, 0);
+        }
+    }
+
+    // Shift visible screen by (dx, dy) pixels, vacated pixels are cleared
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = self.resolution();
+        let source = self.memory.fb[..width * height].to_vec();
+        for (y, x) in (0..height).cartesian_product(0..width) {
+            let (sx, sy) = (x as isize - dx, y as isize - dy);
+            let inside = (0..width as isize).contains(&sx) && (0..height as isize).contains(&sy);
+            let value = if inside { source[sy as usize * width + sx as usize] } else { 0 };
+            self.write_fb(y * width + x, value);
+        }
+    }
+
+    fn write_fb(&mut self, pixel: usize, value: u8) {
+        if self.recording && self.memory.fb[pixel] != value {
+            if let Some(entry) = self.undo_log.back_mut() {
+                entry.fb.push((pixel, self.memory.fb[pixel]));
+            }
+        }
+        self.memory.fb[pixel] = value;
+    }
+
+    fn write_stack(&mut self, index: usize, value: u16) {
+        if self.recording {
+            if let Some(entry) = self.undo_log.back_mut() {
+                entry.stack.push((index, self.memory.stack[index]));
+            }
+        }
+        self.memory.stack[index] = value;
+    }
+
+    // Keep up to depth instructions for undo, zero disables it. RNG is not
+    // rewound, RAND after undo draws the next number.
+    pub fn enable_undo(&mut self, depth: usize) {
+        self.undo_depth = depth;
+        while self.undo_log.len() > depth {
+            self.undo_log.pop_front();
+        }
+    }
+
+    pub fn undo(&mut self) -> bool {
+        match self.undo_log.pop_back() {
+            Some(entry) => {
+                for (addr, value) in entry.ram.into_iter().rev() {
+                    self.invalidate_decoded(addr);
+                    self.memory.ram[addr] = value;
                 }
-            },
-            ISA::SKNE(x, n) => {
-                if self.cpu.r[x] != n {
-                    self.cpu.pc += 4;
-                } else {
-                    self.cpu.pc += 2;
+                for (index, value) in entry.stack.into_iter().rev() {
+                    self.memory.stack[index] = value;
                 }
-            },
-            ISA::SKRE(x, y) => {
-                if self.cpu.r[x] == self.cpu.r[y] {
-                    self.cpu.pc += 4;
-                } else {
-                    self.cpu.pc += 2;
+                for (pixel, value) in entry.fb.into_iter().rev() {
+                    self.memory.fb[pixel] = value;
                 }
-            },
-            ISA::LOAD(x, n) => {
-                self.cpu.r[x] = n;
-                self.cpu.pc += 2;
-            },
-            ISA::ADD(x, n) => {
-                self.cpu.r[x] = self.cpu.r[x].wrapping_add(n);
-                self.cpu.pc += 2;
-            },
-            ISA::MOVE(x, y) => {
-                self.cpu.r[x] = self.cpu.r[y];
-                self.cpu.pc += 2;
-            },
-            ISA::OR(x, y) => {
-                self.cpu.r[x] |= self.cpu.r[y];
-                self.cpu.pc += 2;
-            },
-            ISA::AND(x, y) => {
-                self.cpu.r[x] &= self.cpu.r[y];
-                self.cpu.pc += 2;
-            },
-            ISA::XOR(x, y) => {
-                self.cpu.r[x] ^= self.cpu.r[y];
-                self.cpu.pc += 2;
-            },
-            ISA::ADDR(x, y) => {
-                self.cpu.r[0xf] = match self.cpu.r[x].checked_add(self.cpu.r[y]) {
-                    Some(val) => {self.cpu.r[x] = val; 0}
-                    None => {self.cpu.r[x] = self.cpu.r[x].wrapping_add(self.cpu.r[y]); 1}
-                };
-                self.cpu.pc += 2;
-            },
-            ISA::SUB(x, y) => {
-                self.cpu.r[0xf] = match self.cpu.r[x].checked_sub(self.cpu.r[y]) {
-                    Some(val) => {self.cpu.r[x] = val; 1}
-                    None => {self.cpu.r[x] = self.cpu.r[x].wrapping_sub(self.cpu.r[y]); 0}
-                };
-                self.cpu.pc += 2;
-            },
-            ISA::SHR(x, _y) => {
-                self.cpu.r[0xf] = self.cpu.r[x] & 0x1;
-                self.cpu.r[x] = self.cpu.r[x].wrapping_div(2);
-                self.cpu.pc += 2;
-            },
-            ISA::SHL(x, _y) => {
-                self.cpu.r[0xf] = (self.cpu.r[x] & 0x80) >> 7;
-                self.cpu.r[x] = self.cpu.r[x].wrapping_mul(2);
-                self.cpu.pc += 2;
-            },
-            ISA::SUBN(x, y) => {
-                self.cpu.r[0xf] = match self.cpu.r[y].checked_sub(self.cpu.r[x]) {
-                    Some(val) => {self.cpu.r[x] = val; 1}
-                    None => {self.cpu.r[x] = self.cpu.r[y].wrapping_sub(self.cpu.r[x]); 0}
-                };
-                self.cpu.pc += 2;
-            },
-            ISA::SKRNE(x, y) => {
-                if self.cpu.r[x] != self.cpu.r[y] {
-                    self.cpu.pc += 4;
-                } else {
-                    self.cpu.pc += 2;
-                }
-            },
-            ISA::LOADI(n) => {
-                self.cpu.i = n;
-                self.cpu.pc += 2;
-            },
-            ISA::JUMPI(n) => {
-                self.cpu.pc = self.cpu.r[0] as usize + n;
-            },
-            ISA::RAND(x, n) => {
-                self.cpu.r[x] = rand::thread_rng().gen_range(0..255) & n;
-                self.cpu.pc += 2;
-            },
-            ISA::DRAW(x, y, n) => {
-                self.cpu.r[0xf] = 0;
-                let px = self.cpu.r[x] as usize;
-                let py = self.cpu.r[y] as usize;
-                for (i, j) in (0..8).cartesian_product(0..n) {
-                    let pixel = 64 * ((py + j) % 32) + (px + i) % 64;
-                    if (self.memory.ram[self.cpu.i + j] & (0x80 >> i)) != 0 {
-                        if self.memory.fb[pixel] != 0 {
-                            self.cpu.r[0xf] = 1;
-                        }
-                        self.memory.fb[pixel] = !self.memory.fb[pixel];
-                    }
-                }
-                self.cpu.pc += 2;
-            },
-            ISA::SKPR(x) => {
-                if self.keys[self.cpu.r[x] as usize] == true {
-                    self.cpu.pc += 4;
-                } else {
-                    self.cpu.pc += 2;
-                }
-            },
-            ISA::SKUP(x) => {
-                if self.keys[self.cpu.r[x] as usize] == false {
-                    self.cpu.pc += 4;
-                } else {
-                    self.cpu.pc += 2;
-                }
-            },
-            ISA::MOVED(x) => {
-                self.cpu.r[x] = self.cpu.dt;
-                self.cpu.pc += 2;
-            },
-            ISA::KEYD(x) => {
-                if let Some(key) = self.keys.iter().position(|&e| e) {
-                    self.keys[x] = false;
-                    self.cpu.r[x] = key as u8;
-                    self.cpu.pc += 2;
-                }
-            },
-            ISA::LOADD(x) => {
-                self.cpu.dt = self.cpu.r[x];
-                self.cpu.pc += 2;
-            },
-            ISA::LOADS(x) => {
-                self.cpu.st = self.cpu.r[x];
-                self.cpu.pc += 2;
-            },
-            ISA::ADDI(x) => {
-                self.cpu.i += self.cpu.r[x] as usize;
-                self.cpu.pc += 2;
-            },
-            ISA::LDSPR(x) => {
-                self.cpu.i = ((self.cpu.r[x] & 0xf) * 5) as usize;
-                self.cpu.pc += 2;
-            },
-            ISA::BCD(x) => {
-                let value = self.cpu.r[x];
-                self.memory.ram[self.cpu.i] = (value / 100) % 10;
-                self.memory.ram[self.cpu.i + 1] = (value / 10) % 10;
-                self.memory.ram[self.cpu.i + 2] = value % 10;
-                self.cpu.pc += 2;
-            },
-            ISA::STOR(n) => {
-                for i in 0..(1+n) {
-                    self.memory.ram[self.cpu.i + i] = self.cpu.r[i];
-                }
-                self.cpu.pc += 2;
-            },
-            ISA::READ(n) => {
-                for i in 0..(1+n) {
-                    self.cpu.r[i] = self.memory.ram[self.cpu.i + i];
-                }
-                self.cpu.pc += 2;
-            },
-            ISA::NOP(opcode) => {
-                panic!("Unexpected OP(${:03X}) at ${:03X} address", opcode, self.cpu.pc);
+                self.memory.width = entry.resolution.0;
+                self.memory.height = entry.resolution.1;
+                self.memory.audio = entry.audio;
+                self.tone_hz = entry.tone_hz;
+                self.cpu = entry.cpu;
+                self.pending_key = entry.pending_key;
+                self.halted = false;
+                true
+            }
+            None => false
+        }
+    }
+
+    // Render screen as braille characters, each covers 2x4 pixels
+    pub fn framebuffer_braille(&self) -> String {
+        // dot bits in (column, row) order within a cell
+        const DOTS: [[u32; 4]; 2] = [[0x01, 0x02, 0x04, 0x40], [0x08, 0x10, 0x20, 0x80]];
+        let (width, height) = self.resolution();
+        let mut text = String::new();
+        for cy in (0..height).step_by(4) {
+            for cx in (0..width).step_by(2) {
+                let bits = (0..2).cartesian_product(0..4)
+                    .filter(|&(i, j)| self.memory.fb[width * (cy + j) + cx + i] != 0)
+                    .fold(0, |acc, (i, j)| acc | DOTS[i][j]);
+                text.push(core::char::from_u32(0x2800 + bits).unwrap_or(' '));
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    // Visible screen at 1 bit per pixel, rows top to bottom, MSB leftmost
+    pub fn framebuffer_packed(&self) -> Vec<u8> {
+        let (width, height) = self.resolution();
+        self.memory.fb[..width * height].chunks(8)
+            .map(|pixels| pixels.iter().enumerate().fold(0, |byte, (i, &pixel)| byte | ((pixel != 0) as u8) << (7 - i)))
+            .collect()
+    }
+
+    // Golden image check against framebuffer_packed output
+    pub fn framebuffer_matches(&self, reference_packed: &[u8]) -> bool {
+        self.framebuffer_packed() == reference_packed
+    }
+
+    // Same as framebuffer_matches with reference read from a file
+    #[cfg(feature = "std")]
+    pub fn framebuffer_matches_file(&self, path: &str) -> Result<bool> {
+        let mut reference = Vec::new();
+        File::open(path)?.read_to_end(&mut reference)?;
+        Ok(self.framebuffer_matches(&reference))
+    }
+
+    // Both XO-CHIP bit planes of active screen, combined they give 4 colors
+    pub fn framebuffer_planes(&self) -> (&[u8], &[u8]) {
+        let size = self.memory.width * self.memory.height;
+        (&self.memory.fb[..size], &self.memory.fb2[..size])
+    }
+
+    // Copy of visible framebuffer mirrored along either axis, for mounted displays
+    pub fn framebuffer_flipped(&self, horizontal: bool, vertical: bool) -> Vec<u8> {
+        let (width, height) = self.resolution();
+        (0..height).cartesian_product(0..width)
+            .map(|(y, x)| {
+                let sx = if horizontal { width - 1 - x } else { x };
+                let sy = if vertical { height - 1 - y } else { y };
+                self.memory.fb[width * sy + sx]
+            })
+            .collect()
+    }
+
+    // Framebuffer cells changed since previous call as (index, value)
+    pub fn framebuffer_delta(&mut self) -> Vec<(usize, u8)> {
+        let delta: Vec<(usize, u8)> = self.memory.fb.iter()
+            .zip(self.fb_shadow.iter())
+            .enumerate()
+            .filter(|(_, (new, old))| new != old)
+            .map(|(index, (&new, _))| (index, new))
+            .collect();
+        self.fb_shadow.copy_from_slice(&self.memory.fb[..]);
+        delta
+    }
+
+    // Screen rows changed since previous call, all of them after mode switch
+    pub fn dirty_rows(&mut self) -> Vec<usize> {
+        let (width, height) = self.resolution();
+        let resized = self.row_shadow_resolution != (width, height);
+        let rows = (0..height)
+            .filter(|y| resized || self.memory.fb[y * width..(y + 1) * width] != self.row_shadow[y * width..(y + 1) * width])
+            .collect();
+        self.row_shadow.copy_from_slice(&self.memory.fb[..]);
+        self.row_shadow_resolution = (width, height);
+        rows
+    }
+
+    pub fn read_sprite(&self, addr: usize, rows: usize) -> Vec<Vec<bool>> {
+        // 16 rows means SCHIP 16x16 sprite with two bytes per row
+        let width = if rows == 16 { 2 } else { 1 };
+        let data = self.memory.ram.get(addr..).unwrap_or(&[]);
+        data.chunks_exact(width)
+            .take(rows)
+            .map(|row| (0..8 * width).map(|i| row[i / 8] & (0x80 >> (i % 8)) != 0).collect())
+            .collect()
+    }
+
+    // Built-in glyph LDSPR points at for digit, only low nibble is used as
+    // in LDSPR, RAM changes to the font area are not reflected
+    pub fn font_sprite(&self, digit: u8) -> [[bool; 8]; 5] {
+        let mut glyph = [[false; 8]; 5];
+        let rows = &self.memory.rom[(digit & 0xf) as usize * 5..][..5];
+        for (cells, &row) in glyph.iter_mut().zip(rows) {
+            for (i, cell) in cells.iter_mut().enumerate() {
+                *cell = row & (0x80 >> i) != 0;
+            }
+        }
+        glyph
+    }
+
+    // Release every key, hold times and press order included
+    pub fn reset_keys(&mut self) {
+        self.keys = [false; 16];
+        self.key_hold = [0; 16];
+        self.track_presses();
+    }
+
+    // Update key state returning previous one, None for keys past $F
+    pub fn set_key(&mut self, key: usize, state: bool) -> Option<bool> {
+        let slot = self.keys.get_mut(key)?;
+        if !state {
+            self.key_hold[key] = 0;
+        }
+        let previous = core::mem::replace(slot, state);
+        self.track_presses();
+        Some(previous)
+    }
+
+    // Apply key change once cycle instructions have run, false for keys past $F
+    pub fn queue_key_event(&mut self, cycle: u64, key: usize, state: bool) -> bool {
+        if key >= self.keys.len() {
+            return false;
+        }
+        // keep queue ordered, events for same cycle apply in insertion order
+        let index = self.key_queue.iter().position(|&(at, _, _)| at > cycle).unwrap_or(self.key_queue.len());
+        self.key_queue.insert(index, (cycle, key, state));
+        true
+    }
+
+    fn apply_key_events(&mut self) {
+        let now = self.stats.cycles as u64;
+        while let Some(&(at, key, state)) = self.key_queue.front() {
+            if at > now {
+                break;
+            }
+            self.key_queue.pop_front();
+            self.set_key(key, state);
+        }
+    }
+
+    pub fn set_key_policy(&mut self, policy: KeyPolicy) {
+        self.key_policy = policy;
+    }
+
+    pub fn set_pc_policy(&mut self, policy: PcPolicy) {
+        self.pc_policy = policy;
+    }
+
+    // Number presses in order seen, keys set directly count in index order
+    fn track_presses(&mut self) {
+        for key in 0..self.keys.len() {
+            if !self.keys[key] {
+                self.press_order[key] = 0;
+            } else if self.press_order[key] == 0 {
+                self.press_count += 1;
+                self.press_order[key] = self.press_count;
             }
+        }
+    }
+
+    // Held key KEYD takes according to key policy
+    fn select_key(&self) -> Option<usize> {
+        let held = (0..self.keys.len()).filter(|&key| self.keys[key]);
+        match self.key_policy {
+            KeyPolicy::Lowest => held.min(),
+            KeyPolicy::MostRecent => held.max_by_key(|&key| self.press_order[key]),
+        }
+    }
+
+    // Ticks key has been held, zero once released or for keys past $F
+    pub fn key_hold_ticks(&self, key: usize) -> u32 {
+        self.key_hold.get(key).copied().unwrap_or(0)
+    }
+
+    pub fn keys_pressed(&self) -> Vec<usize> {
+        (0..self.keys.len()).filter(|&key| self.keys[key]).collect()
+    }
+
+    pub fn set_direction_map(&mut self, keys: [usize; 4]) {
+        self.directions = keys;
+    }
+
+    pub fn press_direction(&mut self, dir: Direction) {
+        self.set_key(self.directions[dir as usize] & 0xf, true);
+    }
+
+    pub fn release_direction(&mut self, dir: Direction) {
+        self.set_key(self.directions[dir as usize] & 0xf, false);
+    }
+
+    // Reset levels from strongest: cold_reset wipes RAM and program too,
+    // reset clears registers, timers and screen, reset_cpu_only keeps the
+    // screen, goto_entry only moves PC back to entry point. All but
+    // goto_entry release held keys.
+    pub fn cold_reset(&mut self) {
+        self.memory.clear();
+        self.rom_len = 0;
+        self.flush_decode_cache();
+        self.reset();
+    }
+
+    // Switch target and cold reset, quirks_mut changes made earlier are lost
+    pub fn reset_with_target(&mut self, target: Target) {
+        self.set_target(target);
+        self.cold_reset();
+    }
+
+    // Power-on state, clears registers, timers and screen
+    pub fn reset(&mut self) {
+        self.reset_cpu_only();
+        self.set_resolution(false);
+    }
+
+    // Same as reset but also rewinds RAND to last seed, or to construction
+    // when never seeded, so replay from cycle 0 repeats the original run
+    pub fn reset_preserving_seed(&mut self) {
+        self.reset();
+        self.rng = self.seed_rng.clone_box();
+    }
+
+    pub fn snapshot(&self) -> MachineSnapshot {
+        MachineSnapshot {
+            registers: self.cpu.r,
+            i: self.cpu.i,
+            pc: self.cpu.pc,
+            sp: self.cpu.sp,
+            dt: self.cpu.dt,
+            st: self.cpu.st,
+            ram: self.memory.ram.clone(),
+            stack: self.memory.stack.clone(),
+            keys: self.keys,
+        }
+    }
+
+    // Install snapshot wholesale, framebuffer and quirks are kept. RAM size
+    // must be a power of two and sp within a non-empty stack, machine is
+    // untouched on error.
+    pub fn reset_to(&mut self, snap: &MachineSnapshot) -> core::result::Result<(), StateError> {
+        let ram_size = snap.ram.len();
+        if !ram_size.is_power_of_two() || ram_size <= self.memory.rom.len() {
+            return Err(StateError::BadRamSize(ram_size));
+        }
+        if snap.stack.is_empty() {
+            return Err(StateError::EmptyStack);
+        }
+        if snap.sp > snap.stack.len() {
+            return Err(StateError::BadStackPointer(snap.sp));
+        }
+        self.reset_cpu_only();
+        self.cpu = CPU { r: snap.registers, i: snap.i, dt: snap.dt, st: snap.st, pc: snap.pc, sp: snap.sp };
+        self.set_ram_size(snap.ram.len());
+        self.memory.ram.copy_from_slice(&snap.ram);
+        self.memory.stack = snap.stack.clone();
+        self.keys = snap.keys;
+        self.flush_decode_cache();
+        Ok(())
+    }
+
+    // Full mutable state as bytes for rewinding, quirks and options are not included
+    pub fn snapshot_bytes(&self) -> Vec<u8> {
+        let mut state = Vec::with_capacity(STATE_HEADER_LEN + 32 + self.memory.ram.len() + 2 * 128 * 64);
+        state.extend_from_slice(STATE_MAGIC);
+        state.push(STATE_VERSION);
+        state.push((self.memory.width == 128) as u8);
+        state.extend_from_slice(&(self.memory.ram.len() as u32).to_le_bytes());
+        state.extend_from_slice(&(self.memory.stack.len() as u16).to_le_bytes());
+        state.extend_from_slice(&(self.cpu.sp as u16).to_le_bytes());
+        state.extend_from_slice(&(self.cpu.i as u32).to_le_bytes());
+        state.extend_from_slice(&(self.cpu.pc as u32).to_le_bytes());
+        state.extend_from_slice(&[self.cpu.dt, self.cpu.st]);
+        state.extend_from_slice(&self.cpu.r);
+        state.extend(self.keys.iter().map(|&pressed| pressed as u8));
+        state.extend_from_slice(&self.memory.ram);
+        state.extend(self.memory.stack.iter().flat_map(|slot| slot.to_le_bytes()));
+        state.extend_from_slice(&self.memory.fb);
+        state.extend_from_slice(&self.memory.fb2);
+        state
+    }
+
+    // Install state from snapshot_bytes, machine is untouched on error
+    pub fn restore(&mut self, state: &[u8]) -> core::result::Result<(), StateError> {
+        let header = state.get(..STATE_HEADER_LEN).filter(|header| header.starts_with(STATE_MAGIC))
+            .ok_or(StateError::BadMagic)?;
+        if header[4] != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(header[4]));
+        }
+        let word = |at: usize| u16::from_le_bytes([header[at], header[at + 1]]) as usize;
+        let long = |at: usize| u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]]) as usize;
+        let (ram_size, slots, sp) = (long(6), word(10), word(12));
+        if ram_size != DEFAULT_RAM_SIZE && ram_size != XOCHIP_RAM_SIZE {
+            return Err(StateError::BadRamSize(ram_size));
+        }
+        let expected = STATE_HEADER_LEN + 32 + ram_size + 2 * slots + 2 * 128 * 64;
+        if state.len() != expected {
+            return Err(StateError::WrongLength { expected, actual: state.len() });
+        }
+        if slots == 0 {
+            return Err(StateError::EmptyStack);
+        }
+        if sp > slots {
+            return Err(StateError::BadStackPointer(sp));
+        }
+        let (registers, rest) = state[STATE_HEADER_LEN..].split_at(16);
+        let (keys, rest) = rest.split_at(16);
+        let (ram, rest) = rest.split_at(ram_size);
+        let (stack, rest) = rest.split_at(2 * slots);
+        let (fb, fb2) = rest.split_at(128 * 64);
+        let mut snap = MachineSnapshot {
+            registers: [0; 16],
+            i: long(14),
+            pc: long(18),
+            sp,
+            dt: header[22],
+            st: header[23],
+            ram: ram.to_vec(),
+            stack: stack.chunks_exact(2).map(|slot| u16::from_le_bytes([slot[0], slot[1]])).collect(),
+            keys: [false; 16],
         };
-        Some((pc, op))
+        snap.registers.copy_from_slice(registers);
+        snap.keys.iter_mut().zip(keys).for_each(|(key, &pressed)| *key = pressed != 0);
+        self.reset_to(&snap)?;
+        self.set_resolution(header[5] != 0);
+        self.memory.fb.copy_from_slice(fb);
+        self.memory.fb2.copy_from_slice(fb2);
+        Ok(())
     }
 
-    pub fn tick(&mut self) {
-        // simluate timers
-        if self.cpu.dt > 0 {
-            self.cpu.dt -= 1;
+    // Independent copy including RNG state, renderer, hooks and callbacks are not carried over
+    pub fn fork(&self) -> Machine {
+        Machine {
+            keys: self.keys,
+            cpu: self.cpu.clone(),
+            memory: self.memory.clone(),
+            rng: self.rng.clone_box(),
+            seed_rng: self.seed_rng.clone_box(),
+            directions: self.directions,
+            quirks: self.quirks,
+            i_mask: self.i_mask,
+            rom_len: self.rom_len,
+            entry_pc: self.entry_pc,
+            randomize_ram: self.randomize_ram,
+            track_self_modify: self.track_self_modify,
+            self_modified: self.self_modified.clone(),
+            decode_cache: self.decode_cache.clone(),
+            vf_events: self.vf_events.clone(),
+            trace: self.trace.clone(),
+            uninit_reads: self.uninit_reads.clone(),
+            written_regs: self.written_regs,
+            misaligned: self.misaligned,
+            tone_hz: self.tone_hz,
+            stop_on_empty: self.stop_on_empty,
+            last_changed_reg: self.last_changed_reg,
+            protect_font: self.protect_font,
+            protected: self.protected.clone(),
+            pending_key: self.pending_key,
+            key_waiting: self.key_waiting,
+            halted: self.halted,
+            timers_frozen: self.timers_frozen,
+            key_hold: self.key_hold,
+            key_policy: self.key_policy,
+            pc_policy: self.pc_policy,
+            press_order: self.press_order,
+            press_count: self.press_count,
+            key_queue: self.key_queue.clone(),
+            vblank: self.vblank,
+            resolution_changed: self.resolution_changed,
+            stats: self.stats,
+            spin_window: self.spin_window,
+            recent_pcs: self.recent_pcs.clone(),
+            pc_history: self.pc_history.clone(),
+            pc_history_len: self.pc_history_len,
+            collision_history: self.collision_history.clone(),
+            collision_history_len: self.collision_history_len,
+            frame_collisions: self.frame_collisions,
+            fb_shadow: self.fb_shadow,
+            row_shadow: self.row_shadow,
+            row_shadow_resolution: self.row_shadow_resolution,
+            renderer: Box::new(NullRenderer),
+            pre_hook: None,
+            post_hook: None,
+            op_handlers: Vec::new(),
+            trace_callback: None,
+            sound_callback: None,
+            dt_zero_callback: None,
+            sound_on: self.sound_on,
+            undo_depth: self.undo_depth,
+            undo_log: self.undo_log.clone(),
+            recording: false,
+            step_writes: Vec::new(),
         }
-        if self.cpu.st > 0 {
-            self.cpu.st -= 1;
+    }
+
+    // Load address and initial PC, $200 unless changed e.g. to $600 for ETI-660
+    pub fn entry_pc(&self) -> usize {
+        self.entry_pc
+    }
+
+    // Takes effect on next reset or load, must be even and inside RAM.
+    // Loaded program length is cut to what fits above new entry point.
+    pub fn set_entry_pc(&mut self, addr: usize) -> core::result::Result<(), EntryError> {
+        if addr & 1 != 0 || addr >= self.memory.ram.len() {
+            return Err(EntryError { addr });
+        }
+        self.entry_pc = addr;
+        self.rom_len = self.rom_len.min(self.memory.ram.len() - addr);
+        Ok(())
+    }
+
+    // Same as reset but preserves framebuffer contents
+    pub fn reset_cpu_only(&mut self) {
+        self.cpu = CPU::default();
+        self.cpu.pc = self.entry_pc;
+        self.cpu.sp = self.memory.stack.len() -1;  // end of stack
+        self.memory.stack[self.cpu.sp] = self.cpu.pc as u16;  // entrypoint
+        self.self_modified.clear();
+        if let Some(events) = self.vf_events.as_mut() {
+            events.clear();
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            trace.clear();
+        }
+        if let Some(reads) = self.uninit_reads.as_mut() {
+            reads.clear();
+        }
+        self.written_regs = 0;
+        self.undo_log.clear();
+        self.misaligned = false;
+        self.pending_key = None;
+        self.key_waiting = false;
+        self.halted = false;
+        self.vblank = true;
+        self.stats = Stats::default();
+        self.recent_pcs.clear();
+        self.pc_history.clear();
+        self.collision_history.clear();
+        self.frame_collisions = 0;
+        self.key_queue.clear();
+        self.reset_keys();
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load(&mut self, filename: &str) -> Result<usize> {
+        self.reset();
+        self.rom_len = self.memory.load(filename, self.cpu.pc)?;
+        self.fill_free_ram();
+        self.flush_decode_cache();
+        Ok(self.rom_len)
+    }
+
+    // Smoke test: load program and run it until it halts, fails or uses up max_cycles
+    #[cfg(feature = "std")]
+    pub fn load_and_run(&mut self, filename: &str, max_cycles: usize) -> Result<RunSummary> {
+        self.load(filename)?;
+        let mut cycles_run = 0;
+        let stop = loop {
+            if self.halted {
+                break StepError::Stopped(StopReason::Halted);
+            }
+            if cycles_run == max_cycles {
+                break StepError::Stopped(StopReason::CycleLimitReached);
+            }
+            match self.step() {
+                Ok(_) => cycles_run += 1,
+                Err(err) => break err,
+            }
+        };
+        Ok(RunSummary { cycles_run, registers: self.cpu.r, i: self.cpu.i, pc: self.cpu.pc, stop })
+    }
+
+    // Load program and scan it to guess the target platform
+    #[cfg(feature = "std")]
+    pub fn load_info(&mut self, filename: &str) -> Result<RomInfo> {
+        self.load(filename)?;
+        Ok(self.rom_info())
+    }
+
+    // Load checking extension and size, in strict mode oversized ROM fails
+    // instead of being truncated
+    #[cfg(feature = "std")]
+    pub fn load_validated(&mut self, filename: &str, strict: bool) -> Result<RomInfo> {
+        let mut data = Vec::new();
+        File::open(filename)?.read_to_end(&mut data)?;
+        let mut warnings = Vec::new();
+        let extension = std::path::Path::new(filename).extension().and_then(|ext| ext.to_str());
+        if !matches!(extension.map(|ext| ext.to_ascii_lowercase()).as_deref(), Some("ch8") | Some("c8")) {
+            warnings.push(RomWarning::UnknownExtension);
+        }
+        let capacity = self.memory.ram.len() - self.entry_pc;
+        if data.len() > capacity {
+            if strict {
+                let msg = format!("ROM of {} bytes exceeds {} bytes of RAM", data.len(), capacity);
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, msg));
+            }
+            warnings.push(RomWarning::Truncated { dropped: data.len() - capacity });
+        }
+        self.load_bytes(&data);
+        Ok(RomInfo { warnings, ..self.rom_info() })
+    }
+
+    // Standard RAM layout around loaded program, empty regions left out
+    pub fn memory_map(&self) -> Vec<MemoryRegion> {
+        let font = self.memory.rom.len();
+        let end = self.entry_pc + self.rom_len;
+        let regions = vec![
+            MemoryRegion { start: 0, end: font, kind: RegionKind::Font, label: "font" },
+            MemoryRegion { start: font, end: self.entry_pc, kind: RegionKind::Reserved, label: "interpreter" },
+            MemoryRegion { start: self.entry_pc, end, kind: RegionKind::Program, label: "program" },
+            MemoryRegion { start: end, end: self.memory.ram.len(), kind: RegionKind::Free, label: "free" },
+        ];
+        regions.into_iter().filter(|region| region.start < region.end).collect()
+    }
+
+    // Loaded program as it is now in RAM, including any patches
+    pub fn export_rom(&self) -> Vec<u8> {
+        let end = (self.entry_pc + self.rom_len).min(self.memory.ram.len());
+        self.memory.ram[self.entry_pc..end].to_vec()
+    }
+
+    // RAM from entry point through last non-zero byte, keeps data written past loaded program
+    pub fn export_rom_trimmed(&self) -> Vec<u8> {
+        let ram = &self.memory.ram[self.entry_pc..];
+        let len = ram.iter().rposition(|&byte| byte != 0).map_or(0, |last| last + 1);
+        ram[..len].to_vec()
+    }
+
+    // Bytes from start of RAM up to end of font or loaded program
+    pub fn ram_used(&self) -> usize {
+        (self.entry_pc + self.rom_len).max(self.memory.rom.len()).min(self.memory.ram.len())
+    }
+
+    // Bytes left above loaded program, e.g. to check an overlay fits
+    pub fn free_ram(&self) -> usize {
+        self.memory.ram.len() - self.ram_used()
+    }
+
+    // Summary of loaded program from linear sweep of its instructions
+    pub fn rom_info(&self) -> RomInfo {
+        let base = self.entry_pc;
+        let end = (base + self.rom_len).min(self.memory.ram.len());
+        let ops: Vec<ISA> = self.memory.ram[base..end].chunks_exact(2).filter_map(decode).collect();
+        let uses_schip = ops.iter().any(|op| matches!(op, ISA::LORES | ISA::HIRES | ISA::SCD(_) | ISA::SCR | ISA::SCL | ISA::EXIT));
+        let uses_xochip = ops.iter().any(|op| matches!(op, ISA::PITCH(_) | ISA::AUDIO));
+        let first_op = ops.into_iter().next();
+        RomInfo { bytes: self.rom_len, base, first_op, uses_schip, uses_xochip, warnings: Vec::new() }
+    }
+
+    // Pick quirks from opcodes used by loaded program, XO-CHIP wins over SCHIP
+    pub fn auto_configure(&mut self) -> Target {
+        let info = self.rom_info();
+        let target = if info.uses_xochip {
+            Target::XoChip
+        } else if info.uses_schip {
+            Target::SuperChip
+        } else {
+            Target::Chip8
+        };
+        self.set_target(target);
+        target
+    }
+
+    // Write raw 4k RAM image, e.g. for diffing against other emulators
+    #[cfg(feature = "std")]
+    pub fn dump_ram(&self, path: &str) -> Result<()> {
+        File::create(path)?.write_all(&self.memory.ram[..])
+    }
+
+    #[cfg(feature = "std")]
+    pub fn load_ram_image(&mut self, path: &str) -> Result<()> {
+        let mut image = Vec::new();
+        File::open(path)?.read_to_end(&mut image)?;
+        if image.len() != self.memory.ram.len() {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "RAM image must match RAM size"));
+        }
+        self.memory.ram.copy_from_slice(&image);
+        self.flush_decode_cache();
+        Ok(())
+    }
+
+    pub fn load_bytes(&mut self, data: &[u8]) -> usize {
+        self.reset();
+        self.rom_len = self.memory.load_bytes(data, self.cpu.pc);
+        self.fill_free_ram();
+        self.flush_decode_cache();
+        self.rom_len
+    }
+
+    // Random bytes from seeded RNG above program when enabled, zeros otherwise
+    fn fill_free_ram(&mut self) {
+        let start = self.cpu.pc + self.rom_len;
+        if self.randomize_ram {
+            self.rng.fill_bytes(&mut self.memory.ram[start..]);
+        } else {
+            self.memory.ram[start..].iter_mut().for_each(|byte| *byte = 0);
+        }
+    }
+
+    pub fn load_hex(&mut self, text: &str) -> core::result::Result<usize, HexError> {
+        let data = parse_hex(text)?;
+        Ok(self.load_bytes(&data))
+    }
+
+    // Patch data into RAM at addr on top of loaded program, no reset
+    pub fn load_overlay(&mut self, data: &[u8], addr: usize) -> core::result::Result<usize, OverlayError> {
+        match addr.checked_add(data.len()) {
+            Some(end) if end <= self.memory.ram.len() => {
+                self.memory.ram[addr..end].copy_from_slice(data);
+                self.flush_decode_cache();
+                Ok(data.len())
+            }
+            _ => Err(OverlayError { addr, len: data.len() })
+        }
+    }
+
+    // Assemble source at $200 and load it as the program
+    pub fn assemble_and_load(&mut self, src: &str) -> core::result::Result<usize, AsmError> {
+        let data = assemble(src, self.entry_pc)?;
+        Ok(self.load_bytes(&data))
+    }
+
+    pub fn set_track_self_modify(&mut self, enabled: bool) {
+        self.track_self_modify = enabled;
+    }
+
+    // Log (pc, op, VF) for every 8xyN instruction that wrote VF
+    pub fn set_trace_vf(&mut self, enabled: bool) {
+        self.vf_events = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    pub fn vf_events(&self) -> &[(usize, ISA, u8)] {
+        self.vf_events.as_deref().unwrap_or(&[])
+    }
+
+    // Keep addresses of last len executed instructions, 0 turns history off
+    pub fn set_pc_history(&mut self, len: usize) {
+        self.pc_history_len = len;
+        self.pc_history.clear();
+    }
+
+    // Oldest first, most recent instruction last
+    pub fn pc_history(&self) -> &[usize] {
+        &self.pc_history[self.pc_history.len().saturating_sub(self.pc_history_len)..]
+    }
+
+    // Keep colliding DRAW counts of last len frames, 0 turns history off
+    pub fn set_collision_history(&mut self, len: usize) {
+        self.collision_history_len = len;
+        self.collision_history.clear();
+        self.frame_collisions = 0;
+    }
+
+    // One count per tick, oldest first, frame in progress not included
+    pub fn collision_history(&self) -> &[u32] {
+        &self.collision_history[self.collision_history.len().saturating_sub(self.collision_history_len)..]
+    }
+
+    // Log (cycle, pc, opcode, op) of every executed instruction until reset, unbounded
+    pub fn set_trace(&mut self, enabled: bool) {
+        self.trace = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    pub fn trace(&self) -> &[(usize, usize, u16, ISA)] {
+        self.trace.as_deref().unwrap_or(&[])
+    }
+
+    // Trace as CSV with header, mnemonics quoted since they hold commas
+    pub fn export_trace_csv(&self) -> String {
+        let mut csv = String::from("cycle,pc,opcode,mnemonic\n");
+        for (cycle, pc, opcode, op) in self.trace() {
+            csv.push_str(&format!("{},0x{:03X},0x{:04X},\"{}\"\n", cycle, pc, opcode, op));
+        }
+        csv
+    }
+
+    // Log registers read before any write since reset or enabling, off by default
+    pub fn set_track_uninitialized(&mut self, enabled: bool) {
+        self.uninit_reads = if enabled { Some(Vec::new()) } else { None };
+        self.written_regs = 0;
+    }
+
+    // (pc, op, register) of first read of each never written register
+    pub fn uninitialized_reads(&self) -> &[(usize, ISA, usize)] {
+        self.uninit_reads.as_deref().unwrap_or(&[])
+    }
+
+    // Bit masks of registers read and written by op
+    fn register_access(&self, op: &ISA) -> (u16, u16) {
+        let bit = |x: usize| 1u16 << x;
+        let upto = |n: usize| (0..=n).fold(0, |mask, x| mask | bit(x));
+        let flag = |enabled: bool| if enabled { bit(0xf) } else { 0 };
+        match *op {
+            ISA::SKE(x, _) | ISA::SKNE(x, _) | ISA::SKPR(x) | ISA::SKUP(x) => (bit(x), 0),
+            ISA::LOADD(x) | ISA::LOADS(x) | ISA::LDSPR(x) | ISA::BCD(x) | ISA::PITCH(x) => (bit(x), 0),
+            ISA::SKRE(x, y) | ISA::SKRNE(x, y) => (bit(x) | bit(y), 0),
+            ISA::LOAD(x, _) | ISA::RAND(x, _) | ISA::MOVED(x) | ISA::KEYD(x) => (0, bit(x)),
+            ISA::ADD(x, _) => (bit(x), bit(x)),
+            ISA::MOVE(x, y) => (bit(y), bit(x)),
+            ISA::OR(x, y) | ISA::AND(x, y) | ISA::XOR(x, y) => (bit(x) | bit(y), bit(x) | flag(self.quirks.logic_resets_vf)),
+            ISA::ADDR(x, y) | ISA::SUB(x, y) | ISA::SUBN(x, y) => (bit(x) | bit(y), bit(x) | bit(0xf)),
+            ISA::SHR(x, y) | ISA::SHL(x, y) => {
+                let source = if self.quirks.shift_uses_vy { y } else { x };
+                (bit(source), bit(x) | bit(0xf))
+            }
+            ISA::JUMPI(_, x) => (bit(if self.quirks.jump_uses_vx { x } else { 0 }), 0),
+            ISA::DRAW(x, y, _) => (bit(x) | bit(y), bit(0xf)),
+            ISA::ADDI(x) => (bit(x), flag(self.quirks.add_i_sets_vf)),
+            ISA::STOR(n) => (upto(n), 0),
+            ISA::READ(n) => (0, upto(n)),
+            _ => (0, 0)
+        }
+    }
+
+    pub fn was_self_modified(&self) -> bool {
+        !self.self_modified.is_empty()
+    }
+
+    pub fn self_modified_addresses(&self) -> &[usize] {
+        &self.self_modified[..]
+    }
+
+    fn write_ram(&mut self, addr: usize, value: u8) {
+        let addr = self.memory.addr(addr);
+        if self.track_self_modify
+            && (self.entry_pc..self.entry_pc + self.rom_len).contains(&addr)
+            && !self.self_modified.contains(&addr) {
+            self.self_modified.push(addr);
+        }
+        if self.recording {
+            if let Some(entry) = self.undo_log.back_mut() {
+                entry.ram.push((addr, self.memory.ram[addr]));
+            }
+        }
+        self.invalidate_decoded(addr);
+        self.step_writes.push(addr);
+        self.memory.ram[addr] = value;
+    }
+
+    // Fail STOR/BCD writes into the font glyphs at $000..$050
+    pub fn set_protect_font(&mut self, enabled: bool) {
+        self.protect_font = enabled;
+    }
+
+    // Fail STOR/BCD writes into [start, end), e.g. to guard a data table
+    pub fn set_protected_range(&mut self, start: usize, end: usize) {
+        self.protected = Some(start..end);
+    }
+
+    pub fn clear_protection(&mut self) {
+        self.protected = None;
+    }
+
+    // RAM range written by op, as start address and length
+    fn ram_write_range(&self, op: &ISA) -> Option<(usize, usize)> {
+        match *op {
+            ISA::BCD(_) => Some((self.cpu.i, 3)),
+            ISA::STOR(n) => Some((self.cpu.i, n + 1)),
+            _ => None
         }
     }
 
+    fn check_writable(&self, addr: usize, len: usize) -> core::result::Result<(), StepError> {
+        let font = 0..self.memory.rom.len();
+        let protected = |a: &usize| {
+            (self.protect_font && font.contains(a)) || self.protected.iter().any(|range| range.contains(a))
+        };
+        match (0..len).map(|k| self.memory.addr(addr + k)).find(protected) {
+            Some(addr) => Err(StepError::WriteToProtectedMemory { addr }),
+            None => Ok(())
+        }
+    }
+
+    pub fn goto_entry(&mut self) {
+        self.cpu.pc = self.entry_pc;
+    }
+
+    // Set once an instruction was fetched from odd address
+    pub fn pc_misaligned(&self) -> bool {
+        self.misaligned
+    }
+
+    // Decode instruction at PC without executing it
+    pub fn current_instruction(&self) -> Option<(u16, ISA)> {
+        self.opcode_at(self.cpu.pc)
+    }
+
+    // Plain English explanation of opcode for teaching tools
+    pub fn describe_opcode(opcode: u16) -> String {
+        decode_pair(((opcode >> 8) as u8, opcode as u8)).describe()
+    }
+
+    // Raw opcode and instruction at any address, None when it runs past RAM end
+    pub fn opcode_at(&self, addr: usize) -> Option<(u16, ISA)> {
+        let (high, low) = self.memory.opcode(addr)?;
+        Some((u16::from_be_bytes([high, low]), decode_pair((high, low))))
+    }
+
+    // Even addresses whose opcode matches pattern under mask, e.g. $D000/$F000 finds every DRAW
+    pub fn find_opcode(&self, pattern: u16, mask: u16) -> Vec<usize> {
+        self.memory.ram.chunks_exact(2)
+            .enumerate()
+            .filter(|(_, bytes)| u16::from_be_bytes([bytes[0], bytes[1]]) & mask == pattern & mask)
+            .map(|(index, _)| index * 2)
+            .collect()
+    }
+
+    // Instructions within radius around PC, flag marks the one at PC
+    pub fn disassemble_around(&self, radius: usize) -> Vec<(usize, u16, ISA, bool)> {
+        let pc = self.cpu.pc;
+        let start = pc.saturating_sub(radius * 2);
+        let end = pc.saturating_add(radius * 2);
+        (start..=end).step_by(2)
+            .map_while(|addr| self.memory.opcode(addr).map(|bytes| (addr, bytes)))
+            .map(|(addr, (high, low))| (addr, u16::from_be_bytes([high, low]), decode_pair((high, low)), addr == pc))
+            .collect()
+    }
+
+    // Remember decoded instructions per address, RAM writes invalidate them
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = if enabled { Some(vec![None; self.memory.ram.len()]) } else { None };
+    }
+
+    fn flush_decode_cache(&mut self) {
+        if let Some(cache) = self.decode_cache.as_mut() {
+            cache.iter_mut().for_each(|entry| *entry = None);
+        }
+    }
+
+    // Byte at addr belongs to instructions starting at addr and addr - 1
+    fn invalidate_decoded(&mut self, addr: usize) {
+        if let Some(cache) = self.decode_cache.as_mut() {
+            cache[addr] = None;
+            if addr > 0 {
+                cache[addr - 1] = None;
+            }
+        }
+    }
+
+    fn fetch(&mut self) -> Option<(u16, ISA)> {
+        let pc = self.cpu.pc;
+        match self.decode_cache.as_ref().and_then(|cache| cache.get(pc).copied().flatten()) {
+            Some(instruction) => Some(instruction),
+            None => {
+                let instruction = self.current_instruction()?;
+                if let Some(entry) = self.decode_cache.as_mut().and_then(|cache| cache.get_mut(pc)) {
+                    *entry = Some(instruction);
+                }
+                Some(instruction)
+            }
+        }
+    }
+
+    // Number of recent instructions inspected by in_spin_loop
+    pub fn set_spin_window(&mut self, window: usize) {
+        self.spin_window = window;
+        while self.recent_pcs.len() > window {
+            self.recent_pcs.pop_front();
+        }
+    }
+
+    // Whole spin window was spent inside a loop of at most 4 instructions,
+    // e.g. polling DT, so front-end may yield until next tick
+    pub fn in_spin_loop(&self) -> bool {
+        match (self.recent_pcs.iter().min(), self.recent_pcs.iter().max()) {
+            (Some(low), Some(high)) => self.recent_pcs.len() == self.spin_window && high - low < 8,
+            _ => false
+        }
+    }
+
+    // Stopped by empty memory, end of RAM or jump to itself, step fails until resume
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // Outcome repeating the JP of an idle loop that halted the machine, None
+    // for other halts. Lets real-time front-ends keep ticking on `JP self`.
+    pub fn idle_outcome(&self) -> Option<StepOutcome> {
+        let pc = self.cpu.pc;
+        match self.current_instruction() {
+            Some((opcode, op @ ISA::JP(target))) if self.halted && target == pc => Some(StepOutcome {
+                pc,
+                op,
+                opcode,
+                drew: false,
+                branched: true,
+                new_pc: pc,
+                key_waiting: false,
+                written_addresses: Vec::new(),
+            }),
+            _ => None,
+        }
+    }
+
+    // Continue after halt, e.g. once PC or memory were patched
+    pub fn resume(&mut self) {
+        self.halted = false;
+    }
+
+    // Counters since last reset
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    // Emulated time of executed cycles at ips instructions per second
+    pub fn elapsed_ms(&self, ips: u32) -> u64 {
+        match ips {
+            0 => 0,
+            _ => self.stats.cycles as u64 * 1000 / ips as u64
+        }
+    }
+
+    // Last step blocked in KEYD waiting for key press or release
+    pub fn is_key_waiting(&self) -> bool {
+        self.key_waiting
+    }
+
+    // Register written last by last step: VF for ops setting a flag, Vn for
+    // READ of V0..Vn, None when no register was written
+    pub fn last_changed_reg(&self) -> Option<usize> {
+        self.last_changed_reg
+    }
+
+    // One line summary for stepping prompts, e.g. "$202 ADD r3, $001 ; r3=$08"
+    pub fn debug_line(&self) -> String {
+        let mut line = match self.current_instruction() {
+            Some((_, op)) => format!("${:03X} {}", self.cpu.pc, op),
+            None => format!("${:03X} ???", self.cpu.pc),
+        };
+        if let Some(x) = self.last_changed_reg {
+            line.push_str(&format!(" ; r{:X}=${:02X}", x, self.cpu.r[x]));
+        }
+        line
+    }
+
+    // Register file for log lines, e.g. "V0=00 V1=0A ... VF=01"
+    pub fn registers_hex(&self) -> String {
+        self.cpu.r.iter().enumerate()
+            .map(|(x, value)| format!("V{:X}={:02X}", x, value))
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
+    // Push screen to renderer after every instruction touching it
+    pub fn set_renderer<R: Renderer + Send + 'static>(&mut self, renderer: R) {
+        self.renderer = Box::new(renderer);
+    }
+
+    pub fn clear_renderer(&mut self) {
+        self.renderer = Box::new(NullRenderer);
+    }
+
+    // Called with address and instruction before it executes
+    pub fn set_pre_hook<F: FnMut(usize, &ISA) + Send + 'static>(&mut self, hook: F) {
+        self.pre_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_pre_hook(&mut self) {
+        self.pre_hook = None;
+    }
+
+    // Called with outcome of every executed instruction
+    pub fn set_post_hook<F: FnMut(&StepOutcome) + Send + 'static>(&mut self, hook: F) {
+        self.post_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_post_hook(&mut self) {
+        self.post_hook = None;
+    }
+
+    // Called by run with outcome of every step, after post hook of same step
+    pub fn set_trace_callback(&mut self, callback: PostHook) {
+        self.trace_callback = Some(callback);
+    }
+
+    pub fn clear_trace_callback(&mut self) {
+        self.trace_callback = None;
+    }
+
+    // Called from tick with true when buzzer starts and false when it stops
+    pub fn set_sound_callback(&mut self, callback: SoundCallback) {
+        self.sound_callback = Some(callback);
+    }
+
+    pub fn clear_sound_callback(&mut self) {
+        self.sound_callback = None;
+    }
+
+    // Called from tick when delay timer counts down to zero
+    pub fn set_dt_zero_callback(&mut self, callback: DtCallback) {
+        self.dt_zero_callback = Some(callback);
+    }
+
+    pub fn clear_dt_zero_callback(&mut self) {
+        self.dt_zero_callback = None;
+    }
+
+    fn sound_edge(&mut self, active: bool) {
+        if self.sound_on != active {
+            self.sound_on = active;
+            if let Some(callback) = self.sound_callback.as_mut() {
+                callback(active);
+            }
+        }
+    }
+
+    pub fn clear_hooks(&mut self) {
+        self.pre_hook = None;
+        self.post_hook = None;
+    }
+
+    // Run handler instead of built-in behaviour for every instruction of same
+    // kind as op, e.g. ISA::SYS(0) for custom syscalls or ISA::NOP(0) for new
+    // opcodes. Handler must advance PC and keep RAM size, its RAM writes skip
+    // undo and tracking but flush the decode cache. Dry runs skip handlers.
+    pub fn set_op_handler(&mut self, op: ISA, handler: OpHandler) {
+        self.clear_op_handler(op);
+        self.op_handlers.push((core::mem::discriminant(&op), handler));
+    }
+
+    pub fn clear_op_handler(&mut self, op: ISA) {
+        let kind = core::mem::discriminant(&op);
+        self.op_handlers.retain(|(handled, _)| *handled != kind);
+    }
+
+    fn has_op_handler(&self, op: &ISA) -> bool {
+        let kind = core::mem::discriminant(op);
+        self.op_handlers.iter().any(|(handled, _)| *handled == kind)
+    }
+
+    fn call_op_handler(&mut self, op: &ISA, pc: usize, dry_run: bool) -> core::result::Result<(), StepError> {
+        let kind = core::mem::discriminant(op);
+        let ram_size = self.memory.ram.len();
+        match self.op_handlers.iter_mut().find(|(handled, _)| *handled == kind) {
+            Some((_, handler)) if !dry_run => handler(&mut self.cpu, &mut self.memory, op),
+            _ => return Ok(()),
+        }
+        self.flush_decode_cache();
+        if self.memory.ram.len() != ram_size {
+            self.memory.ram.resize(ram_size, 0);
+            self.recording = false;
+            return Err(StepError::RamResized { addr: pc });
+        }
+        Ok(())
+    }
+
+    // Stop instead of executing $0000 as SYS $000, enabled by default
+    pub fn set_stop_on_empty(&mut self, enabled: bool) {
+        self.stop_on_empty = enabled;
+    }
+
+    pub fn step(&mut self) -> core::result::Result<StepOutcome, StepError> {
+        self.execute(false).map(|(outcome, _)| outcome)
+    }
+
+    // Set whole keypad from bitmask, bit k for key k, then step once
+    pub fn step_with_keys(&mut self, keys: u16) -> core::result::Result<StepOutcome, StepError> {
+        for key in 0..self.keys.len() {
+            self.set_key(key, keys & (1 << key) != 0);
+        }
+        self.step()
+    }
+
+    // Execute next instruction, in dry run framebuffer is left untouched.
+    // Returns step outcome and whether DRAW collided.
+    pub fn execute(&mut self, dry_run: bool) -> core::result::Result<(StepOutcome, bool), StepError> {
+        let mut collided = false;
+        if self.halted {
+            return Err(StepError::Stopped(StopReason::Halted));
+        }
+        if self.cpu.pc >= self.memory.ram.len() {
+            // dry run reports PC past end of RAM without wrapping or halting
+            match self.pc_policy {
+                PcPolicy::Wrap if !dry_run => self.cpu.pc = self.memory.addr(self.cpu.pc),
+                PcPolicy::Halt => {
+                    self.halted = !dry_run;
+                    return Err(StepError::Stopped(StopReason::RanPastMemory));
+                }
+                _ => return Err(StepError::PcOutOfRange { pc: self.cpu.pc }),
+            }
+        }
+        let pc = self.cpu.pc;
+        if !dry_run {
+            self.apply_key_events();
+        }
+        let (opcode, op) = match self.fetch() {
+            Some(instruction) => instruction,
+            None => {
+                if !dry_run {
+                    self.halted = true;
+                }
+                return Err(StepError::Stopped(StopReason::RanPastMemory));
+            }
+        };
+        if opcode == 0x0000 && self.stop_on_empty {
+            if !dry_run {
+                self.halted = true;
+            }
+            return Err(StepError::Stopped(StopReason::RanIntoEmptyMemory));
+        }
+        if let ISA::NOP(opcode) = op {
+            if !self.has_op_handler(&op) {
+                return Err(StepError::InvalidOpcode { opcode, addr: pc });
+            }
+        }
+        if let ISA::SCD(_) | ISA::SCR | ISA::SCL | ISA::EXIT = op {
+            if !self.quirks.schip_opcodes && !self.has_op_handler(&op) {
+                return Err(StepError::InvalidOpcode { opcode, addr: pc });
+            }
+        }
+        if let ISA::CALL(_) = op {
+            if self.cpu.sp == 0 {
+                return Err(StepError::StackOverflow { addr: pc });
+            }
+        }
+        if let ISA::RET = op {
+            // entry point slot already popped
+            if self.cpu.sp >= self.memory.stack.len() {
+                return Err(StepError::StackUnderflow { addr: pc });
+            }
+        }
+        if let Some((addr, len)) = self.ram_write_range(&op) {
+            self.check_writable(addr, len)?;
+        }
+        self.misaligned |= pc & 1 != 0;
+        // register masks only matter while tracking uninitialized reads
+        let writes = if self.uninit_reads.is_some() {
+            let (reads, writes) = self.register_access(&op);
+            let unset = reads & !self.written_regs;
+            if let Some(log) = self.uninit_reads.as_mut() {
+                log.extend((0..16).filter(|x| unset & (1 << x) != 0).map(|x| (pc, op, x)));
+            }
+            // report each register once
+            self.written_regs |= reads;
+            writes
+        } else {
+            0
+        };
+        if let Some(hook) = self.pre_hook.as_mut() {
+            hook(pc, &op);
+        }
+        if self.undo_depth > 0 {
+            if self.undo_log.len() == self.undo_depth {
+                self.undo_log.pop_front();
+            }
+            self.undo_log.push_back(UndoEntry {
+                cpu: self.cpu.clone(),
+                pending_key: self.pending_key,
+                resolution: self.resolution(),
+                audio: self.memory.audio,
+                tone_hz: self.tone_hz,
+                ram: Vec::new(),
+                stack: Vec::new(),
+                fb: Vec::new(),
+            });
+            self.recording = true;
+        }
+        self.last_changed_reg = None;
+        self.key_waiting = false;
+        match op {
+            custom if self.has_op_handler(&custom) => self.call_op_handler(&custom, pc, dry_run)?,
+            ISA::CLS => self.exec_cls(dry_run),
+            ISA::LORES => self.exec_resolution(false, dry_run),
+            ISA::HIRES => self.exec_resolution(true, dry_run),
+            ISA::SCD(n) => self.exec_scroll(0, n as isize, dry_run),
+            ISA::SCR => self.exec_scroll(4, 0, dry_run),
+            ISA::SCL => self.exec_scroll(-4, 0, dry_run),
+            ISA::EXIT => self.exec_exit(dry_run),
+            ISA::RET => self.exec_ret(),
+            ISA::SYS(n) => self.exec_sys(n),
+            ISA::JP(n) => self.cpu.pc = n,
+            ISA::CALL(n) => self.exec_call(n),
+            ISA::SKE(x, n) => self.exec_skip(self.cpu.r[x] == n),
+            ISA::SKNE(x, n) => self.exec_skip(self.cpu.r[x] != n),
+            ISA::SKRE(x, y) => self.exec_skip(self.cpu.r[x] == self.cpu.r[y]),
+            ISA::SKRNE(x, y) => self.exec_skip(self.cpu.r[x] != self.cpu.r[y]),
+            ISA::SKPR(x) => self.exec_skip(self.keys[(self.cpu.r[x] & 0xf) as usize]),
+            ISA::SKUP(x) => self.exec_skip(!self.keys[(self.cpu.r[x] & 0xf) as usize]),
+            ISA::LOAD(x, n) => self.exec_load(x, n),
+            ISA::ADD(x, n) => self.exec_load(x, self.cpu.r[x].wrapping_add(n)),
+            ISA::MOVE(x, y) => self.exec_load(x, self.cpu.r[y]),
+            ISA::OR(x, y) => self.exec_logic(x, self.cpu.r[x] | self.cpu.r[y]),
+            ISA::AND(x, y) => self.exec_logic(x, self.cpu.r[x] & self.cpu.r[y]),
+            ISA::XOR(x, y) => self.exec_logic(x, self.cpu.r[x] ^ self.cpu.r[y]),
+            ISA::ADDR(x, y) => {
+                let (value, carry) = self.cpu.r[x].overflowing_add(self.cpu.r[y]);
+                self.exec_flagged(x, value, carry as u8);
+            },
+            ISA::SUB(x, y) => {
+                let (value, borrow) = self.cpu.r[x].overflowing_sub(self.cpu.r[y]);
+                self.exec_flagged(x, value, !borrow as u8);
+            },
+            ISA::SUBN(x, y) => {
+                let (value, borrow) = self.cpu.r[y].overflowing_sub(self.cpu.r[x]);
+                self.exec_flagged(x, value, !borrow as u8);
+            },
+            ISA::SHR(x, y) => {
+                let value = if self.quirks.shift_uses_vy { self.cpu.r[y] } else { self.cpu.r[x] };
+                self.exec_flagged(x, value >> 1, value & 0x1);
+            },
+            ISA::SHL(x, y) => {
+                let value = if self.quirks.shift_uses_vy { self.cpu.r[y] } else { self.cpu.r[x] };
+                self.exec_flagged(x, value << 1, value >> 7);
+            },
+            ISA::LOADI(n) => self.exec_loadi(n),
+            ISA::JUMPI(n, x) => self.exec_jumpi(n, x),
+            ISA::RAND(x, n) => {
+                let value = self.rng.next_byte() & n;
+                self.exec_load(x, value);
+            },
+            ISA::DRAW(x, y, n) => collided = self.exec_draw(x, y, n, dry_run),
+            ISA::MOVED(x) => self.exec_load(x, self.cpu.dt),
+            ISA::KEYD(x) => self.exec_keyd(x),
+            ISA::LOADD(x) => self.exec_timers(Some(self.cpu.r[x]), None),
+            ISA::LOADS(x) => self.exec_timers(None, Some(self.cpu.r[x])),
+            ISA::ADDI(x) => self.exec_addi(x),
+            ISA::LDSPR(x) => self.exec_loadi(((self.cpu.r[x] & 0xf) * 5) as usize),
+            ISA::BCD(x) => self.exec_bcd(x),
+            ISA::STOR(n) => self.exec_stor(n),
+            ISA::READ(n) => self.exec_read(n),
+            ISA::PITCH(x) => {
+                self.tone_hz = pitch_to_hz(self.cpu.r[x]);
+                self.cpu.pc += 2;
+            },
+            ISA::AUDIO => self.exec_audio(),
+            ISA::NOP(_) => unreachable!(),
+        };
+        #[cfg(debug_assertions)]
+        check_vf(&op, self.cpu.r[0xf], &self.quirks);
+        let new_pc = self.cpu.pc;
+        let outcome = StepOutcome {
+            pc,
+            opcode,
+            // DRAW held back by display_wait stays on same PC
+            drew: op.is_draw() && new_pc != pc,
+            branched: new_pc != pc + op.size(),
+            new_pc,
+            key_waiting: self.key_waiting,
+            written_addresses: core::mem::take(&mut self.step_writes),
+            op,
+        };
+        self.recording = false;
+        if !self.key_waiting {
+            self.written_regs |= writes;
+        }
+        // nothing can move PC out of a jump to itself
+        if let ISA::JP(_) = op {
+            if !dry_run {
+                self.halted |= new_pc == pc;
+            }
+        }
+        if let Some(trace) = self.trace.as_mut() {
+            trace.push((self.stats.cycles, pc, outcome.opcode, outcome.op));
+        }
+        self.stats.cycles += 1;
+        if self.recent_pcs.len() == self.spin_window {
+            self.recent_pcs.pop_front();
+        }
+        if self.spin_window > 0 {
+            self.recent_pcs.push_back(pc);
+        }
+        if self.pc_history_len > 0 {
+            // drop old half at once so history stays a slice
+            if self.pc_history.len() == 2 * self.pc_history_len {
+                self.pc_history.drain(..self.pc_history_len);
+            }
+            self.pc_history.push(pc);
+        }
+        if let Some(events) = self.vf_events.as_mut() {
+            if writes_flag(&outcome.op, &self.quirks) {
+                events.push((pc, outcome.op, self.cpu.r[0xf]));
+            }
+        }
+        if outcome.op.is_skip() && outcome.branched {
+            self.stats.skips_taken += 1;
+        }
+        if outcome.drew && !dry_run {
+            let (width, height) = self.resolution();
+            self.renderer.present(&self.memory.fb[..], width, height);
+        }
+        if let Some(hook) = self.post_hook.as_mut() {
+            hook(&outcome);
+        }
+        Ok((outcome, collided))
+    }
+
+    // Per-instruction behaviour dispatched from execute, each advances PC
+
+    fn exec_cls(&mut self, dry_run: bool) {
+        if !dry_run {
+            self.clear_fb();
+        }
+        self.cpu.pc += 2;
+    }
+
+    fn exec_resolution(&mut self, hires: bool, dry_run: bool) {
+        if !dry_run {
+            self.set_resolution(hires);
+        }
+        self.cpu.pc += 2;
+    }
+
+    fn exec_scroll(&mut self, dx: isize, dy: isize, dry_run: bool) {
+        if !dry_run {
+            self.scroll(dx, dy);
+        }
+        self.cpu.pc += 2;
+    }
+
+    fn exec_exit(&mut self, dry_run: bool) {
+        // PC stays on EXIT, further steps report Halted
+        if !dry_run {
+            self.halted = true;
+        }
+    }
+
+    fn exec_ret(&mut self) {
+        self.cpu.pc = self.memory.stack[self.cpu.sp] as usize;
+        self.cpu.sp += 1;
+    }
+
+    fn exec_sys(&mut self, n: usize) {
+        if self.quirks.sys_is_noop {
+            self.cpu.pc += 2;
+        } else {
+            self.cpu.pc = n;
+        }
+    }
+
+    fn exec_call(&mut self, n: usize) {
+        self.cpu.sp -= 1;
+        self.cpu.pc += 2;
+        self.write_stack(self.cpu.sp, self.cpu.pc as u16);
+        self.cpu.pc = n;
+    }
+
+    fn exec_skip(&mut self, condition: bool) {
+        self.cpu.pc += if condition { 4 } else { 2 };
+    }
+
+    fn exec_load(&mut self, x: usize, value: u8) {
+        self.cpu.r[x] = value;
+        self.last_changed_reg = Some(x);
+        self.cpu.pc += 2;
+    }
+
+    fn exec_logic(&mut self, x: usize, value: u8) {
+        self.exec_load(x, value);
+        if self.quirks.logic_resets_vf {
+            self.set_vf(0);
+        }
+    }
+
+    // Flag lands last so it wins when x is VF
+    fn exec_flagged(&mut self, x: usize, value: u8, flag: u8) {
+        self.exec_load(x, value);
+        self.set_vf(flag);
+    }
+
+    fn set_vf(&mut self, flag: u8) {
+        self.cpu.r[0xf] = flag;
+        self.last_changed_reg = Some(0xf);
+    }
+
+    fn exec_loadi(&mut self, n: usize) {
+        self.cpu.i = n;
+        self.cpu.pc += 2;
+    }
+
+    fn exec_jumpi(&mut self, n: usize, x: usize) {
+        // SCHIP reads Bxnn as jump to xnn + Vx
+        let x = if self.quirks.jump_uses_vx { x } else { 0 };
+        self.cpu.pc = self.memory.addr(self.cpu.r[x] as usize + n);
+    }
+
+    // Returns whether sprite collided
+    fn exec_draw(&mut self, x: usize, y: usize, n: usize, dry_run: bool) -> bool {
+        if self.quirks.display_wait && !self.vblank {
+            // VIP draws once per frame, retry after next tick
+            return false;
+        }
+        let px = self.cpu.r[x] as usize;
+        let py = self.cpu.r[y] as usize;
+        let (rows, width) = self.sprite_rows(n);
+        let collided = self.draw(px, py, (&rows, width), !dry_run);
+        if collided && !dry_run && self.collision_history_len > 0 {
+            self.frame_collisions += 1;
+        }
+        self.set_vf(collided as u8);
+        self.vblank = false;
+        self.stats.draws += 1;
+        self.cpu.pc += 2;
+        collided
+    }
+
+    fn exec_keyd(&mut self, x: usize) {
+        // VIP waits for key press and then for its release
+        match self.pending_key {
+            Some(key) if !self.keys[key] => {
+                self.pending_key = None;
+                self.exec_load(x, key as u8);
+            }
+            Some(_) => self.key_waiting = true,
+            None => {
+                self.track_presses();
+                self.pending_key = self.select_key();
+                self.key_waiting = true;
+            }
+        }
+    }
+
+    fn exec_timers(&mut self, dt: Option<u8>, st: Option<u8>) {
+        self.cpu.dt = dt.unwrap_or(self.cpu.dt);
+        self.cpu.st = st.unwrap_or(self.cpu.st);
+        self.cpu.pc += 2;
+    }
+
+    fn exec_addi(&mut self, x: usize) {
+        let sum = self.cpu.i + self.cpu.r[x] as usize;
+        if self.quirks.add_i_sets_vf {
+            self.set_vf((sum > self.i_mask) as u8);
+        }
+        self.cpu.i = sum & self.i_mask;
+        self.cpu.pc += 2;
+    }
+
+    fn exec_bcd(&mut self, x: usize) {
+        let value = self.cpu.r[x];
+        self.write_ram(self.cpu.i, (value / 100) % 10);
+        self.write_ram(self.cpu.i + 1, (value / 10) % 10);
+        self.write_ram(self.cpu.i + 2, value % 10);
+        self.cpu.pc += 2;
+    }
+
+    fn exec_stor(&mut self, n: usize) {
+        for i in 0..(1+n) {
+            self.write_ram(self.cpu.i + i, self.cpu.r[i]);
+        }
+        if self.quirks.mem_increments_i {
+            self.cpu.i = (self.cpu.i + n + 1) & self.i_mask;
+        }
+        self.cpu.pc += 2;
+    }
+
+    fn exec_read(&mut self, n: usize) {
+        for i in 0..(1+n) {
+            self.cpu.r[i] = self.memory.ram[self.memory.addr(self.cpu.i + i)];
+        }
+        if self.quirks.mem_increments_i {
+            self.cpu.i = (self.cpu.i + n + 1) & self.i_mask;
+        }
+        self.last_changed_reg = Some(n);
+        self.cpu.pc += 2;
+    }
+
+    fn exec_audio(&mut self) {
+        for k in 0..self.memory.audio.len() {
+            self.memory.audio[k] = self.memory.ram[self.memory.addr(self.cpu.i + k)];
+        }
+        self.cpu.pc += 2;
+    }
+
+    // Left aligned rows and width of DRAW sprite at I, n rows of 8 pixels.
+    // With n of 0 hires draws 16x16 from 32 bytes, SCHIP lores 8x16 and
+    // CHIP-8 nothing. Reads past end of RAM wrap to $000.
+    fn sprite_rows(&self, n: usize) -> (Vec<u16>, usize) {
+        let byte = |k: usize| self.memory.ram[self.memory.addr(self.cpu.i + k)] as u16;
+        let hires = self.memory.width == 128;
+        match n {
+            0 if hires => ((0..16).map(|j| byte(2 * j) << 8 | byte(2 * j + 1)).collect(), 16),
+            0 if self.quirks.schip_opcodes => ((0..16).map(|j| byte(j) << 8).collect(), 8),
+            _ => ((0..n).map(|j| byte(j) << 8).collect(), 8)
+        }
+    }
+
+    // Framebuffer indices set by sprite rows drawn at (x, y), in plotting
+    // order: rows top to bottom, each row left to right
+    fn sprite_pixels(&self, x: usize, y: usize, sprite: (&[u16], usize)) -> Vec<usize> {
+        self.sprite_cells(x, y, sprite).into_iter().map(|(_, pixel)| pixel).collect()
+    }
+
+    // Plotted pixels tagged with sprite row they come from
+    fn sprite_cells(&self, x: usize, y: usize, (rows, width): (&[u16], usize)) -> Vec<(usize, usize)> {
+        // origin always wraps, clip_sprites only decides about the body
+        let (x, y) = (x % self.memory.width, y % self.memory.height);
+        (0..rows.len()).cartesian_product(0..width)
+            .filter_map(|(j, i)| self.memory.sprite_pixel(rows[j], (x, y), (i, j), self.quirks.clip_sprites).map(|pixel| (j, pixel)))
+            .collect()
+    }
+
+    // XOR 8 pixels wide sprite onto framebuffer at (x, y) same way as DRAW, returns collision flag
+    pub fn draw_sprite(&mut self, x: usize, y: usize, rows: &[u8]) -> bool {
+        let rows: Vec<u16> = rows.iter().map(|&row| (row as u16) << 8).collect();
+        self.draw(x, y, (&rows, 8), true)
+    }
+
+    // Same as draw_sprite but reports which sprite rows hit lit pixels
+    pub fn draw_sprite_detailed(&mut self, x: usize, y: usize, rows: &[u8]) -> Vec<bool> {
+        let rows: Vec<u16> = rows.iter().map(|&row| (row as u16) << 8).collect();
+        let mut collisions = vec![false; rows.len()];
+        for (j, pixel) in self.sprite_cells(x, y, (&rows, 8)) {
+            collisions[j] |= self.memory.fb[pixel] != 0;
+            self.write_fb(pixel, !self.memory.fb[pixel]);
+        }
+        collisions
+    }
+
+    // Collision only counts plotted pixels: with clip_sprites pixels past the
+    // edge don't exist, otherwise they wrap and can hit the opposite edge
+    fn draw(&mut self, px: usize, py: usize, sprite: (&[u16], usize), commit: bool) -> bool {
+        let mut collided = false;
+        for pixel in self.sprite_pixels(px, py, sprite) {
+            collided |= self.memory.fb[pixel] != 0;
+            if commit {
+                self.write_fb(pixel, !self.memory.fb[pixel]);
+            }
+        }
+        collided
+    }
+
+    pub fn would_collide(&self, x: usize, y: usize, n: usize) -> bool {
+        let (rows, width) = self.sprite_rows(n);
+        self.sprite_pixels(x, y, (&rows, width)).into_iter().any(|pixel| self.memory.fb[pixel] != 0)
+    }
+
+    // Run until program halts, e.g. by jumping to itself, returns executed
+    // instructions. Errors with CycleLimitReached after max_cycles.
+    pub fn run(&mut self, max_cycles: usize) -> core::result::Result<usize, StepError> {
+        for executed in 0..max_cycles {
+            if self.halted {
+                return Ok(executed);
+            }
+            let outcome = self.step()?;
+            if let Some(callback) = self.trace_callback.as_mut() {
+                callback(&outcome);
+            }
+        }
+        if !self.halted {
+            return Err(StepError::Stopped(StopReason::CycleLimitReached));
+        }
+        Ok(max_cycles)
+    }
+
+    // Step until DRAW, CLS, scroll or resolution change
+    pub fn run_until_draw(&mut self, max_cycles: usize) -> core::result::Result<RunStatus, StepError> {
+        for executed in 1..=max_cycles {
+            let outcome = self.step()?;
+            if outcome.drew {
+                return Ok(RunStatus::Drew(executed));
+            }
+        }
+        Ok(RunStatus::CycleLimit)
+    }
+
+    // Step up to n times keeping every outcome, error that stopped the batch is returned alongside
+    pub fn step_n(&mut self, n: usize) -> (Vec<StepOutcome>, Option<StepError>) {
+        let mut outcomes = Vec::with_capacity(n.min(4096));
+        for _ in 0..n {
+            match self.step() {
+                Ok(outcome) => outcomes.push(outcome),
+                Err(err) => return (outcomes, Some(err)),
+            }
+        }
+        (outcomes, None)
+    }
+
+    // Run one 60Hz frame worth of instructions then tick timers once.
+    // Returns number of executed instructions, fewer once program idles.
+    pub fn run_frame(&mut self, ips: u32) -> core::result::Result<usize, StepError> {
+        let count = (ips / 60) as usize;
+        let mut executed = 0;
+        for _ in 0..count {
+            // idle loop only waits for timers
+            if self.idle_outcome().is_some() {
+                break;
+            }
+            self.step()?;
+            executed += 1;
+        }
+        self.tick();
+        Ok(executed)
+    }
+
+    // Run in real time for given duration at ips instructions per second,
+    // timers tick at 60Hz. Returns number of executed instructions.
+    #[cfg(feature = "std")]
+    pub fn run_realtime(&mut self, duration_ms: u32, ips: u32) -> usize {
+        let start = Instant::now();
+        let total = (ips as u64 * duration_ms as u64 / 1000) as usize;
+        let mut ticks = 0;
+        for executed in 0..total {
+            let due = Duration::from_secs_f64(executed as f64 / ips as f64);
+            let elapsed = start.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+            let due_ticks = start.elapsed().as_millis() * 60 / 1000;
+            while ticks < due_ticks {
+                self.tick();
+                ticks += 1;
+            }
+            if self.step().is_err() && self.idle_outcome().is_none() {
+                return executed;
+            }
+        }
+        total
+    }
+
+    pub fn tone_hz(&self) -> f32 {
+        self.tone_hz
+    }
+
+    pub fn set_tone_hz(&mut self, hz: f32) {
+        self.tone_hz = hz;
+    }
+
+    pub fn audio_pattern(&self) -> &[u8; 16] {
+        &self.memory.audio
+    }
+
+    pub fn is_sound_active(&self) -> bool {
+        self.cpu.st > 0
+    }
+
+    pub fn tick(&mut self) {
+        self.tick_timers(1);
+    }
+
+    // Debuggers freeze time while inspecting, tick and tick_timers do nothing until unfrozen
+    pub fn set_timers_frozen(&mut self, frozen: bool) {
+        self.timers_frozen = frozen;
+    }
+
+    pub fn timers_frozen(&self) -> bool {
+        self.timers_frozen
+    }
+
+    // Catch up n 60Hz frames at once, timers stop at zero
+    pub fn tick_timers(&mut self, n: u8) {
+        if n == 0 || self.timers_frozen {
+            return;
+        }
+        self.vblank = true;
+        if self.collision_history_len > 0 {
+            if self.collision_history.len() == 2 * self.collision_history_len {
+                self.collision_history.drain(..self.collision_history_len);
+            }
+            self.collision_history.push(self.frame_collisions);
+            self.frame_collisions = 0;
+        }
+        // simluate timers
+        let dt_expired = self.cpu.dt > 0 && self.cpu.dt <= n;
+        self.cpu.dt = self.cpu.dt.saturating_sub(n);
+        if let Some(callback) = self.dt_zero_callback.as_mut().filter(|_| dt_expired) {
+            callback();
+        }
+        if self.cpu.st > 0 {
+            self.sound_edge(true);
+            self.cpu.st = self.cpu.st.saturating_sub(n);
+        }
+        if self.cpu.st == 0 {
+            self.sound_edge(false);
+        }
+        for (hold, &pressed) in self.key_hold.iter_mut().zip(self.keys.iter()) {
+            *hold = if pressed { hold.saturating_add(n as u32) } else { 0 };
+        }
+    }
+
+}
+
+#[cfg(test)]
+fn machine_with(ops: &[u16]) -> Machine {
+    let bytes: Vec<u8> = ops.iter().flat_map(|op| op.to_be_bytes()).collect();
+    let mut m = Machine::new();
+    m.load_bytes(&bytes);
+    m
+}
+
+#[cfg(test)]
+#[test]
+fn test_logic_resets_vf() {
+    // LOAD rF, $01, OR r0, r1, LOAD rF, $01, AND r0, r1, LOAD rF, $01, XOR r0, r1
+    let ops = [0x6F01, 0x8011, 0x6F01, 0x8012, 0x6F01, 0x8013];
+    let mut m = machine_with(&ops);
+    m.quirks_mut().logic_resets_vf = false;
+    for _ in 0..3 {
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(m.cpu.r[0xf], 1);
+    }
+    let mut m = machine_with(&ops);
+    m.quirks_mut().logic_resets_vf = true;
+    for _ in 0..3 {
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(m.cpu.r[0xf], 0);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_font_sprite() {
+    let m = Machine::new();
+    let bits = |glyph: [[bool; 8]; 5]| glyph.iter()
+        .map(|row| row.iter().fold(0u8, |byte, &cell| byte << 1 | cell as u8))
+        .collect::<Vec<u8>>();
+    assert_eq!(bits(m.font_sprite(0x0)), [0xF0, 0x90, 0x90, 0x90, 0xF0]);
+    assert_eq!(bits(m.font_sprite(0xF)), [0xF0, 0x80, 0xF0, 0x80, 0x80]);
+    assert_eq!(m.font_sprite(0x1A), m.font_sprite(0xA));
+}
+
+#[cfg(test)]
+#[test]
+fn test_read_sprite() {
+    let mut m = Machine::new();
+    m.memory.ram[0x300..0x302].copy_from_slice(&[0x81, 0xFF]);
+    let sprite = m.read_sprite(0x300, 2);
+    assert_eq!(sprite.len(), 2);
+    assert_eq!(sprite[0], [true, false, false, false, false, false, false, true]);
+    assert!(sprite[1].iter().all(|&p| p));
+    assert_eq!(m.read_sprite(0x300, 16)[0].len(), 16);
+    assert_eq!(m.read_sprite(4094, 5).len(), 2);
+    assert!(m.read_sprite(5000, 5).is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn test_jumpi_wraps_address() {
+    let mut m = Machine::new();
+    m.reset();
+    m.memory.ram[0x200..0x202].copy_from_slice(&[0xBF, 0xFF]);
+    m.cpu.r[0] = 0xFF;
+    assert!(m.step().is_ok());
+    assert_eq!(m.cpu.pc, 0x0FE);
+}
+
+#[cfg(test)]
+#[test]
+fn test_jumpi_register() {
+    // JUMPI $310
+    let mut m = machine_with(&[0xB310]);
+    m.cpu.r[0] = 0x01;
+    m.cpu.r[3] = 0x20;
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x311);
+    m.reset();
+    m.goto_entry();
+    m.quirks_mut().jump_uses_vx = true;
+    m.cpu.r[0] = 0x01;
+    m.cpu.r[3] = 0x20;
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x330);
+}
+
+#[cfg(test)]
+#[test]
+fn test_step_outcome() {
+    let mut m = Machine::new();
+    m.load_bytes(&[0x00, 0xE0, 0x12, 0x00]);
+    let outcome = m.step().unwrap();
+    assert_eq!(outcome.opcode, 0x00E0);
+    assert!(outcome.drew);
+    assert!(!outcome.branched);
+    assert_eq!(outcome.new_pc, 0x202);
+    let outcome = m.step().unwrap();
+    assert_eq!(outcome.op, ISA::JP(0x200));
+    assert!(!outcome.drew);
+    assert!(outcome.branched);
+    // DRAW waiting for vblank touched nothing
+    m.load_bytes(&[0xD0, 0x05, 0xD0, 0x05]);
+    assert!(m.step().unwrap().drew);
+    let outcome = m.step().unwrap();
+    assert_eq!(outcome.new_pc, 0x202);
+    assert!(!outcome.drew);
+    m.tick();
+    assert!(m.step().unwrap().drew);
+}
+
+#[cfg(test)]
+#[test]
+fn test_press_direction() {
+    let mut m = Machine::new();
+    m.press_direction(Direction::Up);
+    assert!(m.keys[0x2]);
+    m.release_direction(Direction::Up);
+    assert!(!m.keys[0x2]);
+    m.set_direction_map([0x1, 0x2, 0x3, 0xC]);
+    m.press_direction(Direction::Right);
+    assert!(m.keys[0xC]);
+    // direction press is newer than key pressed before it
+    m.set_key_policy(KeyPolicy::MostRecent);
+    m.release_direction(Direction::Right);
+    m.set_key(0x1, true);
+    m.press_direction(Direction::Right);
+    assert_eq!(m.select_key(), Some(0xC));
+    m.tick();
+    m.release_direction(Direction::Right);
+    assert_eq!(m.key_hold_ticks(0xC), 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_self_modified() {
+    let mut m = Machine::new();
+    // LOADI $200, STOR $1
+    m.load_bytes(&[0xA2, 0x00, 0xF1, 0x55]);
+    m.step().unwrap();
+    m.step().unwrap();
+    assert!(!m.was_self_modified());
+    m.set_track_self_modify(true);
+    m.load_bytes(&[0xA2, 0x00, 0xF1, 0x55]);
+    m.step().unwrap();
+    m.step().unwrap();
+    assert!(m.was_self_modified());
+    assert_eq!(m.self_modified_addresses(), &[0x200, 0x201]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_reset_keys() {
+    let mut m = Machine::new();
+    m.keys[0x1] = true;
+    m.keys[0x5] = true;
+    m.keys[0xF] = true;
+    m.reset_keys();
+    assert!(m.keys.iter().all(|&k| !k));
+    // held key is released by reset, hold time included
+    m.set_key(0x5, true);
+    m.tick();
+    assert_eq!(m.key_hold_ticks(0x5), 1);
+    m.reset();
+    assert!(m.keys_pressed().is_empty());
+    assert_eq!(m.key_hold_ticks(0x5), 0);
+    m.set_key(0x5, true);
+    m.reset_cpu_only();
+    assert!(m.keys_pressed().is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn test_keys_pressed() {
+    let mut m = Machine::new();
+    assert!(m.keys_pressed().is_empty());
+    m.keys[0x9] = true;
+    m.keys[0x1] = true;
+    assert_eq!(m.keys_pressed(), [0x1, 0x9]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_draw_wraps_ram() {
+    let mut m = Machine::new();
+    // DRAW r0, r0, $5
+    m.load_bytes(&[0xD0, 0x05]);
+    m.cpu.i = 0xFFF;
+    m.memory.ram[0xFFF] = 0x80;
+    assert!(m.step().is_ok());
+    // rows 1..5 come from font glyph 0 at $000
+    assert_ne!(m.memory.fb[0], 0);
+    assert_eq!(m.memory.fb[64..68], [0xFF; 4]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_set_target() {
+    let mut m = Machine::new();
+    assert_eq!(*m.quirks(), Quirks::for_target(Target::Chip8));
+    m.set_resolution(true);
+    m.set_target(Target::SuperChip);
+    assert_eq!(m.resolution(), (64, 32));
+    assert!(m.quirks().jump_uses_vx);
+    m.quirks_mut().jump_uses_vx = false;
+    assert!(!m.quirks().jump_uses_vx);
+    // LOAD r0, $01, LOAD r1, $80, SHR r0, r1
+    m.set_target(Target::Chip8);
+    m.load_bytes(&[0x60, 0x01, 0x61, 0x80, 0x80, 0x16]);
+    m.step().unwrap();
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.cpu.r[0], 0x40);
+    assert_eq!(m.cpu.r[0xf], 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_ram_size_per_target() {
+    // LOAD r0, $10, LOADI $FF8, ADDI r0, READ r0
+    let ops = [0x6010, 0xAFF8, 0xF01E, 0xF065];
+    let mut m = machine_with(&ops);
+    m.set_target(Target::Chip8);
+    m.memory.ram[0x008] = 0x11;
+    for _ in 0..4 {
+        m.step().unwrap();
+    }
+    assert_eq!(m.mem().ram.len(), 0x1000);
+    assert_eq!((m.cpu.i, m.cpu.r[0]), (0x009, 0x11));
+
+    let mut m = machine_with(&ops);
+    m.set_target(Target::XoChip);
+    assert_eq!(m.mem().ram.len(), 0x10000);
+    m.memory.ram[0x1008] = 0x22;
+    for _ in 0..4 {
+        m.step().unwrap();
+    }
+    assert_eq!((m.cpu.i, m.cpu.r[0]), (0x1009, 0x22));
+    // 16-bit I wraps at $FFFF
+    m.cpu.i = 0xFFF8;
+    m.cpu.pc = 0x204;
+    m.cpu.r[0] = 0x10;
+    m.step().unwrap();
+    assert_eq!(m.cpu.i, 0x0008);
+    // shrinking back keeps low RAM and rewraps I
+    m.cpu.i = 0x1234;
+    m.set_target(Target::Chip8);
+    assert_eq!((m.mem().ram.len(), m.cpu.i, m.mem().ram[0x200]), (0x1000, 0x234, 0x60));
+    assert_eq!(m.i_mask(), 0xfff);
+    // narrowed mask survives resizing
+    m.set_i_mask(0xff);
+    m.set_target(Target::XoChip);
+    assert_eq!(m.i_mask(), 0xff);
+}
+
+#[cfg(test)]
+#[test]
+fn test_load_hex() {
+    let mut m = Machine::new();
+    assert_eq!(m.load_hex("0x60 0x0A, 7001 # load and add\n# comment only\n00E0"), Ok(6));
+    assert_eq!(m.memory.ram[0x200..0x206], [0x60, 0x0A, 0x70, 0x01, 0x00, 0xE0]);
+    let err = m.load_hex("60 0A\n123").unwrap_err();
+    assert_eq!(err, HexError { line: 2, token: String::from("123") });
+    assert!(m.load_hex("zz").is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_resolution() {
+    let mut m = Machine::new();
+    assert_eq!(m.resolution(), (64, 32));
+    // HIRES, DRAW r0, r0, $1, LORES
+    m.load_bytes(&[0x00, 0xFF, 0xD0, 0x01, 0x00, 0xFE]);
+    m.step().unwrap();
+    assert_eq!(m.resolution(), (128, 64));
+    m.step().unwrap();
+    assert_ne!(m.memory.fb[0], 0);
+    // dry run keeps mode and screen
+    m.execute(true).unwrap();
+    assert_eq!((m.resolution(), m.take_resolution_changed()), ((128, 64), Some((128, 64))));
+    assert_ne!(m.memory.fb[0], 0);
+    m.cpu.pc = 0x204;
+    m.step().unwrap();
+    assert_eq!(m.resolution(), (64, 32));
+    assert!(m.memory.fb.iter().all(|&p| p == 0));
+}
+
+#[cfg(test)]
+#[test]
+fn test_would_collide() {
+    let mut m = Machine::new();
+    m.quirks_mut().display_wait = false;
+    // LOADI $000, DRAW r0, r0, $5, DRAW r0, r0, $5
+    m.load_bytes(&[0xA0, 0x00, 0xD0, 0x05, 0xD0, 0x05]);
+    m.step().unwrap();
+    assert!(!m.would_collide(0, 0, 5));
+    m.step().unwrap();
+    assert!(m.would_collide(0, 0, 5));
+    assert!(!m.would_collide(8, 0, 5));
+    let fb = m.memory.fb;
+    let (outcome, collided) = m.execute(true).unwrap();
+    assert!(collided);
+    assert!(outcome.drew);
+    assert_eq!(m.cpu.r[0xf], 1);
+    assert_eq!(m.memory.fb[..], fb[..]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_display_machine() {
+    let mut m = Machine::new();
+    m.load_bytes(&[]);
+    m.memory.fb[1] = 0xFF;
+    let text = format!("{}", m);
+    let lines: Vec<&str> = text.lines().collect();
+    assert!(lines[0].starts_with("PC:$200"));
+    assert_eq!(lines[1], "");
+    assert_eq!(lines[2].trim_end(), " 0");
+    assert_eq!(lines.len(), 2 + 32);
+}
+
+#[cfg(test)]
+#[test]
+fn test_undo() {
+    let mut m = Machine::new();
+    // LOAD r0, $07, LOADI $300, DRAW r1, r1, $5, BCD r0, CALL $200
+    m.load_bytes(&[0x60, 0x07, 0xA3, 0x00, 0xD1, 0x15, 0xF0, 0x33, 0x22, 0x00]);
+    m.memory.ram[0x300..0x303].copy_from_slice(&[0xF0, 0x90, 0x90]);
+    m.enable_undo(4);
+    for _ in 0..5 {
+        m.step().unwrap();
+    }
+    assert_eq!(m.memory.ram[0x302], 7);
+    assert_ne!(m.memory.fb[0], 0);
+    assert!(m.undo());
+    assert_eq!(m.cpu.pc, 0x208);
+    assert_eq!(m.memory.stack[15], 0);
+    assert!(m.undo());
+    assert_eq!(m.memory.ram[0x300..0x303], [0xF0, 0x90, 0x90]);
+    assert!(m.undo());
+    assert!(m.memory.fb.iter().all(|&p| p == 0));
+    assert!(m.undo());
+    assert_eq!(m.cpu.i, 0x000);
+    // depth limit dropped the oldest entry
+    assert!(!m.undo());
+    assert_eq!(m.cpu.r[0], 7);
+    // LOAD r0, $50, PITCH r0, LOADI $300, AUDIO
+    let mut m = machine_with(&[0x6050, 0xF03A, 0xA300, 0xF002]);
+    m.memory.ram[0x300] = 0xAA;
+    m.enable_undo(4);
+    for _ in 0..4 {
+        m.step().unwrap();
+    }
+    assert_eq!(m.memory.audio[0], 0xAA);
+    assert!(m.undo());
+    assert_eq!(m.memory.audio[0], 0);
+    assert!(m.undo());
+    assert!(m.undo());
+    assert_eq!(m.tone_hz(), 440.0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_load_add() {
+    let mut m = machine_with(&[0x600A, 0x7001, 0x70FF]);
+    m.step().unwrap();
+    assert_eq!(m.cpu.r[0], 0x0A);
+    m.step().unwrap();
+    assert_eq!(m.cpu.r[0], 0x0B);
+    // ADD wraps without touching VF
+    m.step().unwrap();
+    assert_eq!(m.cpu.r[0], 0x0A);
+    assert_eq!(m.cpu.r[0xf], 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_skip() {
+    // SKE taken, SKNE not taken, SKRE taken
+    let mut m = machine_with(&[0x6105, 0x3105, 0x0000, 0x4105, 0x6205, 0x5120]);
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x206);
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x208);
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x20E);
+}
+
+#[cfg(test)]
+#[test]
+fn test_current_instruction() {
+    let mut m = machine_with(&[0x600A, 0x1200]);
+    assert_eq!(m.current_instruction(), Some((0x600A, ISA::LOAD(0, 0x0A))));
+    m.step().unwrap();
+    assert_eq!(m.current_instruction(), Some((0x1200, ISA::JP(0x200))));
+    assert_eq!(m.cpu.pc, 0x202);
+}
+
+#[cfg(test)]
+#[test]
+fn test_reset_clears_state() {
+    let mut m = machine_with(&[0x6A05, 0xF015, 0xD005]);
+    m.step().unwrap();
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_ne!(m.memory.fb[0], 0);
+    m.reset_cpu_only();
+    assert_ne!(m.memory.fb[0], 0);
+    m.reset();
+    assert!(m.memory.fb.iter().all(|&p| p == 0));
+    assert!(m.cpu.r.iter().all(|&r| r == 0));
+    assert_eq!((m.cpu.i, m.cpu.dt, m.cpu.st, m.cpu.pc), (0, 0, 0, 0x200));
+}
+
+#[cfg(test)]
+#[test]
+fn test_misaligned_pc() {
+    // JP $203 lands in the middle of LOAD r0, $12
+    let mut m = machine_with(&[0x1203, 0x6012, 0x0000]);
+    m.step().unwrap();
+    assert!(!m.pc_misaligned());
+    assert_eq!(m.current_instruction(), Some((0x1200, ISA::JP(0x200))));
+    m.step().unwrap();
+    assert!(m.pc_misaligned());
+    m.goto_entry();
+    assert_eq!(m.cpu.pc, 0x200);
+}
+
+#[cfg(all(test, feature = "std"))]
+#[test]
+fn test_run_realtime() {
+    // LOAD r0, $FF, LOADD r0, JP $204
+    let mut m = machine_with(&[0x60FF, 0xF015, 0x1204]);
+    let start = Instant::now();
+    assert_eq!(m.run_realtime(100, 500), 50);
+    assert!(start.elapsed() >= Duration::from_millis(90));
+    assert!(m.cpu.dt < 0xFF);
+}
+
+#[cfg(all(test, feature = "std"))]
+#[test]
+fn test_ram_image() {
+    let path = std::env::temp_dir().join(format!("chip8-ram-{}.bin", std::process::id()));
+    let path = path.to_str().unwrap();
+    let mut m = machine_with(&[0x6001]);
+    m.memory.ram[0xFFF] = 0xAB;
+    m.dump_ram(path).unwrap();
+    let mut other = Machine::new();
+    other.load_ram_image(path).unwrap();
+    assert_eq!(other.memory.ram[..], m.memory.ram[..]);
+    std::fs::write(path, [0; 10]).unwrap();
+    assert!(other.load_ram_image(path).is_err());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn test_pitch() {
+    // LOAD r1, $70, PITCH r1, LOAD r1, $40, PITCH r1, LOAD r1, $10, PITCH r1
+    let mut m = machine_with(&[0x6170, 0xF13A, 0x6140, 0xF13A, 0x6110, 0xF13A]);
+    assert_eq!(m.tone_hz(), 440.0);
+    m.step().unwrap();
+    m.step().unwrap();
+    assert!((m.tone_hz() - 8000.0).abs() < 0.01);
+    m.step().unwrap();
+    m.step().unwrap();
+    assert!((m.tone_hz() - 4000.0).abs() < 0.01);
+    m.step().unwrap();
+    m.step().unwrap();
+    assert!((m.tone_hz() - 2000.0).abs() < 0.01);
+}
+
+#[cfg(test)]
+#[test]
+fn test_audio_pattern() {
+    // LOADI $FF8, AUDIO
+    let mut m = machine_with(&[0xAFF8, 0xF002]);
+    m.memory.ram[0xFF8..].copy_from_slice(&[0xAA; 8]);
+    assert_eq!(m.audio_pattern(), &[0; 16]);
+    m.step().unwrap();
+    m.step().unwrap();
+    // pattern past end of RAM wraps around to font at $000
+    assert_eq!(m.audio_pattern()[..8], [0xAA; 8]);
+    assert_eq!(m.audio_pattern()[8..], m.memory.rom[..8]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_load_overlay() {
+    let mut m = machine_with(&[0x6001]);
+    m.step().unwrap();
+    assert_eq!(m.load_overlay(&[0xAB, 0xCD], 0x400), Ok(2));
+    assert_eq!(m.memory.ram[0x400..0x402], [0xAB, 0xCD]);
+    // no reset happened
+    assert_eq!(m.cpu.pc, 0x202);
+    assert_eq!(m.cpu.r[0], 1);
+    assert_eq!(m.load_overlay(&[0; 4], 0xFFE), Err(OverlayError { addr: 0xFFE, len: 4 }));
+    assert_eq!(m.load_overlay(&[0; 2], 0xFFE), Ok(2));
+}
+
+#[cfg(test)]
+#[test]
+fn test_framebuffer_braille() {
+    let mut m = Machine::new();
+    let text = m.framebuffer_braille();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines.len(), 8);
+    assert!(lines.iter().all(|line| line.chars().count() == 32));
+    assert!(text.chars().all(|c| c == '\u{2800}' || c == '\n'));
+    m.memory.fb[0] = 0xFF;
+    m.memory.fb[64 * 3 + 1] = 0xFF;
+    assert!(m.framebuffer_braille().starts_with('\u{2881}'));
+    m.set_resolution(true);
+    assert_eq!(m.framebuffer_braille().lines().count(), 16);
+}
+
+#[cfg(test)]
+#[test]
+fn test_stop_on_empty() {
+    // LOAD r0, $01 followed by zeroed RAM
+    let mut m = machine_with(&[0x6001]);
+    m.step().unwrap();
+    assert_eq!(m.step(), Err(StepError::Stopped(StopReason::RanIntoEmptyMemory)));
+    assert_eq!(m.cpu.pc, 0x202);
+    // legacy behaviour jumps to $000 into the font
+    m.set_stop_on_empty(false);
+    m.quirks_mut().sys_is_noop = false;
+    m.resume();
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x000);
+    // invalid opcode is reported instead of panicking
+    let mut m = machine_with(&[0xFFFF]);
+    assert_eq!(m.step(), Err(StepError::InvalidOpcode { opcode: 0xFFFF, addr: 0x200 }));
+}
+
+#[cfg(test)]
+#[test]
+fn test_dry_run_never_halts() {
+    // JP $200
+    let mut m = machine_with(&[0x1200]);
+    m.execute(true).unwrap();
+    assert!(!m.is_halted());
+    m.step().unwrap();
+    assert!(m.is_halted());
+    // empty memory and fetch past end of RAM
+    let mut m = machine_with(&[]);
+    assert_eq!(m.execute(true).err(), Some(StepError::Stopped(StopReason::RanIntoEmptyMemory)));
+    assert!(!m.is_halted());
+    m.cpu.pc = 0xFFF;
+    assert_eq!(m.execute(true).err(), Some(StepError::Stopped(StopReason::RanPastMemory)));
+    assert!(!m.is_halted());
+}
+
+#[cfg(test)]
+#[test]
+fn test_last_changed_reg() {
+    // LOAD r3, $07, LOADI $300, DRAW r0, r0, $1, ADDR r2, r3
+    let mut m = machine_with(&[0x6307, 0xA300, 0xD001, 0x8234]);
+    m.step().unwrap();
+    assert_eq!(m.last_changed_reg(), Some(3));
+    m.step().unwrap();
+    assert_eq!(m.last_changed_reg(), None);
+    m.step().unwrap();
+    assert_eq!(m.last_changed_reg(), Some(0xf));
+    m.step().unwrap();
+    assert_eq!(m.last_changed_reg(), Some(0xf));
+    // ADD r1, $01 without carry, ADDR r2, r2 with carry, READ r2, ADDI r1
+    m.load_bytes(&[0x71, 0x01, 0x82, 0x24, 0xF2, 0x65, 0xF1, 0x1E]);
+    m.quirks_mut().add_i_sets_vf = true;
+    m.step().unwrap();
+    assert_eq!(m.last_changed_reg(), Some(1));
+    m.cpu.r[2] = 0x80;
+    m.step().unwrap();
+    assert_eq!((m.cpu.r[0xf], m.last_changed_reg()), (1, Some(0xf)));
+    m.step().unwrap();
+    assert_eq!(m.last_changed_reg(), Some(2));
+    m.step().unwrap();
+    assert_eq!(m.last_changed_reg(), Some(0xf));
+    m.quirks_mut().add_i_sets_vf = false;
+    m.cpu.pc = 0x206;
+    m.step().unwrap();
+    assert_eq!(m.last_changed_reg(), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_protect_font() {
+    // LOADI $000, STOR r1
+    let mut m = machine_with(&[0xA000, 0xF155]);
+    m.set_protect_font(true);
+    m.step().unwrap();
+    assert_eq!(m.step(), Err(StepError::WriteToProtectedMemory { addr: 0x000 }));
+    assert_eq!(m.memory.ram[0x000..0x002], [0xF0, 0x90]);
+    assert_eq!(m.cpu.pc, 0x202);
+    m.set_protect_font(false);
+    m.step().unwrap();
+    assert_eq!(m.memory.ram[0x000..0x002], [0x00, 0x00]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_protected_range() {
+    // LOADI $2FE, STOR r1, LOADI $30F, STOR r0, LOADI $310, STOR r0
+    let mut m = machine_with(&[0xA2FE, 0xF155, 0xA30F, 0xF055, 0xA310, 0xF055]);
+    m.set_protected_range(0x300, 0x310);
+    m.step().unwrap();
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.step(), Err(StepError::WriteToProtectedMemory { addr: 0x30F }));
+    m.cpu.pc = 0x208;
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.mem().ram[0x310], 0x00);
+    m.clear_protection();
+    m.cpu.pc = 0x204;
+    m.cpu.r[0] = 0x42;
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.mem().ram[0x30F], 0x42);
+}
+
+#[cfg(test)]
+#[test]
+fn test_debug_line() {
+    let mut m = machine_with(&[0x6307, 0x7301]);
+    assert_eq!(m.debug_line(), "$200 LOAD r3, $007");
+    m.step().unwrap();
+    assert_eq!(m.debug_line(), "$202 ADD r3, $001 ; r3=$07");
+}
+
+#[cfg(test)]
+#[test]
+fn test_registers_hex() {
+    let mut m = Machine::new();
+    m.cpu.r[0x1] = 0x0A;
+    m.cpu.r[0xF] = 0x01;
+    assert_eq!(
+        m.registers_hex(),
+        "V0=00 V1=0A V2=00 V3=00 V4=00 V5=00 V6=00 V7=00 V8=00 V9=00 VA=00 VB=00 VC=00 VD=00 VE=00 VF=01"
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_keyd_waits_for_release() {
+    let mut m = machine_with(&[0xF30A]);
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x200);
+    m.keys[0x5] = true;
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x200);
+    assert!(m.keys[0x5]);
+    m.keys[0x5] = false;
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x202);
+    assert_eq!(m.cpu.r[3], 0x5);
+}
+
+#[cfg(test)]
+#[test]
+fn test_ran_past_memory() {
+    let mut m = machine_with(&[]);
+    m.cpu.pc = 0xFFF;
+    assert_eq!(m.current_instruction(), None);
+    assert_eq!(m.step(), Err(StepError::Stopped(StopReason::RanPastMemory)));
+    m.resume();
+    m.cpu.pc = 0x1000;
+    assert_eq!(m.step(), Err(StepError::PcOutOfRange { pc: 0x1000 }));
+}
+
+#[cfg(test)]
+#[test]
+fn test_pc_policy() {
+    // ADD r0, $01
+    let mut m = machine_with(&[0x7001]);
+    m.cpu.pc = 0x1200;
+    assert_eq!(m.step(), Err(StepError::PcOutOfRange { pc: 0x1200 }));
+    assert!(!m.is_halted());
+    m.set_pc_policy(PcPolicy::Halt);
+    assert_eq!(m.execute(true).err(), Some(StepError::Stopped(StopReason::RanPastMemory)));
+    assert!(!m.is_halted());
+    assert_eq!(m.step(), Err(StepError::Stopped(StopReason::RanPastMemory)));
+    assert!(m.is_halted());
+    m.resume();
+    m.set_pc_policy(PcPolicy::Wrap);
+    assert_eq!(m.execute(true).err(), Some(StepError::PcOutOfRange { pc: 0x1200 }));
+    assert_eq!(m.cpu.pc, 0x1200);
+    m.step().unwrap();
+    assert_eq!((m.cpu.r[0], m.cpu.pc), (1, 0x202));
+}
+
+#[cfg(test)]
+#[test]
+fn test_framebuffer_delta() {
+    // DRAW r0, r0, $1 with font row $F0, CLS
+    let mut m = machine_with(&[0xD001, 0x00E0]);
+    assert!(m.framebuffer_delta().is_empty());
+    m.step().unwrap();
+    assert_eq!(m.framebuffer_delta(), [(0, 0xFF), (1, 0xFF), (2, 0xFF), (3, 0xFF)]);
+    assert!(m.framebuffer_delta().is_empty());
+    m.step().unwrap();
+    assert_eq!(m.framebuffer_delta(), [(0, 0), (1, 0), (2, 0), (3, 0)]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_stack_depth() {
+    // CALL $200 recursing forever
+    let mut m = machine_with(&[0x2200]);
+    for _ in 0..DEFAULT_STACK_DEPTH {
+        m.step().unwrap();
+    }
+    assert_eq!(m.step(), Err(StepError::StackOverflow { addr: 0x200 }));
+    let mut m = MachineBuilder::new().stack_depth(2).build();
+    m.load_bytes(&[0x22, 0x02, 0x22, 0x04, 0x22, 0x06]);
+    assert_eq!(m.memory.stack.len(), 3);
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.step(), Err(StepError::StackOverflow { addr: 0x204 }));
+    assert_eq!(m.memory.stack, [0x204, 0x202, 0x200]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_rom_info() {
+    let m = machine_with(&[0x00E0, 0x00FF, 0x1202]);
+    let info = m.rom_info();
+    assert_eq!(info.bytes, 6);
+    assert_eq!(info.base, 0x200);
+    assert_eq!(info.first_op, Some(ISA::CLS));
+    assert!(info.uses_schip);
+    assert!(!info.uses_xochip);
+    let m = machine_with(&[0xF13A]);
+    assert!(m.rom_info().uses_xochip);
+    assert_eq!(machine_with(&[]).rom_info().first_op, None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_hooks() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    let pre = Arc::new(AtomicUsize::new(0));
+    let post = Arc::new(AtomicUsize::new(0));
+    let (pre_count, post_count) = (pre.clone(), post.clone());
+    // LOAD r0, $01, ADD r0, $01, JP $202
+    let mut m = machine_with(&[0x6001, 0x7001, 0x1202]);
+    m.set_pre_hook(move |pc, _| { pre_count.fetch_add(pc, Ordering::SeqCst); });
+    m.set_post_hook(move |outcome| { post_count.fetch_add(outcome.branched as usize, Ordering::SeqCst); });
+    for _ in 0..5 {
+        m.step().unwrap();
+    }
+    assert_eq!(pre.load(Ordering::SeqCst), 0x200 + 0x202 + 0x204 + 0x202 + 0x204);
+    assert_eq!(post.load(Ordering::SeqCst), 2);
+    m.clear_pre_hook();
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(pre.load(Ordering::SeqCst), 0x200 + 0x202 + 0x204 + 0x202 + 0x204);
+    assert_eq!(post.load(Ordering::SeqCst), 3);
+    m.clear_post_hook();
+    m.step().unwrap();
+    assert_eq!(post.load(Ordering::SeqCst), 3);
+    m.clear_hooks();
+    m.step().unwrap();
+    assert_eq!(post.load(Ordering::SeqCst), 3);
+}
+
+#[cfg(test)]
+#[test]
+fn test_op_handler() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    // SYS $123, LOAD r0, $01, $FFFF
+    let mut m = machine_with(&[0x0123, 0x6001, 0xFFFF]);
+    m.quirks_mut().sys_is_noop = false;
+    m.set_op_handler(ISA::SYS(0), Box::new(move |cpu, _, op| {
+        if let ISA::SYS(n) = *op {
+            counter.fetch_add(n, Ordering::SeqCst);
+            cpu.r[0xA] = 0x42;
+        }
+        cpu.pc += 2;
+    }));
+    m.step().unwrap();
+    assert_eq!((calls.load(Ordering::SeqCst), m.cpu.r[0xA], m.cpu.pc), (0x123, 0x42, 0x202));
+    m.step().unwrap();
+    assert_eq!(m.cpu.r[0], 0x01);
+    // unknown opcode becomes a custom instruction
+    assert_eq!(m.step(), Err(StepError::InvalidOpcode { opcode: 0xFFFF, addr: 0x204 }));
+    m.set_op_handler(ISA::NOP(0), Box::new(|cpu, memory, _| {
+        memory.ram[0x300] = 0x99;
+        cpu.pc += 2;
+    }));
+    m.step().unwrap();
+    assert_eq!(m.mem().ram[0x300], 0x99);
+    // built-in SYS jumps again once cleared
+    m.clear_op_handler(ISA::SYS(0));
+    m.goto_entry();
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x123);
+    assert_eq!(calls.load(Ordering::SeqCst), 0x123);
+}
+
+#[cfg(test)]
+#[test]
+fn test_op_handler_guards() {
+    // ADD r0, $01, SYS $000 patches ADD to $05, JP $200
+    let mut m = machine_with(&[0x7001, 0x0000, 0x1200]);
+    m.set_stop_on_empty(false);
+    m.set_decode_cache(true);
+    m.set_op_handler(ISA::SYS(0), Box::new(|cpu, memory, _| {
+        memory.ram[0x201] = 0x05;
+        cpu.pc += 2;
+    }));
+    m.step().unwrap();
+    // dry run leaves handler alone
+    m.execute(true).unwrap();
+    assert_eq!((m.cpu.pc, m.mem().ram[0x201]), (0x202, 0x01));
+    m.step().unwrap();
+    m.step().unwrap();
+    // cached ADD was dropped
+    m.step().unwrap();
+    assert_eq!(m.cpu.r[0], 0x06);
+    // RAM size is restored and reported
+    m.set_op_handler(ISA::SYS(0), Box::new(|cpu, memory, _| {
+        memory.ram.truncate(100);
+        cpu.pc += 2;
+    }));
+    assert_eq!(m.step(), Err(StepError::RamResized { addr: 0x202 }));
+    assert_eq!(m.mem().ram.len(), DEFAULT_RAM_SIZE);
+}
+
+// Run program until it jumps to itself, stops or max_cycles pass
+#[cfg(test)]
+fn run_rom_until_halt(bytes: &[u8], max_cycles: usize) -> Machine {
+    let mut m = Machine::new();
+    // no ticks between steps for DRAW to wait for
+    m.quirks_mut().display_wait = false;
+    m.load_bytes(bytes);
+    for _ in 0..max_cycles {
+        match m.step() {
+            Ok(outcome) if outcome.new_pc != outcome.pc => {}
+            _ => break
+        }
+    }
+    m
+}
+
+#[cfg(test)]
+fn assemble_ops(ops: &[ISA]) -> Vec<u8> {
+    ops.iter().flat_map(|op| op.encode().to_be_bytes()).collect()
+}
+
+// FNV-1a hash of active framebuffer
+#[cfg(test)]
+fn fb_checksum(m: &Machine) -> u32 {
+    let (width, height) = m.resolution();
+    m.memory.fb[..width * height].iter()
+        .fold(0x811c9dc5, |hash, &pixel| (hash ^ pixel as u32).wrapping_mul(0x01000193))
+}
+
+#[cfg(test)]
+#[test]
+fn test_conformance_flags() {
+    let rom = assemble_ops(&[
+        ISA::LOAD(0, 0xFF), ISA::LOAD(1, 0x02), ISA::ADDR(0, 1), ISA::MOVE(2, 0xF),
+        ISA::LOAD(3, 0x05), ISA::LOAD(4, 0x07), ISA::SUB(3, 4), ISA::MOVE(5, 0xF),
+        ISA::LOAD(6, 0x81), ISA::SHL(6, 6), ISA::MOVE(7, 0xF),
+        // show flags as digits 1 0 1
+        ISA::LOAD(0xA, 0), ISA::LOAD(0xB, 0),
+        ISA::LDSPR(2), ISA::DRAW(0xA, 0xB, 5), ISA::ADD(0xA, 5),
+        ISA::LDSPR(5), ISA::DRAW(0xA, 0xB, 5), ISA::ADD(0xA, 5),
+        ISA::LDSPR(7), ISA::DRAW(0xA, 0xB, 5),
+        ISA::JP(0x22A),
+    ]);
+    let m = run_rom_until_halt(&rom, 100);
+    assert_eq!(m.cpu.pc, 0x22A);
+    assert_eq!((m.cpu.r[0], m.cpu.r[3], m.cpu.r[6]), (0x01, 0xFE, 0x02));
+    assert_eq!((m.cpu.r[2], m.cpu.r[5], m.cpu.r[7]), (1, 0, 1));
+    assert_eq!(fb_checksum(&m), 0xBCEE3A47);
+}
+
+#[cfg(test)]
+#[test]
+fn test_conformance_draw() {
+    let rom = assemble_ops(&[
+        // BCD of 123 unpacked into r0..r2
+        ISA::LOAD(3, 123), ISA::LOADI(0x300), ISA::BCD(3), ISA::READ(2),
+        ISA::LOAD(0xA, 8), ISA::LOAD(0xB, 4),
+        ISA::LDSPR(0), ISA::DRAW(0xA, 0xB, 5), ISA::ADD(0xA, 5),
+        ISA::LDSPR(1), ISA::DRAW(0xA, 0xB, 5), ISA::ADD(0xA, 5),
+        ISA::LDSPR(2), ISA::DRAW(0xA, 0xB, 5),
+        // drawing last digit twice erases it and collides
+        ISA::DRAW(0xA, 0xB, 5), ISA::MOVE(4, 0xF), ISA::DRAW(0xA, 0xB, 5),
+        ISA::JP(0x220),
+    ]);
+    let m = run_rom_until_halt(&rom, 100);
+    assert_eq!(m.cpu.pc, 0x220);
+    assert_eq!(m.cpu.r[0..3], [1, 2, 3]);
+    assert_eq!(m.cpu.r[4], 1);
+    assert_eq!(fb_checksum(&m), 0x3B351BCD);
+}
+
+#[cfg(test)]
+#[test]
+fn test_is_key_waiting() {
+    let mut m = machine_with(&[0xF00A, 0x6001]);
+    assert!(!m.is_key_waiting());
+    assert!(m.step().unwrap().key_waiting);
+    assert!(m.is_key_waiting());
+    m.keys[0x2] = true;
+    m.step().unwrap();
+    assert!(m.is_key_waiting());
+    m.keys[0x2] = false;
+    assert!(!m.step().unwrap().key_waiting);
+    assert!(!m.is_key_waiting());
+}
+
+#[cfg(test)]
+#[test]
+fn test_shift_vf() {
+    // LOAD rF, $81, SHR rF, r0, LOAD rF, $81, SHL rF, r0
+    let mut m = machine_with(&[0x6F81, 0x8F06, 0x6F81, 0x8F0E]);
+    m.quirks_mut().shift_uses_vy = false;
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.cpu.r[0xf], 1);
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.cpu.r[0xf], 1);
+    // shifted out bit is zero
+    let mut m = machine_with(&[0x6F40, 0x8F06, 0x6F40, 0x8F0E]);
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.cpu.r[0xf], 0);
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.cpu.r[0xf], 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_display_wait() {
+    // DRAW r0, r0, $1, DRAW r0, r0, $1
+    let mut m = machine_with(&[0xD001, 0xD001]);
+    m.quirks_mut().display_wait = true;
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x202);
+    m.tick();
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x204);
+}
+
+#[cfg(test)]
+#[test]
+fn test_add_i_sets_vf() {
+    // LOAD r0, $10, LOADI $FF8, ADDI r0
+    let mut m = machine_with(&[0x6010, 0xAFF8, 0xF01E]);
+    m.quirks_mut().add_i_sets_vf = true;
+    m.step().unwrap();
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.cpu.i, 0x008);
+    assert_eq!(m.cpu.r[0xf], 1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_free_ram() {
+    let mut m = Machine::new();
+    assert_eq!((m.ram_used(), m.free_ram()), (0x200, 0xE00));
+    m.load_bytes(&[0x12, 0x00, 0x00]);
+    assert_eq!((m.ram_used(), m.free_ram()), (0x203, 0xDFD));
+    m.set_entry_pc(0x000).unwrap();
+    m.reset();
+    m.load_bytes(&[0x12, 0x00]);
+    assert_eq!((m.ram_used(), m.free_ram()), (80, 4096 - 80));
+}
+
+#[cfg(test)]
+#[test]
+fn test_export_rom() {
+    let mut m = Machine::new();
+    m.load_bytes(&[0x60, 0x01, 0x12, 0x00, 0x00, 0x00]);
+    m.ram_mut()[0x201] = 0x2A;
+    assert_eq!(m.export_rom(), [0x60, 0x2A, 0x12, 0x00, 0x00, 0x00]);
+    assert_eq!(m.export_rom_trimmed(), [0x60, 0x2A, 0x12]);
+    m.ram_mut()[0x208] = 0xFF;
+    assert_eq!(m.export_rom().len(), 6);
+    assert_eq!(m.export_rom_trimmed().len(), 9);
+}
+
+#[cfg(all(test, feature = "std"))]
+#[test]
+fn test_framebuffer_matches() {
+    let mut m = Machine::new();
+    m.draw_sprite(0, 0, &[0xF0, 0x90]);
+    let reference = m.framebuffer_packed();
+    assert_eq!(reference.len(), 64 * 32 / 8);
+    assert_eq!((reference[0], reference[8], reference[9]), (0xF0, 0x90, 0x00));
+    assert!(m.framebuffer_matches(&reference));
+    let path = std::env::temp_dir().join(format!("chip8-reference-{}.bin", std::process::id()));
+    let path = path.to_str().unwrap();
+    std::fs::write(path, &reference).unwrap();
+    assert!(m.framebuffer_matches_file(path).unwrap());
+    m.draw_sprite(8, 8, &[0x80]);
+    assert!(!m.framebuffer_matches(&reference));
+    assert!(!m.framebuffer_matches_file(path).unwrap());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn test_framebuffer_flipped() {
+    let mut m = Machine::new();
+    // "7" glyph at top-left, no symmetry on either axis
+    m.draw_sprite(0, 0, &[0xF0, 0x10, 0x20, 0x40, 0x40]);
+    let fb = m.framebuffer_planes().0.to_vec();
+    assert_eq!(m.framebuffer_flipped(false, false), fb);
+    let h = m.framebuffer_flipped(true, false);
+    let v = m.framebuffer_flipped(false, true);
+    let both = m.framebuffer_flipped(true, true);
+    assert_eq!(h.len(), 64 * 32);
+    let lit = |buf: &[u8], cells: &[usize]| cells.iter().map(|&i| buf[i] != 0).collect::<Vec<bool>>();
+    assert_eq!(lit(&h, &[63, 60, 59, 64 + 60, 64 + 63]), [true, true, false, true, false]);
+    assert_eq!(lit(&v, &[64 * 31, 64 * 31 + 3, 64 * 30 + 3, 64 * 30]), [true, true, true, false]);
+    assert_eq!(lit(&v, &[64 * 27, 64 * 27 + 1]), [false, true]);
+    let mut reversed = fb.clone();
+    reversed.reverse();
+    assert_eq!(both, reversed);
+    assert_eq!(m.framebuffer_planes().0, &fb[..]);
+    m.set_resolution(true);
+    assert_eq!(m.framebuffer_flipped(true, true).len(), 128 * 64);
+}
+
+#[cfg(test)]
+#[test]
+fn test_find_opcode() {
+    // LOADI $000, DRAW r0, r1, $5, ADD r0, $08, DRAW r0, r1, $5, JP $200
+    let m = machine_with(&[0xA000, 0xD015, 0x7008, 0xD015, 0x1200]);
+    assert_eq!(m.find_opcode(0xD000, 0xF000), [0x202, 0x206]);
+    assert_eq!(m.find_opcode(0x1200, 0xFFFF), [0x208]);
+    // rows $F0 $10 of "2" glyph and even-aligned tail of "4" glyph
+    assert_eq!(m.find_opcode(0xF010, 0xFFFF), [0x00A, 0x016]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_describe_opcode() {
+    assert_eq!(Machine::describe_opcode(0x6A0F), "Set register VA to 0x0F");
+    assert_eq!(Machine::describe_opcode(0x00E0), "Clear the screen");
+    assert_eq!(Machine::describe_opcode(0x1234), "Jump to 0x234");
+    assert_eq!(Machine::describe_opcode(0x8124), "Add V2 to V1, VF set on carry");
+    assert_eq!(Machine::describe_opcode(0xD125), "Draw 5 byte sprite from I at (V1, V2), VF set on collision");
+    assert_eq!(Machine::describe_opcode(0xE39E), "Skip next instruction if key in V3 is pressed");
+    assert_eq!(Machine::describe_opcode(0xF533), "Store decimal digits of V5 at I, I+1 and I+2");
+    assert_eq!(Machine::describe_opcode(0xFFFF), "Unknown or invalid opcode 0xFFFF");
+}
+
+#[cfg(test)]
+#[test]
+fn test_opcode_at() {
+    // LOAD r3, $2A
+    let m = machine_with(&[0x632A]);
+    // "0" glyph rows $F0 $90
+    assert_eq!(m.opcode_at(0x000), Some((0xF090, decode_pair((0xF0, 0x90)))));
+    assert_eq!(m.opcode_at(0x200), Some((0x632A, ISA::LOAD(3, 0x2A))));
+    assert_eq!(m.opcode_at(0xFFE).map(|(opcode, _)| opcode), Some(0x0000));
+    assert_eq!(m.opcode_at(0xFFF), None);
+    assert_eq!(m.cpu.pc, 0x200);
+}
+
+#[cfg(test)]
+#[test]
+fn test_fork() {
+    // RAND r0, $FF, ADD r1, $01, JP $200
+    let mut m = machine_with(&[0xC0FF, 0x7101, 0x1200]);
+    m.set_seed(7);
+    m.step().unwrap();
+    let mut copy = m.fork();
+    for _ in 0..3 {
+        copy.step().unwrap();
+    }
+    assert_eq!((m.cpu.pc, m.cpu.r[1]), (0x202, 0));
+    assert_eq!((copy.cpu.pc, copy.cpu.r[1]), (0x202, 1));
+    m.step().unwrap();
+    m.step().unwrap();
+    m.step().unwrap();
+    // RNG state is cloned, both draw the same second number
+    assert_eq!(m.cpu.r[0], copy.cpu.r[0]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_i_mask() {
+    // LOAD r0, $FF, ADDI r0, JP $202
+    for &(mask, flag) in &[(0xfff, true), (0xffff, false)] {
+        let mut m = machine_with(&[0x60FF, 0xF01E, 0x1202]);
+        m.quirks_mut().add_i_sets_vf = true;
+        m.set_i_mask(mask);
+        m.step().unwrap();
+        let mut flags = Vec::new();
+        for _ in 0..40 {
+            m.step().unwrap();
+            assert!(m.cpu.i <= mask);
+            flags.push(m.cpu.r[0xf] == 1);
+            m.step().unwrap();
+        }
+        // 17th add crosses $FFF
+        assert_eq!(flags[16], flag);
+        assert_eq!(m.cpu.i, (40 * 0xff) & mask);
+        // READ from wrapped I must not panic
+        m.cpu.r[0] = 0;
+        m.memory.ram[0x202] = 0xF0;
+        m.memory.ram[0x203] = 0x65;
+        m.step().unwrap();
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_stats() {
+    // SKE r0, $00, (skipped), DRAW r0, r0, $1, SKNE r0, $00, DRAW r0, r0, $1
+    let mut m = machine_with(&[0x3000, 0x0000, 0xD001, 0x4000, 0xD001]);
+    m.quirks_mut().display_wait = false;
+    for _ in 0..4 {
+        m.step().unwrap();
+    }
+    assert_eq!(m.stats(), Stats { cycles: 4, draws: 2, skips_taken: 1 });
+    m.reset();
+    assert_eq!(m.stats(), Stats::default());
+}
+
+#[cfg(test)]
+#[test]
+fn test_sprite_pixel_order() {
+    let mut m = Machine::new();
+    m.memory.ram[0x300..0x302].copy_from_slice(&[0x81, 0x40]);
+    m.cpu.i = 0x300;
+    let (rows, width) = m.sprite_rows(2);
+    assert_eq!(m.sprite_pixels(2, 1, (&rows, width)), [64 + 2, 64 + 9, 128 + 3]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_memory_map() {
+    let m = machine_with(&[0x00E0, 0x1202]);
+    let map = m.memory_map();
+    let bounds: Vec<(usize, usize, RegionKind)> = map.iter().map(|r| (r.start, r.end, r.kind)).collect();
+    assert_eq!(bounds, [
+        (0x000, 0x050, RegionKind::Font),
+        (0x050, 0x200, RegionKind::Reserved),
+        (0x200, 0x204, RegionKind::Program),
+        (0x204, 0x1000, RegionKind::Free),
+    ]);
+    assert_eq!(machine_with(&[]).memory_map().len(), 3);
+}
+
+#[cfg(test)]
+#[test]
+fn test_draw_origin_wraps() {
+    // LOAD r0, 70, LOAD r1, 33, DRAW r0, r1, $1 with font row $F0
+    for &clip in [false, true].iter() {
+        let mut m = machine_with(&[0x6046, 0x6121, 0xD011]);
+        m.quirks_mut().clip_sprites = clip;
+        m.step().unwrap();
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(m.memory.fb[64..74], [0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_run_frame() {
+    // LOAD r0, $05, LOADD r0, ADD r1, $01, JP $204
+    let mut m = machine_with(&[0x6005, 0xF015, 0x7101, 0x1204]);
+    assert_eq!(m.run_frame(600), Ok(10));
+    assert_eq!(m.cpu.r[1], 4);
+    assert_eq!(m.cpu.dt, 4);
+    let mut m = machine_with(&[0xFFFF]);
+    assert!(m.run_frame(600).is_err());
+    // JP $200 idles after first jump
+    let mut m = machine_with(&[0x1200]);
+    assert_eq!(m.run_frame(600), Ok(1));
+    assert_eq!(m.stats().cycles, 1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_in_spin_loop() {
+    // LOAD r0, $FF, LOADD r0, MOVED r1, SKE r1, $00, JP $204
+    let mut m = machine_with(&[0x60FF, 0xF015, 0xF107, 0x3100, 0x1204]);
+    m.set_spin_window(6);
+    for _ in 0..6 {
+        m.step().unwrap();
+    }
+    assert!(!m.in_spin_loop());
+    for _ in 0..3 {
+        m.step().unwrap();
+    }
+    assert!(m.in_spin_loop());
+    m.reset_cpu_only();
+    assert!(!m.in_spin_loop());
+}
+
+#[cfg(test)]
+#[test]
+fn test_bcd() {
+    for &(value, digits) in [(0, [0, 0, 0]), (9, [0, 0, 9]), (10, [0, 1, 0]), (99, [0, 9, 9]),
+                             (100, [1, 0, 0]), (255, [2, 5, 5])].iter() {
+        // LOAD r0, value, LOADI $300, BCD r0
+        let mut m = machine_with(&[0x6000 | value, 0xA300, 0xF033]);
+        m.step().unwrap();
+        m.step().unwrap();
+        m.step().unwrap();
+        assert_eq!(m.memory.ram[0x300..0x303], digits);
+    }
+    // digits wrap to start of RAM like STOR
+    let mut m = machine_with(&[0x607B, 0xAFFE, 0xF033]);
+    m.step().unwrap();
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.memory.ram[0xFFE..], [1, 2]);
+    assert_eq!(m.memory.ram[0], 3);
+}
+
+#[cfg(all(test, feature = "std"))]
+#[test]
+fn test_renderer() {
+    use crate::render::FrameCollector;
+    // DRAW r0, r0, $1, ADD r0, $01, CLS
+    let mut m = machine_with(&[0xD001, 0x7001, 0x00E0]);
+    let collector = FrameCollector::new();
+    m.set_renderer(collector.clone());
+    m.step().unwrap();
+    m.step().unwrap();
+    m.step().unwrap();
+    let frames = collector.frames();
+    assert_eq!(frames.len(), 2);
+    assert_eq!(frames[0].len(), 64 * 32);
+    assert_eq!(frames[0][..5], [0xFF, 0xFF, 0xFF, 0xFF, 0]);
+    assert!(frames[1].iter().all(|&p| p == 0));
+}
+
+#[cfg(test)]
+#[test]
+fn test_sound_callback() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    let (on, off) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+    let (on_count, off_count) = (on.clone(), off.clone());
+    // LOAD r0, $03, LOADS r0
+    let mut m = machine_with(&[0x6003, 0xF018]);
+    m.set_sound_callback(Box::new(move |active| {
+        let count = if active { &on_count } else { &off_count };
+        count.fetch_add(1, Ordering::SeqCst);
+    }));
+    m.tick();
+    m.step().unwrap();
+    m.step().unwrap();
+    for _ in 0..5 {
+        m.tick();
+    }
+    assert_eq!(on.load(Ordering::SeqCst), 1);
+    assert_eq!(off.load(Ordering::SeqCst), 1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_framebuffer_planes() {
+    let mut m = machine_with(&[0xD001]);
+    m.step().unwrap();
+    let (first, second) = m.framebuffer_planes();
+    assert_eq!((first.len(), second.len()), (64 * 32, 64 * 32));
+    assert_eq!(first[0], 0xFF);
+    assert!(second.iter().all(|&p| p == 0));
+}
+
+#[cfg(test)]
+#[test]
+fn test_assemble_and_load() {
+    let mut m = Machine::new();
+    assert_eq!(m.assemble_and_load("LOAD r1, $05\nloop: ADD r1, $01\nJP loop"), Ok(6));
+    for _ in 0..5 {
+        m.step().unwrap();
+    }
+    assert_eq!(m.cpu.r[1], 7);
+    assert_eq!(m.assemble_and_load("LOAD r1\n").unwrap_err().line, 1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_opcode_fetch() {
+    let mut m = Machine::new();
+    m.memory.ram[0xFFE..].copy_from_slice(&[0x12, 0x34]);
+    assert_eq!(m.memory.opcode(0xFFE), Some((0x12, 0x34)));
+    assert_eq!(m.memory.opcode(0xFFF), None);
+    assert_eq!(m.memory.opcode(usize::MAX), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_take_resolution_changed() {
+    let mut m = Machine::new();
+    // HIRES, HIRES, LORES
+    m.load_bytes(&[0x00, 0xFF, 0x00, 0xFF, 0x00, 0xFE]);
+    assert_eq!(m.take_resolution_changed(), None);
+    m.step().unwrap();
+    assert_eq!(m.take_resolution_changed(), Some((128, 64)));
+    assert_eq!(m.take_resolution_changed(), None);
+    m.step().unwrap();
+    assert_eq!(m.take_resolution_changed(), None);
+    m.step().unwrap();
+    assert_eq!(m.take_resolution_changed(), Some((64, 32)));
+    assert_eq!(m.take_resolution_changed(), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_auto_configure() {
+    let mut m = machine_with(&[0x00E0, 0x1202]);
+    assert_eq!(m.auto_configure(), Target::Chip8);
+    assert_eq!(*m.quirks(), Quirks::for_target(Target::Chip8));
+    let mut m = machine_with(&[0x00FF, 0x1202]);
+    assert_eq!(m.auto_configure(), Target::SuperChip);
+    assert_eq!(*m.quirks(), Quirks::for_target(Target::SuperChip));
+    let mut m = machine_with(&[0x00FF, 0xF002, 0x1204]);
+    assert_eq!(m.auto_configure(), Target::XoChip);
+    assert_eq!(*m.quirks(), Quirks::for_target(Target::XoChip));
+}
+
+#[cfg(test)]
+#[test]
+fn test_run_until_draw() {
+    // LOAD r0, $01, ADD r0, $01, LOADI $000, DRAW r0, r0, $5, DRAW r0, r0, $5, JP $20A
+    let mut m = machine_with(&[0x6001, 0x7001, 0xA000, 0xD005, 0xD005, 0x120A]);
+    m.quirks_mut().display_wait = true;
+    assert_eq!(m.run_until_draw(2), Ok(RunStatus::CycleLimit));
+    assert_eq!(m.run_until_draw(100), Ok(RunStatus::Drew(2)));
+    assert_eq!(m.cpu.pc, 0x208);
+    // second DRAW waits for vblank first
+    assert_eq!(m.run_until_draw(100), Ok(RunStatus::CycleLimit));
+    m.reset();
+    m.goto_entry();
+    m.quirks_mut().display_wait = false;
+    assert_eq!(m.run_until_draw(100), Ok(RunStatus::Drew(4)));
+    assert_eq!(m.run_until_draw(100), Ok(RunStatus::Drew(1)));
+    assert_eq!(m.run_until_draw(100), Err(StepError::Stopped(StopReason::Halted)));
+}
+
+#[cfg(test)]
+#[test]
+fn test_step_n() {
+    let mut m = machine_with(&[0x6001, 0x7001, 0x7001]);
+    let (outcomes, err) = m.step_n(2);
+    assert_eq!(outcomes.len(), 2);
+    assert_eq!(outcomes[0].op, ISA::LOAD(0, 1));
+    assert_eq!(outcomes[1].new_pc, 0x204);
+    assert_eq!(err, None);
+    let (outcomes, err) = m.step_n(10);
+    assert_eq!(outcomes.len(), 1);
+    assert_eq!(err, Some(StepError::Stopped(StopReason::RanIntoEmptyMemory)));
+    assert_eq!(m.cpu.r[0], 3);
+}
+
+#[cfg(test)]
+#[test]
+fn test_decode_cache() {
+    // STOR r1, JUMP $204, CLS
+    let mut m = machine_with(&[0xF155, 0x1204, 0x00E0]);
+    m.set_decode_cache(true);
+    m.cpu.pc = 0x204;
+    assert_eq!(m.fetch(), Some((0x00E0, ISA::CLS)));
+    assert_eq!(m.decode_cache.as_ref().unwrap()[0x204], Some((0x00E0, ISA::CLS)));
+    // STOR overwrites the cached CLS with LOAD r0, $05
+    m.cpu.pc = 0x200;
+    m.cpu.i = 0x204;
+    m.cpu.r[0] = 0x60;
+    m.cpu.r[1] = 0x05;
+    m.step().unwrap();
+    assert_eq!(m.decode_cache.as_ref().unwrap()[0x204], None);
+    m.step().unwrap();
+    assert_eq!(m.step().unwrap().op, ISA::LOAD(0, 0x05));
+    assert_eq!(m.cpu.r[0], 0x05);
+}
+
+#[cfg(test)]
+#[test]
+fn test_draw_sprite() {
+    let mut m = Machine::new();
+    m.quirks_mut().clip_sprites = false;
+    assert!(!m.draw_sprite(62, 0, &[0xC0, 0x81]));
+    assert_eq!((m.memory.fb[62], m.memory.fb[63], m.memory.fb[64 + 62]), (0xff, 0xff, 0xff));
+    // body wraps to left edge without clip_sprites
+    assert_eq!(m.memory.fb[64 + 5], 0xff);
+    assert!(m.draw_sprite(62, 0, &[0xC0, 0x81]));
+    assert!(m.memory.fb.iter().all(|&p| p == 0));
+}
+
+#[cfg(test)]
+#[test]
+fn test_draw_sprite_detailed() {
+    let mut m = Machine::new();
+    m.draw_sprite(4, 1, &[0x80]);
+    assert_eq!(m.draw_sprite_detailed(0, 0, &[0xF0, 0x0F]), [false, true]);
+    assert_eq!(m.memory.fb[64 + 4], 0x00);
+    assert_eq!(m.draw_sprite_detailed(0, 0, &[0xF0, 0x0F]), [true, true]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_step_with_keys() {
+    // LOAD r0, $0A, SKP r0, JP $202, JP $206
+    let mut m = machine_with(&[0x600A, 0xE09E, 0x1202, 0x1206]);
+    m.step_with_keys(0x0400).unwrap();
+    assert_eq!(m.keys_pressed(), [0xA]);
+    m.step_with_keys(0x0001).unwrap();
+    assert_eq!(m.cpu.pc, 0x204);
+    m.step_with_keys(0x0400).unwrap();
+    m.step_with_keys(0x0400).unwrap();
+    assert_eq!(m.cpu.pc, 0x206);
+}
+
+#[cfg(test)]
+#[test]
+fn test_queue_key_event() {
+    // SKP r0, JP $200, JP $204
+    let mut m = machine_with(&[0xE09E, 0x1200, 0x1204]);
+    assert!(!m.queue_key_event(10, 0x10, true));
+    assert!(m.queue_key_event(10, 0x0, true));
+    for _ in 0..10 {
+        m.step().unwrap();
+        assert!(!m.keys[0x0]);
+    }
+    assert_eq!(m.cpu.pc, 0x200);
+    // dry run leaves the event queued
+    m.execute(true).unwrap();
+    assert!(!m.keys[0x0]);
+    m.cpu.pc = 0x200;
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x204);
+}
+
+#[cfg(test)]
+#[test]
+fn test_set_key() {
+    let mut m = Machine::new();
+    assert_eq!(m.set_key(0xA, true), Some(false));
+    assert_eq!(m.set_key(0xA, true), Some(true));
+    assert_eq!(m.set_key(0xA, false), Some(true));
+    assert_eq!(m.set_key(16, true), None);
+    assert!(m.keys_pressed().is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn test_snapshot_reset_to() {
+    let mut m = machine_with(&[0x6005, 0x7001, 0x2300]);
+    m.set_key(3, true);
+    let snap = m.snapshot();
+    m.step().unwrap();
+    m.step().unwrap();
+    m.step().unwrap();
+    m.set_key(3, false);
+    assert_ne!(m.snapshot(), snap);
+    m.reset_to(&snap).unwrap();
+    assert_eq!(m.snapshot(), snap);
+    let mut setup = m.snapshot();
+    setup.registers[0] = 0x40;
+    setup.pc = 0x202;
+    m.reset_to(&setup).unwrap();
+    m.step().unwrap();
+    assert_eq!(m.cpu.r[0], 0x41);
+    assert_eq!(m.keys_pressed(), [3]);
+    // RAM must stay a power of two
+    let mut bad = m.snapshot();
+    bad.ram.truncate(3000);
+    assert_eq!(m.reset_to(&bad), Err(StateError::BadRamSize(3000)));
+    let mut bad = m.snapshot();
+    let over = bad.stack.len() + 1;
+    bad.sp = over;
+    assert_eq!(m.reset_to(&bad), Err(StateError::BadStackPointer(over)));
+    bad.stack.clear();
+    assert_eq!(m.reset_to(&bad), Err(StateError::EmptyStack));
+    assert_eq!(m.cpu.pc, 0x204);
+}
+
+#[cfg(test)]
+#[test]
+fn test_snapshot_bytes_restore() {
+    // LOAD r0, $05, LOADI $000, DRAW r0, r0, $5, CALL $20A, JP $208, ADD r0, $08, DRAW r0, r0, $5
+    let mut m = machine_with(&[0x6005, 0xA000, 0xD005, 0x220A, 0x1208, 0x7008, 0xD005]);
+    m.quirks_mut().display_wait = false;
+    m.step_n(3);
+    m.set_key(7, true);
+    let state = m.snapshot_bytes();
+    let (cpu, fb) = (m.cpu.clone(), m.memory.fb);
+    m.step_n(3);
+    m.set_key(7, false);
+    assert_ne!(m.cpu.pc, cpu.pc);
+    assert_ne!(m.memory.fb[..], fb[..]);
+    m.restore(&state).unwrap();
+    assert_eq!((m.cpu.r, m.cpu.i, m.cpu.pc, m.cpu.sp), (cpu.r, cpu.i, cpu.pc, cpu.sp));
+    assert_eq!(m.memory.fb[..], fb[..]);
+    assert_eq!(m.keys_pressed(), [7]);
+    assert_eq!(m.snapshot_bytes(), state);
+    // stepping again repeats the same path
+    m.step_n(3);
+    assert_eq!((m.cpu.r[0], m.cpu.pc), (0x0D, 0x20E));
+    // malformed states leave machine alone
+    let before = m.snapshot_bytes();
+    assert_eq!(m.restore(&state[..10]), Err(StateError::BadMagic));
+    assert_eq!(m.restore(&state[..state.len() - 1]), Err(StateError::WrongLength { expected: state.len(), actual: state.len() - 1 }));
+    let mut bad = state.clone();
+    bad[0] = b'X';
+    assert_eq!(m.restore(&bad), Err(StateError::BadMagic));
+    let mut bad = state.clone();
+    bad[4] = 9;
+    assert_eq!(m.restore(&bad), Err(StateError::UnsupportedVersion(9)));
+    let mut bad = state.clone();
+    bad[12] = 0xFF;
+    assert_eq!(m.restore(&bad), Err(StateError::BadStackPointer(0xFF)));
+    // header claiming no stack, trimmed to match
+    let slots = m.memory.stack.len();
+    let mut bad = state.clone();
+    bad[10..14].copy_from_slice(&[0, 0, 0, 0]);
+    let stack_at = STATE_HEADER_LEN + 32 + DEFAULT_RAM_SIZE;
+    bad.drain(stack_at..stack_at + 2 * slots);
+    assert_eq!(m.restore(&bad), Err(StateError::EmptyStack));
+    assert_eq!(m.snapshot_bytes(), before);
+    m.reset();
+}
+
+#[cfg(test)]
+#[test]
+fn test_vf_events() {
+    // LOAD r0, $F0, LOAD r1, $20, ADDR r0, r1, ADD r0, $01, ADDR r0, r1, SHL r0, r0
+    let mut m = machine_with(&[0x60F0, 0x6120, 0x8014, 0x7001, 0x8014, 0x800E]);
+    m.step_n(3);
+    assert!(m.vf_events().is_empty());
+    m.reset();
+    m.set_trace_vf(true);
+    m.step_n(6);
+    assert_eq!(m.vf_events(), &[
+        (0x204, ISA::ADDR(0, 1), 1),
+        (0x208, ISA::ADDR(0, 1), 0),
+        (0x20A, ISA::SHL(0, 0), 0),
+    ]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_cpu_mut() {
+    // SUB r2, r3
+    let mut m = machine_with(&[0x8235]);
+    let cpu = m.cpu_mut();
+    cpu.r[2] = 0x10;
+    cpu.r[3] = 0x20;
+    cpu.r[0xf] = 0xAA;
+    m.step().unwrap();
+    assert_eq!(m.cpu().r[2], 0xF0);
+    assert_eq!(m.cpu().r[0xf], 0);
+    assert_eq!(m.cpu().pc, 0x202);
+}
+
+#[cfg(test)]
+#[test]
+fn test_stack_underflow() {
+    // RET pops entry point then has nothing left
+    let mut m = machine_with(&[0x00EE]);
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x200);
+    assert_eq!(m.step(), Err(StepError::StackUnderflow { addr: 0x200 }));
+    assert_eq!(m.cpu.sp, m.memory.stack.len());
+}
+
+#[cfg(test)]
+#[test]
+fn test_entry_pc() {
+    let mut m = Machine::new();
+    assert_eq!(m.entry_pc(), 0x200);
+    assert_eq!(m.set_entry_pc(0x601), Err(EntryError { addr: 0x601 }));
+    assert_eq!(m.set_entry_pc(0x1000), Err(EntryError { addr: 0x1000 }));
+    assert_eq!(m.entry_pc(), 0x200);
+    m.set_entry_pc(0x600).unwrap();
+    // RET at the top returns to entry point
+    m.load_bytes(&[0x00, 0xEE]);
+    assert_eq!(m.cpu.pc, 0x600);
+    assert_eq!(m.memory.ram[0x600..0x602], [0x00, 0xEE]);
+    assert_eq!(m.rom_info().first_op, Some(ISA::RET));
+    m.step().unwrap();
+    assert_eq!(m.cpu.pc, 0x600);
+    m.reset();
+    assert_eq!(m.cpu.pc, 0x600);
+    // program loaded at $200 runs past RAM seen from new entry point
+    m.set_entry_pc(0x200).unwrap();
+    m.load_bytes(&[0; 0xD00]);
+    m.set_entry_pc(0x800).unwrap();
+    assert_eq!(m.rom_info().bytes, 0x800);
+    assert_eq!(m.export_rom().len(), 0x800);
+    assert_eq!(m.auto_configure(), Target::Chip8);
+}
+
+#[cfg(all(test, debug_assertions))]
+#[test]
+#[should_panic(expected = "ADDR r1, r2 left VF = $02")]
+fn test_check_vf_fires() {
+    // stands in for an arm storing carry as a raw sum bit
+    check_vf(&ISA::ADDR(1, 2), 0x02, &Quirks::default());
+}
+
+#[cfg(test)]
+#[test]
+fn test_run_cycle_limit() {
+    // LOAD r0, $01, JP $202
+    let mut m = machine_with(&[0x6001, 0x1202]);
+    assert_eq!(m.run(100), Ok(2));
+    assert_eq!(m.run(100), Ok(0));
+    // ADD r0, $01, JP $200
+    let mut m = machine_with(&[0x7001, 0x1200]);
+    assert_eq!(m.run(100), Err(StepError::Stopped(StopReason::CycleLimitReached)));
+    assert_eq!(m.stats().cycles, 100);
+}
+
+#[cfg(test)]
+#[test]
+fn test_halted() {
+    // LOAD r0, $0A, JP $202
+    let mut m = machine_with(&[0x600A, 0x1202]);
+    m.step().unwrap();
+    assert!(!m.is_halted());
+    m.step().unwrap();
+    assert!(m.is_halted());
+    let snap = m.snapshot();
+    assert_eq!(m.step(), Err(StepError::Stopped(StopReason::Halted)));
+    assert_eq!(m.step(), Err(StepError::Stopped(StopReason::Halted)));
+    assert_eq!(m.snapshot(), snap);
+    assert_eq!(m.stats().cycles, 2);
+    m.resume();
+    m.cpu.pc = 0x204;
+    assert_eq!(m.step(), Err(StepError::Stopped(StopReason::RanIntoEmptyMemory)));
+    assert_eq!(m.step(), Err(StepError::Stopped(StopReason::Halted)));
+    m.reset();
+    assert!(!m.is_halted());
+}
+
+#[cfg(test)]
+#[test]
+fn test_idle_outcome() {
+    // CLS, JP $202 as at the end of the IBM logo
+    let mut m = machine_with(&[0x00E0, 0x1202]);
+    m.quirks_mut().display_wait = false;
+    m.step().unwrap();
+    assert_eq!(m.idle_outcome(), None);
+    m.step().unwrap();
+    let outcome = m.idle_outcome().unwrap();
+    assert_eq!((outcome.op, outcome.new_pc, outcome.drew), (ISA::JP(0x202), 0x202, false));
+    // frames keep ticking timers
+    m.cpu.dt = 5;
+    assert_eq!(m.run_frame(600), Ok(0));
+    assert_eq!(m.cpu.dt, 4);
+    m.resume();
+    m.cpu.pc = 0x204;
+    assert!(m.step().is_err());
+    assert_eq!(m.idle_outcome(), None);
+}
+
+#[cfg(test)]
+#[test]
+fn test_schip_scroll_exit() {
+    // SCD $2, SCR, SCL, SCL, EXIT
+    let mut m = machine_with(&[0x00C2, 0x00FB, 0x00FC, 0x00FC, 0x00FD]);
+    assert_eq!(m.step(), Err(StepError::InvalidOpcode { opcode: 0x00C2, addr: 0x200 }));
+    m.quirks_mut().schip_opcodes = true;
+    m.draw_sprite(4, 0, &[0x80]);
+    m.step().unwrap();
+    assert_eq!((m.memory.fb[4], m.memory.fb[64 * 2 + 4]), (0x00, 0xff));
+    m.step().unwrap();
+    assert_eq!(m.memory.fb[64 * 2 + 8], 0xff);
+    m.step().unwrap();
+    m.step().unwrap();
+    assert_eq!(m.memory.fb[64 * 2], 0xff);
+    assert_eq!(m.memory.fb.iter().filter(|&&pixel| pixel != 0).count(), 1);
+    m.execute(true).unwrap();
+    assert!(!m.is_halted());
+    m.step().unwrap();
+    assert!(m.is_halted());
+    assert_eq!(m.cpu.pc, 0x208);
+    assert_eq!(m.step(), Err(StepError::Stopped(StopReason::Halted)));
+}
+
+#[cfg(test)]
+#[test]
+fn test_memory_clear() {
+    let mut m = machine_with(&[0xA000, 0xD005]);
+    m.memory.ram[0xFFF] = 0xAB;
+    m.step().unwrap();
+    m.step().unwrap();
+    m.memory.clear();
+    assert_eq!(m.memory.ram[..80], m.memory.rom[..]);
+    assert_eq!((m.memory.ram[0x200], m.memory.ram[0xFFF]), (0x00, 0x00));
+    assert!(m.memory.fb.iter().all(|&pixel| pixel == 0));
+    m.load_bytes(&[0x12, 0x00]);
+    m.cold_reset();
+    assert_eq!((m.export_rom(), m.cpu.pc), (Vec::new(), 0x200));
+}
+
+#[cfg(test)]
+#[test]
+fn test_reset_with_target() {
+    let mut m = machine_with(&[0x6A42, 0xA300]);
+    m.set_target(Target::Chip8);
+    m.step().unwrap();
+    m.step().unwrap();
+    m.set_resolution(true);
+    m.reset_with_target(Target::SuperChip);
+    assert_eq!(m.resolution(), (64, 32));
+    assert!(m.quirks().jump_uses_vx);
+    assert_eq!((m.cpu.r[0xA], m.cpu.i, m.cpu.pc), (0, 0, 0x200));
+    assert_eq!(m.export_rom(), Vec::new());
+}
+
+#[cfg(test)]
+#[test]
+fn test_set_register_bank() {
+    // ADDR r1, r2, STOR r2
+    let mut m = machine_with(&[0x8124, 0xF255]);
+    let mut regs = [0; 16];
+    regs[0x1] = 0xF0;
+    regs[0x2] = 0x20;
+    m.set_register_bank(regs, 0x1300);
+    assert_eq!(m.cpu.i, 0x300);
+    m.step().unwrap();
+    assert_eq!((m.cpu.r[0x1], m.cpu.r[0xF]), (0x10, 1));
+    m.step().unwrap();
+    assert_eq!(m.mem().ram[0x300..0x303], [0x00, 0x10, 0x20]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_collision_history() {
+    // LOADI $000, DRAW r0, r0, $5 four times
+    let mut m = machine_with(&[0xA000, 0xD005, 0xD005, 0xD005, 0xD005]);
+    m.quirks_mut().display_wait = false;
+    m.step().unwrap();
+    m.step().unwrap();
+    m.tick();
+    assert!(m.collision_history().is_empty());
+    m.set_collision_history(2);
+    // glyph is erased with collision, drawn and erased again within a frame
+    for _ in 0..3 {
+        m.step().unwrap();
+    }
+    m.tick();
+    assert_eq!(m.collision_history(), [2]);
+    m.tick();
+    m.tick();
+    assert_eq!(m.collision_history(), [0, 0]);
+    m.reset();
+    assert!(m.collision_history().is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn test_pc_history() {
+    // LOAD r0, $01, ADD r0, $01, SKE r0, $00, JP $202
+    let mut m = machine_with(&[0x6001, 0x7001, 0x3000, 0x1202]);
+    m.step().unwrap();
+    assert!(m.pc_history().is_empty());
+    m.set_pc_history(4);
+    m.step().unwrap();
+    assert_eq!(m.pc_history(), [0x202]);
+    for _ in 0..10 {
+        m.step().unwrap();
+    }
+    assert_eq!(m.pc_history(), [0x204, 0x206, 0x202, 0x204]);
+    m.reset();
+    assert!(m.pc_history().is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn test_export_trace_csv() {
+    // LOAD r0, $01, ADD r0, $01, JP $202
+    let mut m = machine_with(&[0x6001, 0x7001, 0x1202]);
+    m.step().unwrap();
+    m.set_trace(true);
+    for _ in 0..4 {
+        m.step().unwrap();
+    }
+    let csv = m.export_trace_csv();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 1 + 4);
+    assert_eq!(lines[0], "cycle,pc,opcode,mnemonic");
+    assert_eq!(lines[1], "1,0x202,0x7001,\"ADD r0, $001\"");
+    assert_eq!(lines[4], "4,0x204,0x1202,\"JP $202\"");
+    m.reset();
+    assert_eq!(m.export_trace_csv().lines().count(), 1);
+}
+
+#[cfg(test)]
+#[test]
+fn test_reset_preserving_seed() {
+    // RAND r0, $FF, RAND r1, $FF, JP $204
+    let run = |m: &mut Machine| {
+        for _ in 0..3 {
+            m.step().unwrap();
+        }
+        (m.cpu.r[0], m.cpu.r[1])
+    };
+    for &seeded in &[true, false] {
+        let mut m = machine_with(&[0xC0FF, 0xC1FF, 0x1204]);
+        if seeded {
+            m.set_seed(99);
+        }
+        let first = run(&mut m);
+        m.reset_preserving_seed();
+        assert_eq!(run(&mut m), first);
+        if seeded {
+            // plain reset keeps drawing from the advanced stream
+            m.reset();
+            assert_ne!(run(&mut m), first);
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_rand_mask() {
+    for &(mask, expected) in &[(0x0F, 16), (0x00, 1), (0xFF, 256)] {
+        // RAND r0, mask, JP $200
+        let mut m = machine_with(&[0xC000 | mask, 0x1200]);
+        m.set_seed(42);
+        let mut seen = [false; 256];
+        for _ in 0..4096 {
+            m.step().unwrap();
+            m.step().unwrap();
+            assert_eq!(m.cpu.r[0] & !(mask as u8), 0);
+            seen[m.cpu.r[0] as usize] = true;
+        }
+        assert_eq!(seen.iter().filter(|&&hit| hit).count(), expected);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_set_rng() {
+    // RAND r0, $0F, JP $200
+    let mut m = machine_with(&[0xC00F, 0x1200]);
+    m.set_rng(Box::new(CyclicRng::new(vec![0x12, 0xAB, 0x07])));
+    let mut values = Vec::new();
+    for _ in 0..4 {
+        m.step().unwrap();
+        m.step().unwrap();
+        values.push(m.cpu.r[0]);
+    }
+    assert_eq!(values, [0x02, 0x0B, 0x07, 0x02]);
+    m.reset_preserving_seed();
+    m.goto_entry();
+    m.step().unwrap();
+    assert_eq!(m.cpu.r[0], 0x02);
+}
+
+#[cfg(test)]
+#[test]
+fn test_randomize_ram() {
+    let load = |seed| {
+        let mut m = MachineBuilder::new().randomize_ram(true).build();
+        m.set_seed(seed);
+        m.load_bytes(&[0x60, 0x01]);
+        m
+    };
+    let (a, b, c) = (load(7), load(7), load(8));
+    assert_eq!(a.memory.ram[..], b.memory.ram[..]);
+    assert_ne!(a.memory.ram[0x202..], c.memory.ram[0x202..]);
+    assert_eq!(a.memory.ram[0x200..0x202], [0x60, 0x01]);
+    assert!(a.memory.ram[0x202..].iter().any(|&byte| byte != 0));
+    let mut expected = [0; 4];
+    RngCore::fill_bytes(&mut StdRng::seed_from_u64(7), &mut expected);
+    assert_eq!(a.memory.ram[0x202..0x206], expected);
+    // injected source is drained by the fill
+    let mut m = MachineBuilder::new().randomize_ram(true).build();
+    m.set_rng(Box::new(CyclicRng::new(vec![1, 2, 3])));
+    m.load_bytes(&[0xC0, 0xFF]);
+    assert_eq!(m.memory.ram[0x202..0x205], [1, 2, 3]);
+    m.step().unwrap();
+    assert_eq!(m.cpu.r[0], [1, 2, 3][(m.memory.ram.len() - 0x202) % 3]);
+    // default zero fills, also clearing previous program
+    let mut m = machine_with(&[0x6001, 0x7001]);
+    m.load_bytes(&[0x60, 0x01]);
+    assert!(m.memory.ram[0x202..].iter().all(|&byte| byte == 0));
+}
+
+#[cfg(test)]
+#[test]
+fn test_key_hold_ticks() {
+    let mut m = Machine::new();
+    m.set_key(5, true);
+    assert_eq!(m.key_hold_ticks(5), 0);
+    for _ in 0..3 {
+        m.tick();
+    }
+    assert_eq!(m.key_hold_ticks(5), 3);
+    assert_eq!(m.key_hold_ticks(6), 0);
+    assert_eq!(m.key_hold_ticks(16), 0);
+    m.set_key(5, false);
+    assert_eq!(m.key_hold_ticks(5), 0);
+    m.set_key(5, true);
+    m.tick();
+    m.keys[5] = false;
+    m.tick();
+    assert_eq!(m.key_hold_ticks(5), 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_disassemble_around() {
+    let mut m = machine_with(&[0x6001, 0x7001, 0x00E0, 0x1206]);
+    m.cpu.pc = 0x204;
+    assert_eq!(m.disassemble_around(1), vec![
+        (0x202, 0x7001, ISA::ADD(0, 1), false),
+        (0x204, 0x00E0, ISA::CLS, true),
+        (0x206, 0x1206, ISA::JP(0x206), false),
+    ]);
+    // clamped to RAM at both ends
+    m.cpu.pc = 0x002;
+    assert_eq!(m.disassemble_around(4).first().map(|line| line.0), Some(0x000));
+    m.cpu.pc = 0xFFC;
+    assert_eq!(m.disassemble_around(4).last().map(|line| line.0), Some(0xFFE));
+}
+
+#[cfg(test)]
+#[test]
+fn test_elapsed_ms() {
+    // ADD r0, $01, JP $200
+    let mut m = machine_with(&[0x7001, 0x1200]);
+    m.step_n(1400);
+    assert_eq!(m.stats().cycles, 1400);
+    assert_eq!(m.elapsed_ms(700), 2000);
+    assert_eq!(m.elapsed_ms(500), 2800);
+    assert_eq!(m.elapsed_ms(0), 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_draw_large_sprite_wraps_ram() {
+    // HIRES, DRAW r0, r0, $0
+    let mut m = machine_with(&[0x00FF, 0xD000]);
+    m.cpu.i = 0xFF0;
+    m.memory.ram[0xFF0..].copy_from_slice(&[0x80, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    // rows 8 to 15 come from the font at $000
+    let font = m.memory.ram[..16].to_vec();
+    m.step().unwrap();
+    m.step().unwrap();
+    let fb = &m.memory.fb;
+    assert_eq!((fb[0], fb[1], fb[14], fb[15]), (0xff, 0, 0, 0xff));
+    for j in 8..16 {
+        for i in 0..16 {
+            let bit = font[2 * (j - 8) + i / 8] & (0x80 >> (i % 8)) != 0;
+            assert_eq!(fb[128 * j + i] != 0, bit);
+        }
+    }
+    assert_eq!(m.cpu.r[0xf], 0);
+}
+
+#[cfg(test)]
+#[test]
+fn test_draw_zero_rows_lores() {
+    // DRAW r0, r0, $0
+    let mut m = machine_with(&[0xD000, 0xD000]);
+    m.quirks_mut().display_wait = false;
+    m.cpu.i = 0x300;
+    m.memory.ram[0x300..0x310].copy_from_slice(&[0xff; 16]);
+    m.step().unwrap();
+    assert!(m.memory.fb.iter().all(|&pixel| pixel == 0));
+    m.quirks_mut().schip_opcodes = true;
+    m.step().unwrap();
+    for j in 0..16 {
+        assert_eq!(m.memory.fb[64 * j..64 * j + 9], [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0]);
+    }
+    assert_eq!(m.memory.fb.iter().filter(|&&pixel| pixel != 0).count(), 8 * 16);
+}
+
+#[cfg(test)]
+#[test]
+fn test_uninitialized_reads() {
+    // LOAD r2, $01, ADD r2, r3, ADDR r2, r3, LOAD r4, $00, MOVE r5, r4
+    let mut m = machine_with(&[0x6201, 0x8234, 0x8234, 0x6400, 0x8540]);
+    m.set_track_uninitialized(true);
+    m.step_n(5);
+    assert_eq!(m.uninitialized_reads(), &[(0x202, ISA::ADDR(2, 3), 3)]);
+    m.reset();
+    assert!(m.uninitialized_reads().is_empty());
+    m.set_track_uninitialized(false);
+    m.step_n(5);
+    assert!(m.uninitialized_reads().is_empty());
+}
+
+#[cfg(test)]
+#[test]
+fn test_timers_frozen() {
+    let mut m = Machine::new();
+    m.cpu.dt = 10;
+    m.cpu.st = 5;
+    m.set_timers_frozen(true);
+    m.tick();
+    m.tick_timers(3);
+    assert_eq!((m.cpu.dt, m.cpu.st), (10, 5));
+    m.set_timers_frozen(false);
+    m.tick();
+    m.tick_timers(3);
+    assert_eq!((m.cpu.dt, m.cpu.st), (6, 1));
+}
+
+#[cfg(test)]
+#[test]
+fn test_tick_timers() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    let edges = Arc::new(AtomicUsize::new(0));
+    let counter = edges.clone();
+    let mut m = Machine::new();
+    m.set_sound_callback(Box::new(move |_| { counter.fetch_add(1, Ordering::SeqCst); }));
+    m.cpu.dt = 3;
+    m.cpu.st = 3;
+    m.set_key(1, true);
+    m.tick_timers(5);
+    assert_eq!((m.cpu.dt, m.cpu.st), (0, 0));
+    // sound went on and off within the batch
+    assert_eq!(edges.load(Ordering::SeqCst), 2);
+    assert_eq!(m.key_hold_ticks(1), 5);
+    m.cpu.dt = 10;
+    m.tick_timers(4);
+    assert_eq!(m.cpu.dt, 6);
+}
+
+#[cfg(test)]
+#[test]
+fn test_collision_at_edge() {
+    let draw = |clip_sprites| {
+        let mut m = Machine::new();
+        m.quirks_mut().clip_sprites = clip_sprites;
+        // content on the left edge, sprite body overhangs the right edge
+        m.draw_sprite(0, 0, &[0x80]);
+        let collided = m.draw_sprite(60, 0, &[0xFF]);
+        (collided, m.memory.fb[0])
+    };
+    assert_eq!(draw(false), (true, 0));
+    assert_eq!(draw(true), (false, 0xff));
+}
+
+#[cfg(test)]
+#[test]
+fn test_trace_callback() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    let calls = Arc::new(AtomicUsize::new(0));
+    let counter = calls.clone();
+    // ADD r0, $01 three times, JP $206
+    let mut m = machine_with(&[0x7001, 0x7001, 0x7001, 0x1206]);
+    m.set_trace_callback(Box::new(move |_| { counter.fetch_add(1, Ordering::SeqCst); }));
+    assert_eq!(m.run(100), Ok(4));
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+    // plain steps are not traced
+    m.reset();
+    m.goto_entry();
+    m.step().unwrap();
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+    m.clear_trace_callback();
+    assert_eq!(m.run(100), Ok(3));
+    assert_eq!(calls.load(Ordering::SeqCst), 4);
+}
+
+#[cfg(test)]
+#[test]
+fn test_dt_zero_callback() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    let fired = Arc::new(AtomicUsize::new(0));
+    let counter = fired.clone();
+    let mut m = Machine::new();
+    m.set_dt_zero_callback(Box::new(move || { counter.fetch_add(1, Ordering::SeqCst); }));
+    m.cpu.dt = 3;
+    m.tick();
+    m.tick();
+    assert_eq!(fired.load(Ordering::SeqCst), 0);
+    for _ in 0..5 {
+        m.tick();
+    }
+    assert_eq!(fired.load(Ordering::SeqCst), 1);
+    m.cpu.dt = 2;
+    m.tick_timers(10);
+    assert_eq!(fired.load(Ordering::SeqCst), 2);
+}
+
+#[cfg(test)]
+#[test]
+fn test_error_display() {
+    let errors = vec![
+        (StepError::InvalidOpcode { opcode: 0x812F, addr: 0x204 }, "invalid opcode $812F at $204"),
+        (StepError::StackOverflow { addr: 0x206 }, "stack overflow at $206"),
+        (StepError::StackUnderflow { addr: 0x208 }, "stack underflow at $208"),
+        (StepError::WriteToProtectedMemory { addr: 0x010 }, "write to protected memory at $010"),
+        (StepError::PcOutOfRange { pc: 0x1000 }, "PC out of range at $1000"),
+        (StepError::RamResized { addr: 0x200 }, "op handler resized RAM at $200"),
+        (StepError::Stopped(StopReason::RanIntoEmptyMemory), "stopped: ran into empty memory"),
+        (StepError::Stopped(StopReason::RanPastMemory), "stopped: ran past end of RAM"),
+        (StepError::Stopped(StopReason::Halted), "stopped: machine is halted"),
+        (StepError::Stopped(StopReason::CycleLimitReached), "stopped: cycle limit reached"),
+    ];
+    for (err, text) in errors {
+        assert_eq!(err.to_string(), text);
+    }
+    let err = HexError { line: 2, token: String::from("zz") };
+    assert_eq!(err.to_string(), "invalid hex byte 'zz' at line 2");
+    assert_eq!(OverlayError { addr: 0xFFF, len: 2 }.to_string(), "overlay of 2 bytes at $FFF does not fit in RAM");
+    assert_eq!(EntryError { addr: 0x201 }.to_string(), "entry point $201 is odd or past end of RAM");
+}
+
+#[cfg(all(test, feature = "std"))]
+#[test]
+fn test_error_trait() {
+    fn step_twice(m: &mut Machine) -> core::result::Result<(), Box<dyn std::error::Error>> {
+        m.step()?;
+        m.step()?;
+        Ok(())
+    }
+    let mut m = machine_with(&[0x6001]);
+    let err = step_twice(&mut m).unwrap_err();
+    assert_eq!(err.to_string(), "stopped: ran into empty memory");
+}
+
+#[cfg(test)]
+#[test]
+fn test_dirty_rows() {
+    let mut m = Machine::new();
+    assert!(m.dirty_rows().is_empty());
+    m.draw_sprite(10, 5, &[0x81]);
+    assert_eq!(m.dirty_rows(), [5]);
+    assert!(m.dirty_rows().is_empty());
+    m.draw_sprite(60, 31, &[0xFF]);
+    assert_eq!(m.dirty_rows(), [31]);
+    m.set_resolution(true);
+    assert_eq!(m.dirty_rows().len(), 64);
+}
+
+#[cfg(all(test, feature = "std"))]
+#[test]
+fn test_load_and_run() {
+    let path = std::env::temp_dir().join(format!("chip8-smoke-{}.ch8", std::process::id()));
+    let path = path.to_str().unwrap();
+    // LOAD r0, $05, ADD r0, $01, JP $204
+    std::fs::write(path, [0x60, 0x05, 0x70, 0x01, 0x12, 0x04]).unwrap();
+    let mut m = Machine::new();
+    let summary = m.load_and_run(path, 100).unwrap();
+    assert_eq!((summary.cycles_run, summary.registers[0], summary.pc), (3, 6, 0x204));
+    assert_eq!(summary.stop, StepError::Stopped(StopReason::Halted));
+    let summary = m.load_and_run(path, 2).unwrap();
+    assert_eq!((summary.cycles_run, summary.pc), (2, 0x204));
+    assert_eq!(summary.stop, StepError::Stopped(StopReason::CycleLimitReached));
+    // ran into empty memory after program
+    std::fs::write(path, [0x60, 0x05]).unwrap();
+    let summary = m.load_and_run(path, 100).unwrap();
+    assert_eq!((summary.cycles_run, summary.stop), (1, StepError::Stopped(StopReason::RanIntoEmptyMemory)));
+    std::fs::remove_file(path).unwrap();
+    assert!(m.load_and_run(path, 100).is_err());
+}
+
+#[cfg(all(test, feature = "std"))]
+#[test]
+fn test_load_validated() {
+    let path = std::env::temp_dir().join(format!("chip8-big-{}.ch8", std::process::id()));
+    let path = path.to_str().unwrap();
+    std::fs::write(path, vec![0x12; 4000]).unwrap();
+    let mut m = Machine::new();
+    assert!(m.load_validated(path, true).is_err());
+    let info = m.load_validated(path, false).unwrap();
+    assert_eq!(info.bytes, 4096 - 0x200);
+    assert_eq!(info.warnings, [RomWarning::Truncated { dropped: 4000 - (4096 - 0x200) }]);
+    std::fs::remove_file(path).unwrap();
+    let path = std::env::temp_dir().join(format!("chip8-small-{}.bin", std::process::id()));
+    let path = path.to_str().unwrap();
+    std::fs::write(path, [0x00, 0xE0]).unwrap();
+    let info = m.load_validated(path, true).unwrap();
+    assert_eq!(info.warnings, [RomWarning::UnknownExtension]);
+    assert_eq!(info.first_op, Some(ISA::CLS));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[cfg(test)]
+#[test]
+fn test_written_addresses() {
+    // LOAD r0, $7B, LOADI $300, BCD r0, STOR r1
+    let mut m = machine_with(&[0x607B, 0xA300, 0xF033, 0xF155]);
+    assert!(m.step().unwrap().written_addresses.is_empty());
+    m.step().unwrap();
+    let outcome = m.step().unwrap();
+    assert_eq!(outcome.written_addresses, [0x300, 0x301, 0x302]);
+    assert_eq!(m.memory.ram[0x300..0x303], [1, 2, 3]);
+    assert_eq!(m.step().unwrap().written_addresses, [0x300, 0x301]);
+}
+
+#[cfg(test)]
+#[test]
+fn test_keyd_policy() {
+    let keyd = |policy, presses: &[usize]| {
+        // KEYD r0
+        let mut m = machine_with(&[0xF00A]);
+        m.set_key_policy(policy);
+        for &key in presses {
+            m.set_key(key, true);
+        }
+        m.step().unwrap();
+        m.reset_keys();
+        m.step().unwrap();
+        m.cpu.r[0]
+    };
+    assert_eq!(keyd(KeyPolicy::Lowest, &[2, 9]), 2);
+    assert_eq!(keyd(KeyPolicy::Lowest, &[9, 2]), 2);
+    assert_eq!(keyd(KeyPolicy::MostRecent, &[2, 9]), 9);
+    assert_eq!(keyd(KeyPolicy::MostRecent, &[9, 2]), 2);
 }
@@ -0,0 +1,1283 @@
+use std::os::raw::{c_void, c_int, c_long};
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use pyo3::prelude::*;
+use pyo3::types::PyDict;
+use pyo3::types::PyBytes;
+use pyo3::types::PyType;
+use pyo3::types::PySlice;
+use pyo3::wrap_pyfunction;
+use pyo3::PySequenceProtocol;
+use pyo3::PyMappingProtocol;
+use pyo3::PyIterProtocol;
+use pyo3::PyBufferProtocol;
+use pyo3::exceptions::PyValueError;
+use pyo3::exceptions::PyIndexError;
+use pyo3::exceptions::PyRuntimeError;
+use pyo3::exceptions::PyTimeoutError;
+use pyo3::ffi;
+use pyo3::AsPyPointer;
+use crate::machine::Machine;
+use crate::machine::MachineBuilder;
+use crate::machine::DEFAULT_STACK_DEPTH;
+use crate::machine::DEFAULT_RAM_SIZE;
+use crate::machine::XOCHIP_RAM_SIZE;
+use crate::machine::StepOutcome;
+use crate::machine::RunStatus;
+use crate::machine::PcPolicy;
+use crate::machine::CyclicRng;
+use crate::machine::RomInfo;
+use crate::machine::StepError;
+use crate::machine::StopReason;
+use crate::machine::MachineSnapshot;
+use crate::machine::Direction;
+use crate::quirks::Quirks;
+use crate::quirks::Target;
+use crate::quirks::screen_dimensions;
+use crate::quirks::suggest_quirks;
+use crate::isa::decode;
+use crate::isa::diff_roms;
+use crate::isa::disassemble;
+use crate::isa::DisasmLine;
+use crate::isa::decode_strict;
+use crate::cfg::build_cfg;
+use crate::headless::HeadlessRunner;
+use crate::cfg::Edge;
+
+
+// First exception raised by a hook or callback, re-raised once the
+// emulator call that ran it returns
+type HookError = Arc<Mutex<Option<PyErr>>>;
+
+/// A python Chip8 emulator implemented in Rust
+#[pyclass(name="Machine", module="chip8")]
+struct PyMachine {
+    m: Machine,
+    hook_error: HookError
+}
+
+// Call f unless an earlier hook failed, keep its error otherwise
+fn call_hook<F: FnOnce(Python) -> PyResult<PyObject>>(hook_error: &HookError, f: F) {
+    let lock = || hook_error.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    if lock().is_some() {
+        return;
+    }
+    if let Err(err) = Python::with_gil(f) {
+        lock().get_or_insert(err);
+    }
+}
+
+impl PyMachine {
+    fn from_machine(m: Machine) -> PyMachine {
+        PyMachine { m, hook_error: HookError::default() }
+    }
+
+    fn take_hook_error(&self) -> PyResult<()> {
+        match self.hook_error.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).take() {
+            Some(err) => Err(err),
+            None => Ok(())
+        }
+    }
+
+    fn get_rom(self: &PyMachine) -> &[u8] {
+        &self.m.mem().rom[..]
+    }
+
+    fn get_ram(self: &PyMachine) -> &[u8] {
+        &self.m.mem().ram[..]
+    }
+
+    fn get_fb(self: &PyMachine) -> &[u8] {
+        let (width, height) = self.m.resolution();
+        &self.m.mem().fb[..width * height]
+    }
+}
+
+#[pymethods]
+impl PyMachine {
+    #[new]
+    #[args(target = "None", stack_depth = "DEFAULT_STACK_DEPTH", quirks = "None", randomize_ram = "false")]
+    fn new(target: Option<&str>, stack_depth: usize, quirks: Option<PyRef<PyQuirks>>, randomize_ram: bool) -> PyResult<Self> {
+        let mut m = MachineBuilder::new().stack_depth(stack_depth).randomize_ram(randomize_ram).build();
+        if let Some(name) = target {
+            m.set_target(parse_target(name)?);
+        }
+        if let Some(quirks) = quirks {
+            *m.quirks_mut() = Quirks::from(&*quirks);
+        }
+        Ok(Self::from_machine(m))
+    }
+
+    #[getter]
+    fn quirks(&self) -> PyQuirks {
+        PyQuirks::from(*self.m.quirks())
+    }
+
+    fn set_quirks(&mut self, quirks: PyRef<PyQuirks>) {
+        *self.m.quirks_mut() = Quirks::from(&*quirks);
+    }
+
+    fn set_target(&mut self, target: &str) -> PyResult<()> {
+        self.m.set_target(parse_target(target)?);
+        Ok(())
+    }
+
+    // RAND draws from data cyclically instead of the seeded generator, so
+    // does the free RAM fill of later loads when randomize_ram is on
+    fn set_rng_bytes(&mut self, data: Vec<u8>) -> PyResult<()> {
+        if data.is_empty() {
+            return Err(PyValueError::new_err("empty byte sequence"));
+        }
+        self.m.set_rng(Box::new(CyclicRng::new(data)));
+        Ok(())
+    }
+
+    // "wrap", "halt" or "error" when PC points past end of RAM
+    fn set_pc_policy(&mut self, policy: &str) -> PyResult<()> {
+        self.m.set_pc_policy(parse_pc_policy(policy)?);
+        Ok(())
+    }
+
+    fn auto_configure(&mut self) -> &'static str {
+        match self.m.auto_configure() {
+            Target::Chip8 => "chip8",
+            Target::SuperChip => "schip",
+            Target::XoChip => "xochip",
+        }
+    }
+
+    fn reg(&self, i: usize) -> u8 {
+        self.m.cpu().r[i]
+    }
+
+    #[getter]
+    fn registers(&self) -> Vec<u8> {
+        self.m.cpu().r.to_vec()
+    }
+
+    // Set all 16 registers and I in one call
+    fn set_register_bank(&mut self, regs: Vec<u8>, i: usize) -> PyResult<()> {
+        let mut bank = [0; 16];
+        if regs.len() != bank.len() {
+            return Err(PyValueError::new_err("register bank must have 16 values"));
+        }
+        bank.copy_from_slice(&regs);
+        self.m.set_register_bank(bank, i);
+        Ok(())
+    }
+
+    #[getter]
+    fn stack(&self) -> Vec<u16> {
+        // live entries from innermost call out
+        self.m.mem().stack[self.m.cpu().sp..].to_vec()
+    }
+
+    #[getter]
+    fn i(&self) -> usize {
+        self.m.cpu().i
+    }
+
+    #[getter]
+    fn pc(&self) -> usize {
+        self.m.cpu().pc
+    }
+
+    #[getter]
+    fn sp(&self) -> usize {
+        self.m.cpu().sp
+    }
+
+    #[getter]
+    fn dt(&self) -> u8 {
+        self.m.cpu().dt
+    }
+
+    #[getter]
+    fn st(&self) -> u8 {
+        self.m.cpu().st
+    }
+
+    #[getter]
+    fn in_spin_loop(&self) -> bool {
+        self.m.in_spin_loop()
+    }
+
+    fn take_resolution_changed(&mut self) -> Option<(usize, usize)> {
+        self.m.take_resolution_changed()
+    }
+
+    #[getter]
+    fn is_halted(&self) -> bool {
+        self.m.is_halted()
+    }
+
+    fn resume(&mut self) {
+        self.m.resume()
+    }
+
+    #[getter]
+    fn is_key_waiting(&self) -> bool {
+        self.m.is_key_waiting()
+    }
+
+    #[getter]
+    fn last_changed_reg(&self) -> Option<usize> {
+        self.m.last_changed_reg()
+    }
+
+    #[getter]
+    fn pc_misaligned(&self) -> bool {
+        self.m.pc_misaligned()
+    }
+
+    #[getter]
+    fn entry_pc(&self) -> usize {
+        self.m.entry_pc()
+    }
+
+    #[setter]
+    fn set_entry_pc(&mut self, addr: usize) -> PyResult<()> {
+        self.m.set_entry_pc(addr).map_err(|err| PyValueError::new_err(err.to_string()))
+    }
+
+    #[getter]
+    fn tone_hz(&self) -> f32 {
+        self.m.tone_hz()
+    }
+
+    #[setter]
+    fn set_tone_hz(&mut self, hz: f32) {
+        self.m.set_tone_hz(hz)
+    }
+
+    #[getter]
+    fn is_sound_active(&self) -> bool {
+        self.m.is_sound_active()
+    }
+
+    #[getter]
+    fn audio_pattern<'p>(&self, py: Python<'p>) -> &'p PyBytes {
+        PyBytes::new(py, self.m.audio_pattern())
+    }
+
+    // 1 bit per pixel, MSB leftmost, suitable as golden image fixture
+    fn framebuffer_packed<'p>(&self, py: Python<'p>) -> &'p PyBytes {
+        PyBytes::new(py, &self.m.framebuffer_packed())
+    }
+
+    fn framebuffer_matches(&self, reference: &[u8]) -> bool {
+        self.m.framebuffer_matches(reference)
+    }
+
+    fn framebuffer_matches_file(&self, path: &str) -> PyResult<bool> {
+        Ok(self.m.framebuffer_matches_file(path)?)
+    }
+
+    fn framebuffer_planes<'p>(&self, py: Python<'p>) -> (&'p PyBytes, &'p PyBytes) {
+        let (first, second) = self.m.framebuffer_planes();
+        (PyBytes::new(py, first), PyBytes::new(py, second))
+    }
+
+    #[getter]
+    fn resolution(&self) -> (usize, usize) {
+        self.m.resolution()
+    }
+
+    #[getter]
+    fn rom(py_self: Py<PyMachine>, py: Python) -> PyResult<Py<PyMemoryView>> {
+        Py::new(py, PyMemoryView {owner: py_self.clone(), getter: PyMachine::get_rom})
+    }
+
+    #[getter]
+    fn ram(py_self: Py<PyMachine>, py: Python) -> PyResult<Py<PyMemoryView>> {
+        Py::new(py, PyMemoryView {owner: py_self.clone(), getter: PyMachine::get_ram})
+    }
+
+    #[getter]
+    fn framebuffer(py_self: Py<PyMachine>, py: Python) -> PyResult<Py<PyMemoryView>> {
+        Py::new(py, PyMemoryView {owner: py_self.clone(), getter: PyMachine::get_fb})
+    }
+
+    // Iterator running count frames at ips, yields framebuffer bytes after each
+    #[args(ips = "700", count = "60")]
+    fn frames(py_self: Py<PyMachine>, py: Python, ips: u32, count: usize) -> PyResult<Py<PyFrameIter>> {
+        Py::new(py, PyFrameIter {owner: py_self, ips, remaining: count})
+    }
+}
+
+#[pymethods]
+impl PyMachine {
+    fn load(&mut self, filename: &str) -> PyResult<usize> {
+        match guarded(|| self.m.load(filename))? {
+            Ok(value) => Ok(value),
+            Err(err) => Err(PyErr::from(err))
+        }
+    }
+
+    // Load and run up to max_cycles, {"cycles_run", "registers", "i", "pc", "stop"}
+    #[args(max_cycles = "100000")]
+    fn load_and_run(&mut self, py: Python, filename: &str, max_cycles: usize) -> PyResult<PyObject> {
+        let summary = guarded(|| self.m.load_and_run(filename, max_cycles))?.map_err(PyErr::from)?;
+        let dict = PyDict::new(py);
+        dict.set_item("cycles_run", summary.cycles_run)?;
+        dict.set_item("registers", summary.registers.to_vec())?;
+        dict.set_item("i", summary.i)?;
+        dict.set_item("pc", summary.pc)?;
+        dict.set_item("stop", summary.stop.to_string())?;
+        Ok(dict.into())
+    }
+
+    fn load_info(&mut self, py: Python, filename: &str) -> PyResult<PyObject> {
+        let info = guarded(|| self.m.load_info(filename))??;
+        rom_info_to_dict(py, &info)
+    }
+
+    // Like load_info, strict raises for ROMs that don't fit instead of truncating
+    #[args(strict = "false")]
+    fn load_validated(&mut self, py: Python, filename: &str, strict: bool) -> PyResult<PyObject> {
+        let info = guarded(|| self.m.load_validated(filename, strict))??;
+        rom_info_to_dict(py, &info)
+    }
+
+    fn assemble_and_load(&mut self, src: &str) -> PyResult<usize> {
+        self.m.assemble_and_load(src).map_err(|err| PyValueError::new_err(err.to_string()))
+    }
+
+    fn load_hex(&mut self, text: &str) -> PyResult<usize> {
+        match guarded(|| self.m.load_hex(text))? {
+            Ok(value) => Ok(value),
+            Err(err) => Err(PyValueError::new_err(err.to_string()))
+        }
+    }
+
+    fn dump_ram(&self, path: &str) -> PyResult<()> {
+        Ok(self.m.dump_ram(path)?)
+    }
+
+    fn load_ram_image(&mut self, path: &str) -> PyResult<()> {
+        Ok(self.m.load_ram_image(path)?)
+    }
+
+    fn load_overlay(&mut self, data: &[u8], addr: usize) -> PyResult<usize> {
+        self.m.load_overlay(data, addr).map_err(|err| PyValueError::new_err(err.to_string()))
+    }
+
+    fn reset(&mut self) -> PyResult<()> {
+        guarded(|| self.m.reset())
+    }
+
+    // Reset also wiping RAM and loaded program
+    fn cold_reset(&mut self) -> PyResult<()> {
+        guarded(|| self.m.cold_reset())
+    }
+
+    // Apply target preset then cold reset
+    fn reset_with_target(&mut self, target: &str) -> PyResult<()> {
+        let target = parse_target(target)?;
+        guarded(|| self.m.reset_with_target(target))
+    }
+
+    // Reset and rewind RAND to last seed for exact replays
+    fn reset_preserving_seed(&mut self) -> PyResult<()> {
+        guarded(|| self.m.reset_preserving_seed())
+    }
+
+    fn reset_cpu_only(&mut self) -> PyResult<()> {
+        guarded(|| self.m.reset_cpu_only())
+    }
+
+    fn goto_entry(&mut self) -> PyResult<()> {
+        guarded(|| self.m.goto_entry())
+    }
+
+    // 5 rows of 8 cells of built-in glyph for hex digit
+    fn font_sprite(&self, digit: u8) -> Vec<Vec<bool>> {
+        self.m.font_sprite(digit).iter().map(|row| row.to_vec()).collect()
+    }
+
+    fn read_sprite(&self, addr: usize, rows: usize) -> PyResult<Vec<Vec<bool>>> {
+        guarded(|| self.m.read_sprite(addr, rows))
+    }
+
+    // Draw sprite rows at (x, y), returns collision flag per row
+    fn draw_sprite_detailed(&mut self, x: usize, y: usize, rows: &[u8]) -> PyResult<Vec<bool>> {
+        guarded(|| self.m.draw_sprite_detailed(x, y, rows))
+    }
+
+    fn would_collide(&self, x: usize, y: usize, n: usize) -> PyResult<bool> {
+        guarded(|| self.m.would_collide(x, y, n))
+    }
+
+    fn current_instruction(&self) -> PyResult<Option<(u16, String)>> {
+        guarded(|| self.m.current_instruction().map(|(opcode, op)| (opcode, op.to_string())))
+    }
+
+    #[args(mask = "0xFFFF")]
+    fn find_opcode(&self, pattern: u16, mask: u16) -> Vec<usize> {
+        self.m.find_opcode(pattern, mask)
+    }
+
+    // (opcode, mnemonic) at address, None past RAM end
+    fn disassemble_pc(&self, addr: usize) -> Option<(u16, String)> {
+        self.m.opcode_at(addr).map(|(opcode, op)| (opcode, op.to_string()))
+    }
+
+    fn peek(&self, addr: usize) -> PyResult<u8> {
+        match self.m.mem().ram.get(addr) {
+            Some(&value) => Ok(value),
+            None => Err(PyValueError::new_err("address out of range"))
+        }
+    }
+
+    fn disassemble_range(&self, start: usize, end: usize) -> Vec<(usize, String)> {
+        let ram = &self.m.mem().ram;
+        let end = end.min(ram.len());
+        (start..end).step_by(2)
+            .filter_map(|addr| decode(ram.get(addr..end)?).map(|op| (addr, op.to_string())))
+            .collect()
+    }
+
+    // [(addr, opcode, mnemonic, is_pc)] within radius instructions around PC
+    #[args(radius = "4")]
+    fn disassemble_around(&self, radius: usize) -> Vec<(usize, u16, String, bool)> {
+        self.m.disassemble_around(radius).into_iter()
+            .map(|(addr, opcode, op, current)| (addr, opcode, op.to_string(), current))
+            .collect()
+    }
+
+    #[args(horizontal = "false", vertical = "false")]
+    fn framebuffer_flipped<'p>(&self, py: Python<'p>, horizontal: bool, vertical: bool) -> &'p PyBytes {
+        PyBytes::new(py, &self.m.framebuffer_flipped(horizontal, vertical))
+    }
+
+    fn framebuffer_delta(&mut self) -> Vec<(usize, u8)> {
+        self.m.framebuffer_delta()
+    }
+
+    fn dirty_rows(&mut self) -> Vec<usize> {
+        self.m.dirty_rows()
+    }
+
+    // Loaded program from RAM as bytes, trim exports through last non-zero byte instead
+    #[args(trim = "false")]
+    fn export_rom<'p>(&self, py: Python<'p>, trim: bool) -> &'p PyBytes {
+        let rom = if trim { self.m.export_rom_trimmed() } else { self.m.export_rom() };
+        PyBytes::new(py, &rom)
+    }
+
+    fn ram_used(&self) -> usize {
+        self.m.ram_used()
+    }
+
+    fn free_ram(&self) -> usize {
+        self.m.free_ram()
+    }
+
+    fn memory_map(&self, py: Python) -> PyResult<Vec<PyObject>> {
+        self.m.memory_map().into_iter()
+            .map(|region| {
+                let dict = PyDict::new(py);
+                dict.set_item("start", region.start)?;
+                dict.set_item("end", region.end)?;
+                dict.set_item("kind", format!("{:?}", region.kind).to_lowercase())?;
+                dict.set_item("label", region.label)?;
+                Ok(dict.into())
+            })
+            .collect()
+    }
+
+    fn stats(&self, py: Python) -> PyResult<PyObject> {
+        let stats = self.m.stats();
+        let dict = PyDict::new(py);
+        dict.set_item("cycles", stats.cycles)?;
+        dict.set_item("draws", stats.draws)?;
+        dict.set_item("skips_taken", stats.skips_taken)?;
+        Ok(dict.into())
+    }
+
+    // Registers, RAM, stack and keys as dict, the screen is not included
+    fn snapshot_dict(&self, py: Python) -> PyResult<PyObject> {
+        let snap = self.m.snapshot();
+        let dict = PyDict::new(py);
+        dict.set_item("registers", snap.registers.to_vec())?;
+        dict.set_item("i", snap.i)?;
+        dict.set_item("pc", snap.pc)?;
+        dict.set_item("sp", snap.sp)?;
+        dict.set_item("dt", snap.dt)?;
+        dict.set_item("st", snap.st)?;
+        dict.set_item("ram", PyBytes::new(py, &snap.ram))?;
+        dict.set_item("stack", snap.stack)?;
+        dict.set_item("keys", snap.keys.to_vec())?;
+        Ok(dict.into())
+    }
+
+    // Install state from dict with the keys returned by snapshot_dict
+    fn reset_to(&mut self, state: &PyDict) -> PyResult<()> {
+        let snap = snapshot_from_dict(state)?;
+        self.m.reset_to(&snap).map_err(|err| PyValueError::new_err(err.to_string()))
+    }
+
+    // Whole machine state as bytes including screen, for rewinding with restore
+    fn snapshot<'p>(&self, py: Python<'p>) -> &'p PyBytes {
+        PyBytes::new(py, &self.m.snapshot_bytes())
+    }
+
+    // Rewind to bytes from snapshot, a snapshot_dict dict works like reset_to
+    fn restore(&mut self, state: &PyAny) -> PyResult<()> {
+        if let Ok(dict) = state.downcast::<PyDict>() {
+            return self.reset_to(dict);
+        }
+        let bytes: &[u8] = state.extract()?;
+        self.m.restore(bytes).map_err(|err| PyValueError::new_err(err.to_string()))
+    }
+
+    // Copy shares no state with original, callbacks are not carried over
+    fn fork(&self) -> PyMachine {
+        PyMachine::from_machine(self.m.fork())
+    }
+
+    fn __copy__(&self) -> PyMachine {
+        self.fork()
+    }
+
+    fn __deepcopy__(&self, _memo: &PyAny) -> PyMachine {
+        self.fork()
+    }
+
+    fn elapsed_ms(&self, ips: u32) -> u64 {
+        self.m.elapsed_ms(ips)
+    }
+
+    fn debug_line(&self) -> String {
+        self.m.debug_line()
+    }
+
+    fn registers_hex(&self) -> String {
+        self.m.registers_hex()
+    }
+
+    // Remember last len executed addresses, 0 turns it off
+    fn set_pc_history(&mut self, len: usize) {
+        self.m.set_pc_history(len)
+    }
+
+    fn pc_history(&self) -> Vec<usize> {
+        self.m.pc_history().to_vec()
+    }
+
+    // Count colliding draws of last len frames, 0 turns it off
+    fn set_collision_history(&mut self, len: usize) {
+        self.m.set_collision_history(len)
+    }
+
+    fn collision_history(&self) -> Vec<u32> {
+        self.m.collision_history().to_vec()
+    }
+
+    // Record every executed instruction for export_trace_csv
+    fn set_trace(&mut self, enabled: bool) {
+        self.m.set_trace(enabled)
+    }
+
+    fn export_trace_csv(&self) -> String {
+        self.m.export_trace_csv()
+    }
+
+    // Idle `JP self` loops keep returning the jump instead of raising
+    fn step<'p>(&mut self, py: Python<'p>) -> PyResult<PyObject> {
+        let result = guarded(|| self.m.step());
+        self.take_hook_error()?;
+        match result? {
+            Ok(outcome) => outcome_to_dict(py, &outcome),
+            Err(err) => match self.m.idle_outcome() {
+                Some(outcome) => outcome_to_dict(py, &outcome),
+                None => Err(PyValueError::new_err(err.to_string()))
+            }
+        }
+    }
+
+    // Set keypad from bitmask, bit k for key k, then step
+    fn step_with_keys(&mut self, py: Python, keys: u16) -> PyResult<PyObject> {
+        let result = guarded(|| self.m.step_with_keys(keys));
+        self.take_hook_error()?;
+        match result? {
+            Ok(outcome) => outcome_to_dict(py, &outcome),
+            Err(err) => Err(PyValueError::new_err(err.to_string()))
+        }
+    }
+
+    // Trace of up to n steps, an error ends the list with {"pc", "error"}
+    fn step_n(&mut self, py: Python, n: usize) -> PyResult<Vec<PyObject>> {
+        let result = guarded(|| self.m.step_n(n));
+        self.take_hook_error()?;
+        let (outcomes, err) = result?;
+        let mut trace = outcomes.iter().map(|outcome| outcome_to_dict(py, outcome)).collect::<PyResult<Vec<_>>>()?;
+        if let Some(err) = err {
+            let dict = PyDict::new(py);
+            dict.set_item("pc", self.m.cpu().pc)?;
+            dict.set_item("error", err.to_string())?;
+            trace.push(dict.into());
+        }
+        Ok(trace)
+    }
+
+    fn run_until(py_self: Py<PyMachine>, py: Python, predicate: PyObject, max_cycles: usize) -> PyResult<usize> {
+        for cycle in 0..max_cycles {
+            let mut machine = py_self.borrow_mut(py);
+            let result = guarded(|| machine.m.step());
+            machine.take_hook_error()?;
+            if let Err(err) = result? {
+                return Err(PyValueError::new_err(err.to_string()));
+            }
+            drop(machine);
+            if predicate.call1(py, (py_self.clone_ref(py),))?.as_ref(py).is_true()? {
+                return Ok(cycle + 1);
+            }
+        }
+        Ok(max_cycles)
+    }
+
+    // Call hook(pc, mnemonic) before each instruction, None removes it.
+    // Hooks and callbacks run while machine is busy, so they get the state
+    // they need as arguments and must not touch the machine. First
+    // exception stops later hook calls and is raised by the step, run or
+    // tick call that ran the hook once it returns.
+    fn set_pre_hook(&mut self, hook: Option<PyObject>) {
+        match hook {
+            Some(hook) => {
+                let hook_error = self.hook_error.clone();
+                self.m.set_pre_hook(move |pc, op| {
+                    call_hook(&hook_error, |py| hook.call1(py, (pc, op.to_string())))
+                })
+            }
+            None => self.m.clear_pre_hook(),
+        }
+    }
+
+    // Call hook(outcome) after each instruction with same dict step
+    // returns, None removes it
+    fn set_post_hook(&mut self, hook: Option<PyObject>) {
+        match hook {
+            Some(hook) => {
+                let hook_error = self.hook_error.clone();
+                self.m.set_post_hook(move |outcome| {
+                    call_hook(&hook_error, |py| outcome_to_dict(py, outcome).and_then(|dict| hook.call1(py, (dict,))))
+                })
+            }
+            None => self.m.clear_post_hook(),
+        }
+    }
+
+    // Call callback(outcome) after each instruction of run_safe, after post hook, None removes it
+    fn set_trace_callback(&mut self, callback: Option<PyObject>) {
+        match callback {
+            Some(callback) => {
+                let hook_error = self.hook_error.clone();
+                self.m.set_trace_callback(Box::new(move |outcome| {
+                    call_hook(&hook_error, |py| outcome_to_dict(py, outcome).and_then(|dict| callback.call1(py, (dict,))))
+                }))
+            }
+            None => self.m.clear_trace_callback(),
+        }
+    }
+
+    // Call callback(active) when buzzer turns on or off, None removes it
+    fn set_sound_callback(&mut self, callback: Option<PyObject>) {
+        match callback {
+            Some(callback) => {
+                let hook_error = self.hook_error.clone();
+                self.m.set_sound_callback(Box::new(move |active| {
+                    call_hook(&hook_error, |py| callback.call1(py, (active,)))
+                }))
+            }
+            None => self.m.clear_sound_callback(),
+        }
+    }
+
+    // Call callback() when delay timer reaches zero, None removes it
+    fn set_dt_zero_callback(&mut self, callback: Option<PyObject>) {
+        match callback {
+            Some(callback) => {
+                let hook_error = self.hook_error.clone();
+                self.m.set_dt_zero_callback(Box::new(move || {
+                    call_hook(&hook_error, |py| callback.call0(py))
+                }))
+            }
+            None => self.m.clear_dt_zero_callback(),
+        }
+    }
+
+    fn set_spin_window(&mut self, window: usize) {
+        self.m.set_spin_window(window)
+    }
+
+    fn set_stop_on_empty(&mut self, enabled: bool) {
+        self.m.set_stop_on_empty(enabled)
+    }
+
+    fn set_protect_font(&mut self, enabled: bool) {
+        self.m.set_protect_font(enabled)
+    }
+
+    fn set_protected_range(&mut self, start: usize, end: usize) {
+        self.m.set_protected_range(start, end)
+    }
+
+    fn clear_protection(&mut self) {
+        self.m.clear_protection()
+    }
+
+    fn set_track_self_modify(&mut self, enabled: bool) {
+        self.m.set_track_self_modify(enabled)
+    }
+
+    fn was_self_modified(&self) -> bool {
+        self.m.was_self_modified()
+    }
+
+    fn self_modified_addresses(&self) -> Vec<usize> {
+        self.m.self_modified_addresses().to_vec()
+    }
+
+    fn enable_undo(&mut self, depth: usize) {
+        self.m.enable_undo(depth)
+    }
+
+    fn undo(&mut self) -> bool {
+        self.m.undo()
+    }
+
+    fn run_realtime(&mut self, py: Python, duration_ms: u32, ips: u32) -> PyResult<usize> {
+        let m = &mut self.m;
+        let result = py.allow_threads(|| guarded(|| m.run_realtime(duration_ms, ips)));
+        self.take_hook_error()?;
+        result
+    }
+
+    // Run until program halts, raises TimeoutError after max_cycles so
+    // spinning ROMs can't hang a notebook
+    fn run_safe(&mut self, max_cycles: usize) -> PyResult<usize> {
+        let result = guarded(|| self.m.run(max_cycles));
+        self.take_hook_error()?;
+        match result? {
+            Ok(executed) => Ok(executed),
+            Err(err @ StepError::Stopped(StopReason::CycleLimitReached)) => Err(PyTimeoutError::new_err(err.to_string())),
+            Err(err) => Err(PyValueError::new_err(err.to_string()))
+        }
+    }
+
+    // Instructions executed up to and including next screen change, None
+    // when max_cycles pass without one
+    #[args(max_cycles = "100000")]
+    fn run_until_draw(&mut self, max_cycles: usize) -> PyResult<Option<usize>> {
+        let result = guarded(|| self.m.run_until_draw(max_cycles));
+        self.take_hook_error()?;
+        match result? {
+            Ok(RunStatus::Drew(executed)) => Ok(Some(executed)),
+            Ok(RunStatus::CycleLimit) => Ok(None),
+            Err(err) => Err(PyValueError::new_err(err.to_string()))
+        }
+    }
+
+    #[args(ips = "700")]
+    fn run_frame(&mut self, ips: u32) -> PyResult<usize> {
+        let result = guarded(|| self.m.run_frame(ips));
+        self.take_hook_error()?;
+        result?.map_err(|err| PyValueError::new_err(err.to_string()))
+    }
+
+    fn tick(&mut self) -> PyResult<()> {
+        let result = guarded(|| self.m.tick());
+        self.take_hook_error()?;
+        result
+    }
+
+    fn tick_timers(&mut self, n: u8) -> PyResult<()> {
+        let result = guarded(|| self.m.tick_timers(n));
+        self.take_hook_error()?;
+        result
+    }
+
+    fn set_timers_frozen(&mut self, frozen: bool) {
+        self.m.set_timers_frozen(frozen)
+    }
+
+    // Returns previous key state so callers can detect edges
+    fn keyevent(&mut self, key: usize, state: bool) -> PyResult<bool> {
+        self.m.set_key(key, state).ok_or_else(|| PyValueError::new_err("key out of range"))
+    }
+
+    // Press or release key once cycle instructions have run
+    fn queue_key_event(&mut self, cycle: u64, key: usize, state: bool) -> PyResult<()> {
+        if !self.m.queue_key_event(cycle, key, state) {
+            return Err(PyValueError::new_err("key out of range"));
+        }
+        Ok(())
+    }
+
+    fn key_hold_ticks(&self, key: usize) -> u32 {
+        self.m.key_hold_ticks(key)
+    }
+
+    fn keys_pressed(&self) -> Vec<usize> {
+        self.m.keys_pressed()
+    }
+
+    fn reset_keys(&mut self) {
+        self.m.reset_keys()
+    }
+
+    fn press_direction(&mut self, direction: &str) -> PyResult<()> {
+        let dir = parse_direction(direction)?;
+        guarded(|| self.m.press_direction(dir))
+    }
+
+    fn release_direction(&mut self, direction: &str) -> PyResult<()> {
+        let dir = parse_direction(direction)?;
+        guarded(|| self.m.release_direction(dir))
+    }
+
+    fn set_direction_map(&mut self, up: usize, down: usize, left: usize, right: usize) -> PyResult<()> {
+        if [up, down, left, right].iter().any(|&key| key > 0xf) {
+            return Err(PyValueError::new_err("key out of range"));
+        }
+        self.m.set_direction_map([up, down, left, right]);
+        Ok(())
+    }
+}
+
+#[pyclass(name="Quirks", module="chip8")]
+#[derive(Clone)]
+struct PyQuirks {
+    #[pyo3(get, set)]
+    shift_uses_vy: bool,
+    #[pyo3(get, set)]
+    mem_increments_i: bool,
+    #[pyo3(get, set)]
+    jump_uses_vx: bool,
+    #[pyo3(get, set)]
+    clip_sprites: bool,
+    #[pyo3(get, set)]
+    sys_is_noop: bool,
+    #[pyo3(get, set)]
+    logic_resets_vf: bool,
+    #[pyo3(get, set)]
+    add_i_sets_vf: bool,
+    #[pyo3(get, set)]
+    display_wait: bool,
+    #[pyo3(get, set)]
+    schip_opcodes: bool,
+}
+
+impl From<Quirks> for PyQuirks {
+    fn from(q: Quirks) -> Self {
+        PyQuirks {
+            shift_uses_vy: q.shift_uses_vy,
+            mem_increments_i: q.mem_increments_i,
+            jump_uses_vx: q.jump_uses_vx,
+            clip_sprites: q.clip_sprites,
+            sys_is_noop: q.sys_is_noop,
+            logic_resets_vf: q.logic_resets_vf,
+            add_i_sets_vf: q.add_i_sets_vf,
+            display_wait: q.display_wait,
+            schip_opcodes: q.schip_opcodes,
+        }
+    }
+}
+
+impl From<&PyQuirks> for Quirks {
+    fn from(q: &PyQuirks) -> Self {
+        Quirks {
+            shift_uses_vy: q.shift_uses_vy,
+            mem_increments_i: q.mem_increments_i,
+            jump_uses_vx: q.jump_uses_vx,
+            clip_sprites: q.clip_sprites,
+            sys_is_noop: q.sys_is_noop,
+            logic_resets_vf: q.logic_resets_vf,
+            add_i_sets_vf: q.add_i_sets_vf,
+            display_wait: q.display_wait,
+            schip_opcodes: q.schip_opcodes,
+        }
+    }
+}
+
+#[pymethods]
+impl PyQuirks {
+    #[new]
+    fn new() -> Self {
+        PyQuirks::from(Quirks::default())
+    }
+
+    #[classmethod]
+    fn chip8(_cls: &PyType) -> Self {
+        PyQuirks::from(Quirks::for_target(Target::Chip8))
+    }
+
+    #[classmethod]
+    fn schip(_cls: &PyType) -> Self {
+        PyQuirks::from(Quirks::for_target(Target::SuperChip))
+    }
+
+    #[classmethod]
+    fn xochip(_cls: &PyType) -> Self {
+        PyQuirks::from(Quirks::for_target(Target::XoChip))
+    }
+}
+
+// Run emulator code and turn any panic into python exception
+fn guarded<T, F: FnOnce() -> T>(f: F) -> PyResult<T> {
+    panic::catch_unwind(AssertUnwindSafe(f)).map_err(|err| {
+        let msg = match err.downcast_ref::<&str>() {
+            Some(msg) => msg.to_string(),
+            None => match err.downcast_ref::<String>() {
+                Some(msg) => msg.clone(),
+                None => String::from("emulator panicked")
+            }
+        };
+        PyRuntimeError::new_err(msg)
+    })
+}
+
+fn parse_target(target: &str) -> PyResult<Target> {
+    match target {
+        "chip8" => Ok(Target::Chip8),
+        "schip" => Ok(Target::SuperChip),
+        "xochip" => Ok(Target::XoChip),
+        _ => Err(PyValueError::new_err("unknown target"))
+    }
+}
+
+fn parse_pc_policy(policy: &str) -> PyResult<PcPolicy> {
+    match policy {
+        "wrap" => Ok(PcPolicy::Wrap),
+        "halt" => Ok(PcPolicy::Halt),
+        "error" => Ok(PcPolicy::Error),
+        _ => Err(PyValueError::new_err("unknown PC policy"))
+    }
+}
+
+fn parse_direction(direction: &str) -> PyResult<Direction> {
+    match direction {
+        "up" => Ok(Direction::Up),
+        "down" => Ok(Direction::Down),
+        "left" => Ok(Direction::Left),
+        "right" => Ok(Direction::Right),
+        _ => Err(PyValueError::new_err("unknown direction"))
+    }
+}
+
+fn snapshot_from_dict(dict: &PyDict) -> PyResult<MachineSnapshot> {
+    fn item<'a, T: FromPyObject<'a>>(dict: &'a PyDict, key: &str) -> PyResult<T> {
+        match dict.get_item(key) {
+            Some(value) => value.extract(),
+            None => Err(PyValueError::new_err(format!("snapshot is missing '{}'", key)))
+        }
+    }
+    fn array<T: Copy + Default, const N: usize>(values: Vec<T>, key: &str) -> PyResult<[T; N]> {
+        if values.len() != N {
+            return Err(PyValueError::new_err(format!("snapshot '{}' must have {} items", key, N)));
+        }
+        let mut array = [T::default(); N];
+        array.copy_from_slice(&values);
+        Ok(array)
+    }
+    let ram: Vec<u8> = item(dict, "ram")?;
+    if ram.len() != DEFAULT_RAM_SIZE && ram.len() != XOCHIP_RAM_SIZE {
+        return Err(PyValueError::new_err("snapshot 'ram' must be 4096 or 65536 bytes"));
+    }
+    Ok(MachineSnapshot {
+        registers: array(item(dict, "registers")?, "registers")?,
+        i: item(dict, "i")?,
+        pc: item(dict, "pc")?,
+        sp: item(dict, "sp")?,
+        dt: item(dict, "dt")?,
+        st: item(dict, "st")?,
+        ram,
+        stack: item(dict, "stack")?,
+        keys: array(item(dict, "keys")?, "keys")?,
+    })
+}
+
+fn rom_info_to_dict(py: Python, info: &RomInfo) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    dict.set_item("bytes", info.bytes)?;
+    dict.set_item("base", info.base)?;
+    dict.set_item("first_op", info.first_op.map(|op| op.to_string()))?;
+    dict.set_item("uses_schip", info.uses_schip)?;
+    dict.set_item("uses_xochip", info.uses_xochip)?;
+    dict.set_item("warnings", info.warnings.iter().map(|warning| warning.to_string()).collect::<Vec<_>>())?;
+    Ok(dict.into())
+}
+
+fn outcome_to_dict(py: Python, outcome: &StepOutcome) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    dict.set_item("pc", outcome.pc)?;
+    dict.set_item("op", outcome.op.to_string())?;
+    dict.set_item("opcode", outcome.opcode)?;
+    dict.set_item("drew", outcome.drew)?;
+    dict.set_item("branched", outcome.branched)?;
+    dict.set_item("new_pc", outcome.new_pc)?;
+    dict.set_item("key_waiting", outcome.key_waiting)?;
+    dict.set_item("written_addresses", outcome.written_addresses.clone())?;
+    Ok(dict.into())
+}
+
+// A python bytes like object implemented in Rust
+#[pyclass(name="MachineMemoryView", module="chip8")]
+struct PyMemoryView {
+    owner: Py<PyMachine>,
+    getter: fn(&PyMachine) -> &[u8]
+}
+
+impl PyMemoryView {
+    fn len(&self, py: Python) -> PyResult<usize> {
+        let machine: PyRef<PyMachine> = self.owner.try_borrow(py)?;
+        Ok((self.getter)(&machine).len())
+    }
+
+    fn at(&self, py: Python, index: usize) -> PyResult<u8> {
+        let machine: PyRef<PyMachine> = self.owner.try_borrow(py)?;
+        let mem = (self.getter)(&*machine);
+        if index < mem.len() {
+            Ok(mem[index])
+        }
+        else {
+            Err(PyIndexError::new_err("index out of range"))
+        }
+    }
+}
+
+#[pyproto]
+impl PyBufferProtocol for PyMemoryView {
+    fn bf_getbuffer(slf: PyRefMut<Self>, view: *mut ffi::Py_buffer, flags: c_int) -> PyResult<()> {
+        let machine: PyRef<PyMachine> = slf.owner.try_borrow(slf.py())?;
+        let mem: &[u8] = (slf.getter)(&machine);
+        let obj = slf.as_ptr();
+        let buff = mem.as_ptr() as *mut c_void;
+        let size = mem.len() as isize;
+        unsafe {
+            ffi::PyBuffer_FillInfo(view, obj, buff, size, 1, flags)
+        };
+        Ok(())
+    }
+
+    fn bf_releasebuffer(_slf: PyRefMut<Self>, _view: *mut ffi::Py_buffer) -> PyResult<()> {
+        Ok(())
+    }
+}
+
+#[pyproto]
+impl PySequenceProtocol for PyMemoryView {
+    fn __len__(&self) -> PyResult<usize> {
+        let gil = Python::acquire_gil();
+        self.len(gil.python())
+    }
+}
+
+#[pyproto]
+impl PyMappingProtocol for PyMemoryView {
+    fn __getitem__(&self, key: &PyAny) -> PyResult<PyObject> {
+        let gil = Python::acquire_gil();
+        let py = gil.python();
+        let len = self.len(py)?;
+        if let Ok(slice) = key.downcast::<PySlice>() {
+            let range = slice.indices(len as c_long)?;
+            let machine: PyRef<PyMachine> = self.owner.try_borrow(py)?;
+            let mem = (self.getter)(&machine);
+            let bytes: Vec<u8> = (0..range.slicelength)
+                .map(|k| mem[(range.start + k * range.step) as usize])
+                .collect();
+            return Ok(PyBytes::new(py, &bytes).into());
+        }
+        // negative index counts from the end like python sequences
+        let index: isize = key.extract()?;
+        let index = if index < 0 { index + len as isize } else { index };
+        if index < 0 {
+            return Err(PyIndexError::new_err("index out of range"));
+        }
+        Ok(self.at(py, index as usize)?.into_py(py))
+    }
+}
+
+#[pyproto]
+impl PyIterProtocol for PyMemoryView {
+    fn __iter__(slf: Py<Self>) -> PyResult<Py<PyMemoryViewIter>> {
+        let gil = Python::acquire_gil();
+        let iter = PyMemoryViewIter {owner: slf.clone(), index: 0};
+        Py::new(gil.python(), iter)
+    }
+}
+
+// A Python iterator implementaed in Rust
+#[pyclass(name="MachineMemoryViewIter", module="chip8")]
+struct PyMemoryViewIter {
+    owner: Py<PyMemoryView>,
+    index: usize
+}
+
+#[pyproto]
+impl PyIterProtocol for PyMemoryViewIter {
+    fn __iter__(slf: Py<Self>) -> PyResult<Py<PyMemoryViewIter>> {
+        Ok(slf)
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> Option<u8> {
+        let py = slf.py();
+        let result = slf.owner.borrow(py).at(py, slf.index);
+        slf.index += 1;
+        match result {
+            Ok(value) => Some(value),
+            Err(_) => None
+        }
+    }
+}
+
+// Runs one frame per step, stops early once the machine halts
+#[pyclass(name="MachineFrameIter", module="chip8")]
+struct PyFrameIter {
+    owner: Py<PyMachine>,
+    ips: u32,
+    remaining: usize
+}
+
+#[pyproto]
+impl PyIterProtocol for PyFrameIter {
+    fn __iter__(slf: Py<Self>) -> PyResult<Py<PyFrameIter>> {
+        Ok(slf)
+    }
+
+    fn __next__(mut slf: PyRefMut<Self>) -> PyResult<Option<PyObject>> {
+        let py = slf.py();
+        let mut machine = slf.owner.borrow_mut(py);
+        if slf.remaining == 0 || machine.m.is_halted() {
+            return Ok(None);
+        }
+        let result = guarded(|| machine.m.run_frame(slf.ips));
+        machine.take_hook_error()?;
+        match result? {
+            Ok(_) | Err(StepError::Stopped(_)) => {}
+            Err(err) => return Err(PyValueError::new_err(err.to_string()))
+        }
+        let frame = PyBytes::new(py, machine.get_fb()).into();
+        drop(machine);
+        slf.remaining -= 1;
+        Ok(Some(frame))
+    }
+}
+
+/// Control flow graph of program as {block: [successor or None]}
+#[pyfunction(base = "0x200")]
+#[name = "build_cfg"]
+fn py_build_cfg(data: &[u8], base: usize) -> HashMap<usize, Vec<Option<usize>>> {
+    build_cfg(data, base).blocks.into_iter()
+        .map(|block| {
+            let edges = block.successors.into_iter()
+                .map(|edge| match edge {
+                    Edge::To(addr) => Some(addr),
+                    Edge::Unknown => None
+                })
+                .collect();
+            (block.start, edges)
+        })
+        .collect()
+}
+
+/// Changed instructions between two ROMs as [{"addr", "old", "new"}]
+#[pyfunction(base = "0x200")]
+#[name = "diff_roms"]
+fn py_diff_roms(py: Python, a: &[u8], b: &[u8], base: usize) -> PyResult<Vec<PyObject>> {
+    diff_roms(a, b, base).into_iter()
+        .map(|diff| {
+            let dict = PyDict::new(py);
+            dict.set_item("addr", diff.addr)?;
+            dict.set_item("old", diff.old)?;
+            dict.set_item("new", diff.new)?;
+            Ok(dict.into())
+        })
+        .collect()
+}
+
+/// Listing as [{"addr", "kind", "text"}], kind is "code" or "data" for bytes
+/// in data ranges given as (start, end) address pairs
+#[pyfunction(base = "0x200", data = "Vec::new()")]
+#[name = "disassemble"]
+fn py_disassemble(py: Python, rom: &[u8], base: usize, data: Vec<(usize, usize)>) -> PyResult<Vec<PyObject>> {
+    let ranges: Vec<_> = data.into_iter().map(|(start, end)| start..end).collect();
+    disassemble(rom, base, &ranges).into_iter()
+        .map(|line| {
+            let dict = PyDict::new(py);
+            dict.set_item("addr", line.addr())?;
+            dict.set_item("kind", match line {
+                DisasmLine::Code { .. } => "code",
+                DisasmLine::Data { .. } => "data",
+            })?;
+            dict.set_item("text", line.to_string())?;
+            Ok(dict.into())
+        })
+        .collect()
+}
+
+/// Mnemonic of instruction in first two bytes, raises ValueError for data
+#[pyfunction]
+#[name = "decode_strict"]
+fn py_decode_strict(data: &[u8]) -> PyResult<String> {
+    decode_strict(data)
+        .map(|op| op.to_string())
+        .map_err(|err| PyValueError::new_err(err.to_string()))
+}
+
+/// Plain English explanation of a 16-bit opcode
+#[pyfunction]
+#[name = "describe_opcode"]
+fn py_describe_opcode(word: u16) -> String {
+    Machine::describe_opcode(word)
+}
+
+/// Heuristic (Quirks, notes) guess for an unknown ROM
+#[pyfunction]
+#[name = "suggest_quirks"]
+fn py_suggest_quirks(data: &[u8]) -> (PyQuirks, Vec<&'static str>) {
+    let suggestion = suggest_quirks(data);
+    (PyQuirks::from(suggestion.quirks), suggestion.notes)
+}
+
+/// Maximum (width, height) a target can display
+#[pyfunction]
+#[name = "screen_dimensions"]
+fn py_screen_dimensions(target: &str) -> PyResult<(usize, usize)> {
+    Ok(screen_dimensions(parse_target(target)?))
+}
+
+/// Run ROM for frames without display, returns dict with packed framebuffer
+#[pyfunction(frames = "60", ips = "700")]
+#[name = "run_headless"]
+fn py_run_headless(py: Python, data: &[u8], frames: usize, ips: u32) -> PyResult<PyObject> {
+    let result = guarded(|| HeadlessRunner::default().run_rom(data, frames, ips))?;
+    let dict = PyDict::new(py);
+    dict.set_item("framebuffer", PyBytes::new(py, &result.framebuffer))?;
+    dict.set_item("width", result.width)?;
+    dict.set_item("height", result.height)?;
+    dict.set_item("checksum", result.checksum)?;
+    dict.set_item("frames", result.frames)?;
+    dict.set_item("stop", result.stop.map(|err| err.to_string()))?;
+    Ok(dict.into())
+}
+
+/// A Python module implemented in Rust
+#[pymodule]
+fn chip8(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<PyMachine>()?;
+    m.add_class::<PyQuirks>()?;
+    m.add_function(wrap_pyfunction!(py_build_cfg, m)?)?;
+    m.add_function(wrap_pyfunction!(py_diff_roms, m)?)?;
+    m.add_function(wrap_pyfunction!(py_disassemble, m)?)?;
+    m.add_function(wrap_pyfunction!(py_decode_strict, m)?)?;
+    m.add_function(wrap_pyfunction!(py_run_headless, m)?)?;
+    m.add_function(wrap_pyfunction!(py_screen_dimensions, m)?)?;
+    m.add_function(wrap_pyfunction!(py_describe_opcode, m)?)?;
+    m.add_function(wrap_pyfunction!(py_suggest_quirks, m)?)?;
+    Ok(())
+}
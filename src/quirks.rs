@@ -0,0 +1,148 @@
+use alloc::vec::Vec;
+
+use crate::isa::decode;
+use crate::isa::ISA;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Target {
+    Chip8,      // COSMAC VIP interpreter
+    SuperChip,  // HP48 SCHIP 1.1
+    XoChip,     // Octo XO-CHIP
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Quirks {
+    pub shift_uses_vy: bool,     // 8xy6/8xyE shift Vy into Vx
+    pub mem_increments_i: bool,  // Fx55/Fx65 leave I at I+x+1
+    pub jump_uses_vx: bool,      // Bnnn jumps to nnn + Vx
+    pub clip_sprites: bool,      // DRAW clips at screen edges instead of wrapping
+    pub sys_is_noop: bool,       // 0nnn is ignored instead of jumping
+    pub logic_resets_vf: bool,   // 8xy1/8xy2/8xy3 clear VF
+    pub add_i_sets_vf: bool,     // Fx1E sets VF when I overflows Machine::i_mask
+    pub display_wait: bool,      // DRAW waits for next 60Hz tick
+    pub schip_opcodes: bool,     // 00Cn/00FB/00FC/00FD scroll and exit, invalid otherwise
+}
+
+impl Quirks {
+    pub fn for_target(target: Target) -> Quirks {
+        match target {
+            Target::Chip8 => Quirks {
+                shift_uses_vy: true,
+                mem_increments_i: true,
+                jump_uses_vx: false,
+                clip_sprites: true,
+                sys_is_noop: true,
+                logic_resets_vf: true,
+                add_i_sets_vf: false,
+                display_wait: true,
+                schip_opcodes: false,
+            },
+            Target::SuperChip => Quirks {
+                shift_uses_vy: false,
+                mem_increments_i: false,
+                jump_uses_vx: true,
+                clip_sprites: true,
+                sys_is_noop: true,
+                logic_resets_vf: false,
+                add_i_sets_vf: false,
+                display_wait: false,
+                schip_opcodes: true,
+            },
+            Target::XoChip => Quirks {
+                shift_uses_vy: true,
+                mem_increments_i: true,
+                jump_uses_vx: false,
+                clip_sprites: false,
+                sys_is_noop: true,
+                logic_resets_vf: false,
+                add_i_sets_vf: false,
+                display_wait: false,
+                schip_opcodes: true,
+            },
+        }
+    }
+}
+
+// Largest screen a target can switch to, e.g. to size a texture before loading
+pub fn screen_dimensions(target: Target) -> (usize, usize) {
+    match target {
+        Target::Chip8 => (64, 32),
+        Target::SuperChip | Target::XoChip => (128, 64),
+    }
+}
+
+// Quirks guessed from a ROM with notes on what to try if it misbehaves
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuirkSuggestion {
+    pub quirks: Quirks,
+    pub notes: Vec<&'static str>,
+}
+
+// Best-effort guess from a linear sweep of opcodes at even offsets. Data tables
+// decode as instructions too and usage can't tell which behaviour the author
+// tested against, so notes only point at quirks worth toggling.
+pub fn suggest_quirks(bytes: &[u8]) -> QuirkSuggestion {
+    let ops: Vec<ISA> = bytes.chunks_exact(2).filter_map(decode).collect();
+    let uses = |f: fn(&ISA) -> bool| ops.iter().any(f);
+    let mut notes = Vec::new();
+    let target = if uses(|op| matches!(op, ISA::PITCH(_) | ISA::AUDIO)) {
+        notes.push("XO-CHIP audio opcodes found, XO-CHIP quirks likely");
+        Target::XoChip
+    } else if uses(|op| matches!(op, ISA::LORES | ISA::HIRES | ISA::SCD(_) | ISA::SCR | ISA::SCL | ISA::EXIT)) {
+        notes.push("SCHIP display opcodes found, SCHIP quirks likely");
+        Target::SuperChip
+    } else {
+        Target::Chip8
+    };
+    let mut quirks = Quirks::for_target(target);
+    if uses(|op| matches!(*op, ISA::SHR(x, y) | ISA::SHL(x, y) if x != y)) {
+        notes.push("shifts name distinct Vx and Vy, try toggling shift_uses_vy");
+    } else {
+        // with Vx == Vy both shift behaviours agree
+        quirks.shift_uses_vy = false;
+    }
+    if uses(|op| matches!(op, ISA::STOR(_) | ISA::READ(_))) {
+        if uses(|op| matches!(op, ISA::ADDI(_))) {
+            notes.push("Fx55/Fx65 next to Fx1E, I is probably advanced by hand, maybe mem_increments_i off");
+            quirks.mem_increments_i = false;
+        } else {
+            notes.push("Fx55/Fx65 without Fx1E, may rely on mem_increments_i");
+            quirks.mem_increments_i = true;
+        }
+    }
+    if uses(|op| matches!(*op, ISA::JUMPI(_, x) if x != 0)) {
+        notes.push("Bnnn with non-zero high nibble, try toggling jump_uses_vx");
+    }
+    if uses(|op| matches!(op, ISA::ADDI(_))) {
+        notes.push("Fx1E found, a few games need add_i_sets_vf");
+    }
+    QuirkSuggestion { quirks, notes }
+}
+
+#[cfg(test)]
+#[test]
+fn test_suggest_quirks() {
+    // LOADI $300, SHR r1, r2, READ r2, SHL r3, r3, JP $200
+    let rom = [0xA3, 0x00, 0x81, 0x26, 0xF2, 0x65, 0x83, 0x3E, 0x12, 0x00];
+    let suggestion = suggest_quirks(&rom);
+    assert!(suggestion.quirks.shift_uses_vy);
+    assert!(suggestion.quirks.mem_increments_i);
+    assert_eq!(suggestion.notes.len(), 2);
+    assert!(suggestion.notes[0].contains("shift_uses_vy"));
+    assert!(suggestion.notes[1].contains("mem_increments_i"));
+    // HIRES, SHR r1, r1, STOR r0, ADDI r0
+    let rom = [0x00, 0xFF, 0x81, 0x16, 0xF0, 0x55, 0xF0, 0x1E];
+    let suggestion = suggest_quirks(&rom);
+    assert!(!suggestion.quirks.shift_uses_vy);
+    assert!(!suggestion.quirks.mem_increments_i);
+    assert!(suggestion.quirks.jump_uses_vx);
+    assert_eq!(suggestion.notes.len(), 3);
+}
+
+#[cfg(test)]
+#[test]
+fn test_screen_dimensions() {
+    assert_eq!(screen_dimensions(Target::Chip8), (64, 32));
+    assert_eq!(screen_dimensions(Target::SuperChip), (128, 64));
+    assert_eq!(screen_dimensions(Target::XoChip), (128, 64));
+}
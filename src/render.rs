@@ -0,0 +1,41 @@
+#[cfg(feature = "std")]
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "std")]
+use alloc::vec::Vec;
+
+// Display back-end receiving frames pushed by Machine after each draw
+pub trait Renderer {
+    fn present(&mut self, fb: &[u8], width: usize, height: usize);
+}
+
+// Default renderer, callers poll framebuffer instead
+pub struct NullRenderer;
+
+impl Renderer for NullRenderer {
+    fn present(&mut self, _fb: &[u8], _width: usize, _height: usize) {}
+}
+
+// Keeps copies of all presented frames, clones share the same storage
+#[cfg(feature = "std")]
+#[derive(Clone, Default)]
+pub struct FrameCollector {
+    frames: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+
+#[cfg(feature = "std")]
+impl FrameCollector {
+    pub fn new() -> FrameCollector {
+        FrameCollector::default()
+    }
+
+    pub fn frames(&self) -> Vec<Vec<u8>> {
+        self.frames.lock().unwrap().clone()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Renderer for FrameCollector {
+    fn present(&mut self, fb: &[u8], width: usize, height: usize) {
+        self.frames.lock().unwrap().push(fb[..width * height].to_vec());
+    }
+}
@@ -0,0 +1,209 @@
+"""Tests of python bindings, run after building extension with:
+
+    $ maturin develop --features extension-module
+    $ python -m unittest discover -s tests
+"""
+import copy
+import unittest
+
+from chip8.chip8 import Machine
+
+
+def machine_with(hex_program):
+    machine = Machine()
+    machine.load_hex(hex_program)
+    return machine
+
+
+class TestStep(unittest.TestCase):
+
+    def test_idle_loop_keeps_stepping(self):
+        # CLS, JP $202 like the end of IBM logo, stepped as app.py does
+        machine = machine_with('00E0 1202')
+        outcomes = [machine.step() for _ in range(5)]
+        self.assertTrue(machine.is_halted)
+        self.assertEqual([outcome['pc'] for outcome in outcomes], [0x200, 0x202, 0x202, 0x202, 0x202])
+        self.assertFalse(outcomes[-1]['drew'])
+        machine.tick()
+
+    def test_other_halts_raise(self):
+        # LOAD r0, $01 then empty memory
+        machine = machine_with('6001')
+        machine.step()
+        with self.assertRaises(ValueError):
+            machine.step()
+
+
+class TestGuarded(unittest.TestCase):
+
+    def test_panic_raises_runtime_error(self):
+        # rows of such a sprite can't be allocated, Rust panics
+        machine = Machine()
+        with self.assertRaises(RuntimeError):
+            machine.would_collide(0, 0, 2 ** 63)
+        self.assertFalse(machine.would_collide(0, 0, 1))
+
+
+class TestFork(unittest.TestCase):
+
+    def test_copies_are_independent(self):
+        # ADD r0, $01, JP $200
+        machine = machine_with('7001 1200')
+        machine.step()
+        for clone in (machine.fork(), copy.copy(machine), copy.deepcopy(machine)):
+            clone.step()
+            clone.step()
+            self.assertEqual((clone.pc, clone.registers[0]), (0x202, 2))
+        self.assertEqual((machine.pc, machine.registers[0]), (0x202, 1))
+
+
+class TestHooks(unittest.TestCase):
+
+    def test_none_removes_hooks(self):
+        # ADD r0, $01, JP $200
+        machine = machine_with('7001 1200')
+        calls = []
+        machine.set_pre_hook(lambda pc, op: calls.append(pc))
+        machine.set_post_hook(lambda outcome: calls.append(outcome['pc']))
+        machine.step()
+        machine.set_pre_hook(None)
+        machine.set_post_hook(None)
+        machine.step()
+        self.assertEqual(calls, [0x200, 0x200])
+
+    def test_exception_raised_from_step(self):
+        # ADD r0, $01, JP $200
+        machine = machine_with('7001 1200')
+        calls = []
+
+        def breakpoint(pc, op):
+            calls.append(pc)
+            if pc == 0x202:
+                raise KeyError(pc)
+        machine.set_pre_hook(breakpoint)
+        machine.step()
+        with self.assertRaises(KeyError):
+            machine.step()
+        with self.assertRaises(KeyError):
+            machine.run_safe(10)
+        self.assertEqual(calls, [0x200, 0x202, 0x200, 0x202])
+
+    def test_hook_cannot_borrow_machine(self):
+        machine = machine_with('7001 1200')
+        machine.set_post_hook(lambda outcome: machine.pc)
+        with self.assertRaises(RuntimeError):
+            machine.step()
+        machine.set_post_hook(None)
+        self.assertEqual(machine.pc, 0x202)
+
+
+class TestRunSafe(unittest.TestCase):
+
+    def test_runs_until_halt(self):
+        # LOAD r0, $05, JP $202
+        machine = machine_with('6005 1202')
+        self.assertEqual(machine.run_safe(100), 2)
+        self.assertEqual(machine.registers[0], 5)
+
+    def test_cycle_limit_raises_timeout(self):
+        # ADD r0, $01, JP $200
+        machine = machine_with('7001 1200')
+        with self.assertRaises(TimeoutError):
+            machine.run_safe(10)
+        self.assertEqual(machine.registers[0], 5)
+
+    def test_errors_raise_value_error(self):
+        machine = machine_with('6001')
+        with self.assertRaises(ValueError):
+            machine.run_safe(10)
+
+
+class TestFrames(unittest.TestCase):
+
+    def test_frames_yield_screens(self):
+        # LOADI $000, DRAW r0, r0, $5, CLS, JP $200
+        machine = machine_with('A000 D005 00E0 1200')
+        frames = list(machine.frames(ips=60, count=4))
+        self.assertEqual(len(frames), 4)
+        self.assertEqual([frame[0] for frame in frames], [0, 0xFF, 0, 0])
+
+    def test_frames_stop_on_halt(self):
+        machine = machine_with('1200')
+        self.assertEqual(len(list(machine.frames(ips=600, count=10))), 1)
+        self.assertTrue(machine.is_halted)
+
+
+class TestKeys(unittest.TestCase):
+
+    def test_keyevent_returns_previous_state(self):
+        machine = Machine()
+        self.assertFalse(machine.keyevent(5, True))
+        self.assertTrue(machine.keyevent(5, True))
+        self.assertTrue(machine.keyevent(5, False))
+        self.assertEqual(machine.keys_pressed(), [])
+
+    def test_keyevent_out_of_range(self):
+        machine = Machine()
+        with self.assertRaises(ValueError):
+            machine.keyevent(16, True)
+
+
+class TestMemoryView(unittest.TestCase):
+
+    def test_negative_index(self):
+        machine = machine_with('6001')
+        self.assertEqual(machine.ram[0x200], 0x60)
+        self.assertEqual(machine.ram[-1], machine.ram[len(machine.ram) - 1])
+        self.assertEqual(machine.ram[-len(machine.ram)], machine.ram[0])
+
+    def test_index_out_of_range(self):
+        machine = Machine()
+        with self.assertRaises(IndexError):
+            machine.ram[len(machine.ram)]
+        with self.assertRaises(IndexError):
+            machine.ram[-len(machine.ram) - 1]
+
+    def test_slices(self):
+        machine = machine_with('6001 7102')
+        self.assertEqual(machine.ram[0x200:0x204], bytes([0x60, 0x01, 0x71, 0x02]))
+        self.assertEqual(machine.ram[0x200:0x204:2], bytes([0x60, 0x71]))
+        self.assertEqual(machine.ram[0x203:0x1FF:-1], bytes([0x02, 0x71, 0x01, 0x60]))
+        self.assertEqual(machine.ram[-2:], bytes(machine.ram)[-2:])
+        self.assertEqual(machine.ram[len(machine.ram):], b'')
+
+
+class TestSnapshot(unittest.TestCase):
+
+    def test_snapshot_restore(self):
+        # LOADI $000, DRAW r0, r0, $5, ADD r0, $08, JP $202
+        machine = machine_with('A000 D005 7008 1202')
+        machine.step()
+        machine.step()
+        state = machine.snapshot()
+        screen = bytes(machine.framebuffer)
+        # DRAW waits for the next frame on CHIP-8
+        machine.tick()
+        for _ in range(3):
+            machine.step()
+        self.assertNotEqual(bytes(machine.framebuffer), screen)
+        machine.restore(state)
+        self.assertEqual((machine.pc, machine.registers[0]), (0x204, 0))
+        self.assertEqual(bytes(machine.framebuffer), screen)
+
+    def test_restore_dict(self):
+        machine = machine_with('6005')
+        state = machine.snapshot_dict()
+        machine.step()
+        machine.restore(state)
+        self.assertEqual((machine.pc, machine.registers[0]), (0x200, 0))
+
+    def test_restore_rejects_garbage(self):
+        machine = Machine()
+        with self.assertRaises(ValueError):
+            machine.restore(b'C8ST')
+        with self.assertRaises(ValueError):
+            machine.restore(machine.snapshot()[:-1])
+
+
+if __name__ == '__main__':
+    unittest.main()